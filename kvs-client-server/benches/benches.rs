@@ -1,38 +1,55 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use kvs::{Durability, KvStore, KvsEngine, LsmKvsEngine, SledKvsEngine, SledOptions};
 use rand::prelude::*;
-use std::iter;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, _| {
-            b.iter_batched(
-                || {
-                    let temp_dir = TempDir::new().unwrap();
-                    (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
-                },
-                |(mut store, _temp_dir)| {
-                    for i in 1..(1 << 12) {
-                        store.set(format!("key{}", i), "value".to_string()).unwrap();
-                    }
-                },
-                BatchSize::SmallInput,
-            )
-        },
-        iter::once(()),
-    )
-    .with_function("sled", |b, _| {
+    let mut group = c.benchmark_group("set_bench");
+    group.bench_function("kvs", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled", |b| {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("sled-background", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let options = SledOptions {
+                    durability: Durability::Background,
+                    ..SledOptions::default()
+                };
+                (
+                    SledKvsEngine::open_with_options(temp_dir.path(), options).unwrap(),
+                    temp_dir,
+                )
+            },
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -40,15 +57,29 @@ fn set_bench(c: &mut Criterion) {
             BatchSize::SmallInput,
         )
     });
-    c.bench("set_bench", bench);
+    group.bench_function("lsm", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (LsmKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
 }
 
 fn get_bench(c: &mut Criterion) {
-    let bench = ParameterizedBenchmark::new(
-        "kvs",
-        |b, i| {
+    let mut group = c.benchmark_group("get_bench");
+    for i in &[8, 12, 16, 20] {
+        group.bench_with_input(BenchmarkId::new("kvs", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -60,22 +91,33 @@ fn get_bench(c: &mut Criterion) {
                     .get(format!("key{}", rng.gen_range(1, 1 << i)))
                     .unwrap();
             })
-        },
-        vec![8, 12, 16, 20],
-    )
-    .with_function("sled", |b, i| {
-        let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::open(temp_dir.path()).unwrap();
-        for key_i in 1..(1 << i) {
-            db.set(format!("key{}", key_i), "value".to_string())
-                .unwrap();
-        }
-        let mut rng = SmallRng::from_seed([0; 16]);
-        b.iter(|| {
-            db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
-        })
-    });
-    c.bench("get_bench", bench);
+        });
+        group.bench_with_input(BenchmarkId::new("sled", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let db = SledKvsEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("lsm", i), i, |b, i| {
+            let temp_dir = TempDir::new().unwrap();
+            let db = LsmKvsEngine::open(temp_dir.path()).unwrap();
+            for key_i in 1..(1 << i) {
+                db.set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            })
+        });
+    }
+    group.finish();
 }
 
 criterion_group!(benches, set_bench, get_bench);
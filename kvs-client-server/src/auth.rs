@@ -0,0 +1,195 @@
+//! Users of the native protocol and what they may do.
+//!
+//! An ACL file is JSON:
+//!
+//! ```text
+//! {
+//!   "users": {
+//!     "alice": {
+//!       "token": "s3cret",
+//!       "grants": [{ "prefix": "", "permission": "admin" }]
+//!     },
+//!     "bob": {
+//!       "token": "hunter2",
+//!       "grants": [{ "prefix": "cache:", "permission": "write", "keyspace": "web" }]
+//!     }
+//!   },
+//!   "anonymous": [{ "prefix": "public:", "permission": "read" }]
+//! }
+//! ```
+//!
+//! A grant gives a permission on the keys starting with its prefix, in the
+//! default keyspace unless it names another. `write` includes `read` and
+//! `admin` includes both. Engine statistics need `admin` on the empty
+//! prefix. Connections that have not authenticated, legacy ones included,
+//! get the `anonymous` grants.
+//!
+//! Credentials are not part of the handshake: a client sends them in a
+//! `Request::Auth`, so legacy connections can authenticate too and a
+//! connection can switch users without reconnecting.
+
+use crate::network::Request;
+use crate::{KvsError, Result, DEFAULT_KEYSPACE};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// What a grant allows on its keys, each level including the ones before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// A permission on the keys starting with `prefix`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    pub prefix: String,
+    pub permission: Permission,
+    /// The keyspace of the keys, the default one when `None`.
+    #[serde(default)]
+    pub keyspace: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    /// The password or token the user authenticates with.
+    pub token: String,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+
+/// The users a `Server` accepts and their grants.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    #[serde(default)]
+    pub users: HashMap<String, User>,
+    /// Grants of connections that have not authenticated.
+    #[serde(default)]
+    pub anonymous: Vec<Grant>,
+}
+
+impl Acl {
+    /// Reads an ACL file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Acl> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
+
+/// What a connection may do. Every request is allowed when the server has
+/// no ACL.
+pub(crate) struct Session {
+    acl: Option<Arc<Acl>>,
+    user: Option<String>,
+}
+
+impl Session {
+    /// Starts an anonymous session.
+    pub(crate) fn new(acl: Option<Arc<Acl>>) -> Session {
+        Session { acl, user: None }
+    }
+
+    /// Switches to `user` if `token` is theirs. Anyone is accepted when
+    /// there is no ACL.
+    pub(crate) fn authenticate(&mut self, user: &str, token: &str) -> bool {
+        let accepted = match &self.acl {
+            // an unknown user still costs a comparison, so the time taken
+            // does not tell which users exist
+            Some(acl) => match acl.users.get(user) {
+                Some(known) => same_token(&known.token, token),
+                None => {
+                    same_token(UNKNOWN_USER_TOKEN, token);
+                    false
+                }
+            },
+            None => true,
+        };
+        if accepted {
+            self.user = Some(user.to_owned());
+        }
+        accepted
+    }
+
+    /// Fails with `KvsError::PermissionDenied` unless every key `req`
+    /// touches is granted. A batch is denied whole.
+    pub(crate) fn check(&self, req: &Request) -> Result<()> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        let grants = match &self.user {
+            Some(user) => &acl.users[user].grants,
+            None => &acl.anonymous,
+        };
+        let check = |permission: Permission, keyspace: &Option<String>, key: &str| {
+            let keyspace = keyspace.as_deref().unwrap_or(DEFAULT_KEYSPACE);
+            let granted = grants.iter().any(|grant| {
+                grant.permission >= permission
+                    && grant.keyspace.as_deref().unwrap_or(DEFAULT_KEYSPACE) == keyspace
+                    && key.starts_with(&grant.prefix)
+            });
+            if granted {
+                Ok(())
+            } else {
+                Err(KvsError::PermissionDenied {
+                    permission,
+                    key: key.to_owned(),
+                })
+            }
+        };
+        match req {
+            Request::Get { key, keyspace } => check(Permission::Read, keyspace, key),
+            Request::Set { key, keyspace, .. }
+            | Request::Remove { key, keyspace }
+            | Request::Merge { key, keyspace, .. } => check(Permission::Write, keyspace, key),
+            // every key under the prefix must be readable
+            Request::Watch { prefix, keyspace } => check(Permission::Read, keyspace, prefix),
+            Request::Stats { keyspace } => check(Permission::Admin, keyspace, ""),
+            Request::MultiGet { keys, keyspace } => keys
+                .iter()
+                .try_for_each(|key| check(Permission::Read, keyspace, key)),
+            Request::MultiSet { pairs, keyspace } => pairs
+                .iter()
+                .try_for_each(|(key, _)| check(Permission::Write, keyspace, key)),
+            Request::MultiRemove { keys, keyspace } => keys
+                .iter()
+                .try_for_each(|key| check(Permission::Write, keyspace, key)),
+            Request::Auth { .. } => Ok(()),
+        }
+    }
+}
+
+// What the token of an unknown user is compared against.
+const UNKNOWN_USER_TOKEN: &str = "";
+
+// Compares digests of the tokens without returning early, so the time
+// taken tells neither how much of a token was right nor how long it is.
+fn same_token(known: &str, given: &str) -> bool {
+    let known = digest::digest(&digest::SHA256, known.as_bytes());
+    let given = digest::digest(&digest::SHA256, given.as_bytes());
+    known
+        .as_ref()
+        .iter()
+        .zip(given.as_ref())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
@@ -0,0 +1,241 @@
+//! The kvs-admin executable inspects and repairs a `kvs` engine directory
+//! while no server is running on it:
+//!
+//!     kvs-admin verify [--dir PATH]
+//!     Replay every record of the log and check its checksum.
+//!     Exit with a non-zero code if any record is unreadable or corrupted.
+//!
+//!     kvs-admin stats [--dir PATH]
+//!     Print the number of live keys and the live and stale bytes of the log.
+//!
+//!     kvs-admin compact [--dir PATH]
+//!     Rewrite the log so that it only contains live records.
+//!
+//!     kvs-admin dump-log [--dir PATH]
+//!     Print every record of the log along with its offset and length.
+//!
+//!     kvs-admin repair <DEST> [--dir PATH]
+//!     Copy every readable live record into a fresh store at DEST. Keys
+//!     merged with custom operators are left out and listed.
+//!
+//!     kvs-admin migrate --to ENGINE-NAME <DEST> [--dir PATH]
+//!     Copy every keyspace into a new directory DEST using another engine,
+//!     check that the key counts match and record the engine in DEST.
+//!     A kvs store is opened read-only, so it is never upgraded or compacted.
+//!     The memory engine is read from and written to its snapshot file.
+//!     Start kvs-server from DEST afterwards.
+//!
+//! --dir defaults to the current directory.
+
+use kvs::{
+    Engine, KvStore, KvsEngine, KvsError, LogOp, LsmKvsEngine, MemoryKvsEngine, OsFileSystem,
+    Result, SledKvsEngine,
+};
+use std::env;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+// number of keys copied per scan during a migration
+const MIGRATE_BATCH: usize = 1024;
+
+#[derive(StructOpt)]
+#[structopt(name = "kvs-admin")]
+struct Options {
+    #[structopt(
+        long = "dir",
+        help = "The store directory, defaults to the current directory",
+        value_name = "PATH",
+        global = true,
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    subcommand: SubCommand,
+}
+
+#[derive(StructOpt)]
+enum SubCommand {
+    #[structopt(about = "Replay and checksum every record of the log")]
+    Verify,
+    #[structopt(about = "Print key count and live/stale bytes of the log")]
+    Stats,
+    #[structopt(about = "Rewrite the log keeping only live records")]
+    Compact,
+    #[structopt(name = "dump-log", about = "Print every record of the log")]
+    DumpLog,
+    #[structopt(about = "Salvage readable records into a fresh store")]
+    Repair {
+        #[structopt(help = "Directory of the new store", name = "DEST", parse(from_os_str))]
+        dest: PathBuf,
+    },
+    #[structopt(about = "Copy the store into a new directory using another engine")]
+    Migrate {
+        #[structopt(
+            long = "to",
+            help = "The engine to migrate to",
+            value_name = "ENGINE-NAME"
+        )]
+        to: Engine,
+        #[structopt(help = "Directory of the new store", name = "DEST", parse(from_os_str))]
+        dest: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    let opts = Options::from_args();
+    let dir = match opts.dir {
+        Some(dir) => dir,
+        None => env::current_dir()?,
+    };
+
+    match opts.subcommand {
+        SubCommand::Verify => {
+            let scan = KvStore::scan_log(&OsFileSystem, &dir)?;
+            let mut problems = 0;
+            for (offset, len) in &scan.corrupt {
+                println!("unreadable: offset {} len {}", offset, len);
+                problems += 1;
+            }
+            for record in &scan.records {
+                if record.checksum_ok == Some(false) {
+                    println!(
+                        "checksum mismatch: offset {} key {}",
+                        record.offset, record.key
+                    );
+                    problems += 1;
+                }
+            }
+            println!("{} records, {} problems", scan.records.len(), problems);
+            if problems > 0 {
+                return Err(KvsError::StringError(format!(
+                    "log verification failed with {} problems",
+                    problems
+                )));
+            }
+        }
+        SubCommand::Stats => {
+            let scan = KvStore::scan_log(&OsFileSystem, &dir)?;
+            let live = scan.live_records();
+            let live_bytes: u64 = live
+                .values()
+                .flat_map(|records| records.iter().map(|record| record.len))
+                .sum();
+            println!("keys: {}", live.len());
+            println!("records: {}", scan.records.len());
+            println!("live bytes: {}", live_bytes);
+            println!("stale bytes: {}", scan.file_len.saturating_sub(live_bytes));
+        }
+        SubCommand::Compact => {
+            KvStore::open(&dir)?.compact()?;
+        }
+        SubCommand::DumpLog => {
+            let scan = KvStore::scan_log(&OsFileSystem, &dir)?;
+            for record in &scan.records {
+                let checksum = match record.checksum_ok {
+                    Some(true) => "ok",
+                    Some(false) => "BAD",
+                    None => "-",
+                };
+                match &record.op {
+                    LogOp::Set => println!(
+                        "{}\t{}\tset\t{}\t{}\t{}",
+                        record.offset, record.len, checksum, record.key, record.value
+                    ),
+                    LogOp::Remove => println!(
+                        "{}\t{}\trm\t{}\t{}",
+                        record.offset, record.len, checksum, record.key
+                    ),
+                    LogOp::Merge(op) => println!(
+                        "{}\t{}\tmerge {:?}\t{}\t{}\t{}",
+                        record.offset, record.len, op, checksum, record.key, record.value
+                    ),
+                }
+            }
+            for (offset, len) in &scan.corrupt {
+                println!("{}\t{}\tunreadable", offset, len);
+            }
+        }
+        SubCommand::Repair { dest } => {
+            let scan = KvStore::scan_log(&OsFileSystem, &dir)?;
+            let store = KvStore::open(&dest)?;
+            let (live, unfolded) = scan.live_values();
+            for (key, value) in &live {
+                store.set(key.clone(), value.clone())?;
+            }
+            println!("salvaged {} keys into {}", live.len(), dest.display());
+            for key in &unfolded {
+                eprintln!("left out {}: its merges cannot be folded", key);
+            }
+        }
+        SubCommand::Migrate { to, dest } => {
+            let from = Engine::read_marker(&dir)?.unwrap_or(Engine::Kvs);
+            if dest.exists() && dest.read_dir()?.next().is_some() {
+                return Err(KvsError::StringError(format!(
+                    "{} is not empty",
+                    dest.display()
+                )));
+            }
+            let copied = match from {
+                Engine::Kvs => migrate_from(KvStore::open_read_only(&dir)?, to, &dest)?,
+                Engine::Sled => migrate_from(SledKvsEngine::open(&dir)?, to, &dest)?,
+                Engine::Memory => migrate_from(MemoryKvsEngine::open(&dir)?, to, &dest)?,
+                Engine::Lsm => migrate_from(LsmKvsEngine::open(&dir)?, to, &dest)?,
+            };
+            to.write_marker(&dest)?;
+            println!(
+                "migrated {} keys from {} to {} in {}",
+                copied,
+                from,
+                to,
+                dest.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn migrate_from<S: KvsEngine>(source: S, to: Engine, dest: &Path) -> Result<u64> {
+    match to {
+        Engine::Kvs => copy_keyspaces(&source, &KvStore::open(dest)?),
+        Engine::Sled => copy_keyspaces(&source, &SledKvsEngine::open(dest)?),
+        Engine::Memory => {
+            let engine = MemoryKvsEngine::open(dest)?;
+            let copied = copy_keyspaces(&source, &engine)?;
+            engine.snapshot()?;
+            Ok(copied)
+        }
+        Engine::Lsm => copy_keyspaces(&source, &LsmKvsEngine::open(dest)?),
+    }
+}
+
+// Copies every keyspace of `source` into `dest` and checks the key counts.
+fn copy_keyspaces<S: KvsEngine, D: KvsEngine>(source: &S, dest: &D) -> Result<u64> {
+    let mut total = 0;
+    for name in source.keyspaces()? {
+        let (from, to) = (source.keyspace(&name)?, dest.keyspace(&name)?);
+        let mut copied = 0;
+        let mut after: Option<String> = None;
+        loop {
+            let pairs = from.scan("", after.as_deref(), MIGRATE_BATCH)?;
+            let done = pairs.len() < MIGRATE_BATCH;
+            for (key, value) in pairs {
+                to.set(key.clone(), value)?;
+                after = Some(key);
+                copied += 1;
+            }
+            if done {
+                break;
+            }
+        }
+
+        let (expected, actual) = (from.stats()?.key_count, to.stats()?.key_count);
+        if expected != copied || actual != copied {
+            return Err(KvsError::StringError(format!(
+                "keyspace {}: {} keys in source, {} copied, {} in destination",
+                name, expected, copied, actual
+            )));
+        }
+        total += copied;
+    }
+    Ok(total)
+}
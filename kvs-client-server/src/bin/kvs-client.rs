@@ -16,11 +16,53 @@
 //!     --addr accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If --addr is not specified then connect on 127.0.0.1:4000.
 //!     Print an error and return a non-zero exit code on server error, or if IP-PORT does not parse as an address. A "key not found" is also treated as an error in the "rm" command.
 //!
+//!     kvs-client mget <KEY>... [--addr IP-PORT]
+//!     Print the value of every key on its own line, or "Key not found".
+//!
+//!     kvs-client mset <KEY> <VALUE> [<KEY> <VALUE>...] [--addr IP-PORT]
+//!     Set several keys with a single request.
+//!
+//!     kvs-client mdel <KEY>... [--addr IP-PORT]
+//!     Remove several keys with a single request. Keys not found are reported
+//!     and make the command fail once the others are removed.
+//!
+//!     kvs-client stats [--addr IP-PORT]
+//!     Print the storage engine statistics of the server.
+//!
+//!     kvs-client incr <KEY> [--by DELTA] [--addr IP-PORT]
+//!     Atomically add DELTA (default 1) to an integer key and print the result.
+//!
+//!     kvs-client merge <KEY> <OPERAND> --op add|append|max|json|NAME [--addr IP-PORT]
+//!     Atomically merge OPERAND into the value of a key and print the result.
+//!     Any other NAME is an operator registered with the engine of the server.
+//!
+//!     kvs-client watch <PREFIX> [--addr IP-PORT]
+//!     Print every change to keys starting with PREFIX until interrupted.
+//!
 //!     kvs-client -V
 //!     Print the version.
+//!
+//! Every --addr also accepts unix:PATH to connect to a server listening on a
+//! Unix domain socket.
+//!
+//! Every command accepts --tls-ca FILE to connect over TLS to a server with a
+//! certificate signed by a CA of that PEM bundle, with --tls-cert FILE and
+//! --tls-key FILE for servers requiring client certificates and
+//! --tls-server-name NAME when the certificate is not for the address host.
+//!
+//! Every command accepts --user NAME with --token TOKEN, or the KVS_TOKEN
+//! environment variable, to authenticate before the request. Requests the
+//! user or an anonymous client is not granted fail with "Permission denied".
+//!
+//! Every command accepts --keyspace NAME to act on a named keyspace instead of
+//! the default one, and --protocol legacy|json|bincode to pick how requests
+//! are encoded. By default the framed protocol is used with the codec the
+//! server prefers among json and bincode; legacy talks to older servers.
 //! All error messages should be printed to stderr.
 
-use kvs::{Client, Result};
+use kvs::{Client, ClientTlsOptions, KvsError, MergeOperator, Protocol, Result, WatchEvent};
+use std::path::PathBuf;
+use std::process::exit;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -28,6 +70,68 @@ const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 #[derive(StructOpt)]
 #[structopt(name = "kvs-client")]
 struct Options {
+    #[structopt(
+        long = "keyspace",
+        help = "Use a named keyspace instead of the default one",
+        value_name = "NAME",
+        global = true
+    )]
+    keyspace: Option<String>,
+    #[structopt(
+        long = "protocol",
+        help = "Encode requests with the legacy protocol or with frames in json or bincode",
+        value_name = "PROTOCOL",
+        global = true
+    )]
+    protocol: Option<Protocol>,
+    #[structopt(
+        long = "tls-ca",
+        help = "Connects over TLS, trusting the CAs of this PEM bundle",
+        value_name = "FILE",
+        global = true
+    )]
+    tls_ca: Option<PathBuf>,
+    #[structopt(
+        long = "tls-cert",
+        help = "Presents this PEM client certificate chain",
+        value_name = "FILE",
+        requires_all = &["tls-key", "tls-ca"],
+        global = true
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long = "tls-key",
+        help = "The PEM private key of --tls-cert",
+        value_name = "FILE",
+        requires = "tls-cert",
+        global = true
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long = "tls-server-name",
+        help = "The name the server certificate must be valid for",
+        value_name = "NAME",
+        requires = "tls-ca",
+        global = true
+    )]
+    tls_server_name: Option<String>,
+    #[structopt(
+        long = "user",
+        help = "Authenticates as this user",
+        value_name = "NAME",
+        requires = "token",
+        global = true
+    )]
+    user: Option<String>,
+    #[structopt(
+        long = "token",
+        help = "The password or token of --user",
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        global = true
+    )]
+    token: Option<String>,
     #[structopt(subcommand)]
     subcommand: SubCommand,
 }
@@ -41,7 +145,7 @@ enum SubCommand {
         #[structopt(help = "The string value of the key", name = "VALUE")]
         value: String,
         #[structopt(
-            long="addr", help = "Set the server address",
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
             value_name = "IP:PORT",
             default_value = DEFAULT_LISTENING_ADDRESS,
             parse(try_from_str)
@@ -53,7 +157,7 @@ enum SubCommand {
         #[structopt(help = "A string key", name = "KEY")]
         key: String,
         #[structopt(
-            long="addr", help = "Set the server address",
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
             value_name = "IP:PORT",
             default_value = DEFAULT_LISTENING_ADDRESS,
             parse(try_from_str)
@@ -65,7 +169,109 @@ enum SubCommand {
         #[structopt(help = "A string key", name = "KEY")]
         key: String,
         #[structopt(
-            long="addr", help = "Set the server address",
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Get the values of several keys")]
+    Mget {
+        #[structopt(help = "String keys", name = "KEY", required = true)]
+        keys: Vec<String>,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Set several keys")]
+    Mset {
+        #[structopt(
+            help = "Keys each followed by its value",
+            name = "KEY VALUE",
+            required = true
+        )]
+        args: Vec<String>,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Remove several keys")]
+    Mdel {
+        #[structopt(help = "String keys", name = "KEY", required = true)]
+        keys: Vec<String>,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Atomically add to an integer key")]
+    Incr {
+        #[structopt(help = "A string key", name = "KEY")]
+        key: String,
+        #[structopt(
+            long = "by",
+            help = "The amount to add",
+            value_name = "DELTA",
+            default_value = "1",
+            allow_hyphen_values = true
+        )]
+        delta: i64,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Atomically merge an operand into the value of a key")]
+    Merge {
+        #[structopt(help = "A string key", name = "KEY")]
+        key: String,
+        #[structopt(help = "The operand to merge", name = "OPERAND")]
+        operand: String,
+        #[structopt(
+            long = "op",
+            help = "The merge operator: add, append, max, json or one registered on the server",
+            value_name = "OPERATOR"
+        )]
+        operator: MergeOperator,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Print changes to keys starting with a prefix")]
+    Watch {
+        #[structopt(help = "A key prefix", name = "PREFIX")]
+        prefix: String,
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
+            value_name = "IP:PORT",
+            default_value = DEFAULT_LISTENING_ADDRESS,
+            parse(try_from_str)
+        )]
+        addr: String,
+    },
+    #[structopt(about = "Print the storage engine statistics")]
+    Stats {
+        #[structopt(
+            long="addr", help = "Set the server address, or unix:PATH for a Unix socket",
             value_name = "IP:PORT",
             default_value = DEFAULT_LISTENING_ADDRESS,
             parse(try_from_str)
@@ -74,15 +280,45 @@ enum SubCommand {
     },
 }
 
-fn main() -> Result<()> {
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let opts = Options::from_args();
+    let keyspace = opts.keyspace;
+    let protocol = opts.protocol;
+    let (user, token) = (opts.user, opts.token);
+    let tls = match opts.tls_ca {
+        Some(ca) => Some(ClientTlsOptions {
+            ca,
+            cert: opts.tls_cert.zip(opts.tls_key),
+            server_name: opts.tls_server_name,
+        }),
+        None => None,
+    };
+    let connect = |addr: String| -> Result<Client> {
+        let mut client = match (&tls, protocol) {
+            (Some(tls), _) => Client::with_tls(addr, protocol, tls)?,
+            (None, Some(protocol)) => Client::with_protocol(addr, protocol)?,
+            (None, None) => Client::new(addr)?,
+        };
+        if let (Some(user), Some(token)) = (&user, &token) {
+            client.authenticate(user.clone(), token.clone())?;
+        }
+        client.set_keyspace(keyspace.clone());
+        Ok(client)
+    };
     match opts.subcommand {
         SubCommand::Set { key, value, addr } => {
-            let mut client = Client::new(addr)?;
+            let mut client = connect(addr)?;
             client.set(key.to_string(), value.to_string())?
         }
         SubCommand::Get { key, addr } => {
-            let mut client = Client::new(addr)?;
+            let mut client = connect(addr)?;
             let output = match client.get(key.to_string())? {
                 Some(value) => value,
                 None => "Key not found".to_string(),
@@ -91,9 +327,87 @@ fn main() -> Result<()> {
             println!("{}", output);
         }
         SubCommand::Rm { key, addr } => {
-            let mut client = Client::new(addr)?;
+            let mut client = connect(addr)?;
             client.remove(key.to_string())?;
         }
+        SubCommand::Mget { keys, addr } => {
+            let mut client = connect(addr)?;
+            for value in client.get_many(keys)? {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+            }
+        }
+        SubCommand::Mset { args, addr } => {
+            if args.len() % 2 != 0 {
+                return Err(KvsError::StringError("every key needs a value".to_owned()));
+            }
+            let pairs: Vec<(String, String)> = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+            let mut client = connect(addr)?;
+            report_failures(&keys, client.set_many(pairs)?)?;
+        }
+        SubCommand::Mdel { keys, addr } => {
+            let mut client = connect(addr)?;
+            let results = client.remove_many(keys.clone())?;
+            report_failures(&keys, results)?;
+        }
+        SubCommand::Incr { key, delta, addr } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.increment(key, delta)?);
+        }
+        SubCommand::Merge {
+            key,
+            operand,
+            operator,
+            addr,
+        } => {
+            let mut client = connect(addr)?;
+            println!("{}", client.merge(key, operator, operand)?);
+        }
+        SubCommand::Watch { prefix, addr } => {
+            let client = connect(addr)?;
+            for event in client.watch(prefix)? {
+                match event? {
+                    WatchEvent::Set { key, value } => println!("set {} {}", key, value),
+                    WatchEvent::Remove { key } => println!("rm {}", key),
+                }
+            }
+        }
+        SubCommand::Stats { addr } => {
+            let mut client = connect(addr)?;
+            let stats = client.stats()?;
+            println!("keys: {}", stats.key_count);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("stale bytes: {}", stats.stale_bytes);
+            println!("segments: {}", stats.segment_count);
+            println!("compactions: {}", stats.compaction_count);
+            println!("compaction time: {:?}", stats.compaction_time);
+            match stats.hit_rate() {
+                Some(rate) => println!("get hit rate: {:.2}", rate),
+                None => println!("get hit rate: -"),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Prints the keys a batch failed for, failing if there is any.
+fn report_failures(keys: &[String], results: Vec<Result<()>>) -> Result<()> {
+    let mut failed = 0;
+    for (key, result) in keys.iter().zip(results) {
+        if let Err(err) = result {
+            eprintln!("{}: {}", key, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(KvsError::StringError(format!(
+            "{} of {} keys failed",
+            failed,
+            keys.len()
+        )));
     }
     Ok(())
 }
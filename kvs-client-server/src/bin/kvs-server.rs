@@ -1,37 +1,79 @@
-use kvs::{KvStore, KvsEngine, KvsError, Result, Server, SledKvsEngine};
-use log::{info, LevelFilter};
+use kvs::{
+    Acl, Durability, Engine, KvStore, KvStoreOptions, KvsEngine, Limits, LsmKvsEngine, LsmOptions,
+    MemoryKvsEngine, Result, Server, ServerTlsOptions, SledKvsEngine, SledMode, SledOptions,
+};
+use log::{error, info, LevelFilter};
 use std::env;
-use std::fs;
+use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
-const CONFIG_FILENAME: &str = "engine";
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Engine {
-    Kvs,
-    Sled,
-}
-
-impl FromStr for Engine {
-    type Err = KvsError;
-
-    fn from_str(s: &str) -> Result<Self> {
-        match s {
-            "kvs" => Ok(Engine::Kvs),
-            "sled" => Ok(Engine::Sled),
-            _ => Err(KvsError::EngineNotFound),
-        }
-    }
-}
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Options {
-    #[structopt(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS, parse(try_from_str))]
+    #[structopt(
+        long,
+        help = "Listens on IP:PORT, or on a Unix socket with unix:PATH",
+        value_name = "IP:PORT",
+        default_value = DEFAULT_LISTENING_ADDRESS
+    )]
     addr: String,
+    #[structopt(
+        long,
+        help = "Permissions of the Unix socket, in octal",
+        value_name = "MODE",
+        parse(try_from_str = parse_mode)
+    )]
+    socket_mode: Option<u32>,
+    #[structopt(
+        long,
+        help = "Serves over TLS with this PEM certificate chain",
+        value_name = "FILE",
+        requires = "tls-key"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "The PEM private key of --tls-cert",
+        value_name = "FILE",
+        requires = "tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Requires client certificates signed by a CA of this PEM bundle",
+        value_name = "FILE",
+        requires = "tls-cert"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Lets clients do only what this JSON ACL file grants them",
+        value_name = "FILE"
+    )]
+    acl: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Also serves Redis clients on this address",
+        value_name = "IP:PORT"
+    )]
+    resp_addr: Option<String>,
+    #[structopt(
+        long,
+        help = "Also serves memcached clients on this address",
+        value_name = "IP:PORT"
+    )]
+    memcached_addr: Option<String>,
+    #[structopt(
+        long,
+        help = "Also serves an HTTP/JSON API on this address",
+        value_name = "IP:PORT"
+    )]
+    http_addr: Option<String>,
     #[structopt(
         long,
         help = "Sets the storage engine",
@@ -39,6 +81,51 @@ struct Options {
         default_value = "kvs"
     )]
     engine: String,
+    #[structopt(
+        long,
+        help = "Snapshots the memory engine to the current directory every SECONDS",
+        value_name = "SECONDS"
+    )]
+    snapshot_interval: Option<u64>,
+    #[structopt(
+        long,
+        help = "When writes are acknowledged: sync, flush or background",
+        value_name = "POLICY",
+        default_value = "flush",
+        possible_values = &["sync", "flush", "background"]
+    )]
+    durability: Durability,
+    #[structopt(
+        long,
+        help = "Maximum size of the sled page cache",
+        value_name = "BYTES"
+    )]
+    sled_cache_capacity: Option<u64>,
+    #[structopt(
+        long,
+        help = "Milliseconds between sled background flushes, 0 disables them",
+        value_name = "MS"
+    )]
+    sled_flush_every_ms: Option<u64>,
+    #[structopt(long, help = "Compresses sled data, needs the compression feature")]
+    sled_compression: bool,
+    #[structopt(
+        long,
+        help = "The sled segment mode",
+        value_name = "MODE",
+        possible_values = &["low-space", "high-throughput"]
+    )]
+    sled_mode: Option<SledMode>,
+    #[structopt(long, help = "Largest key accepted", value_name = "BYTES")]
+    max_key_size: Option<u64>,
+    #[structopt(long, help = "Largest value accepted", value_name = "BYTES")]
+    max_value_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Most keyspaces clients may create, the default one included",
+        value_name = "COUNT"
+    )]
+    max_keyspaces: Option<usize>,
 }
 
 fn main() -> Result<()> {
@@ -50,17 +137,22 @@ fn main() -> Result<()> {
 
     let opts = Options::from_args();
     let curr_dir = env::current_dir()?;
-    let engine_config = curr_dir.join(CONFIG_FILENAME);
     let engine = Engine::from_str(&opts.engine)?;
-    let curr_engine = match previous_engine_type()? {
-        Some(prev_engine) => {
-            if engine != prev_engine {
-                exit(1);
-            } else {
-                prev_engine
-            }
+    if engine == Engine::Memory && opts.snapshot_interval.is_none() {
+        // nothing is written to disk, so the directory is left alone
+        info!("Storage engine memory, data is lost on shutdown");
+        info!("Listening on: {}", opts.addr);
+        return start_server_with(&opts, MemoryKvsEngine::new().with_limits(limits(&opts)));
+    }
+    let curr_engine = match Engine::read_marker(&curr_dir)? {
+        Some(prev_engine) if prev_engine != engine => {
+            error!(
+                "Data was written by the {} engine, use `kvs-admin migrate --to {}` to convert it",
+                prev_engine, engine
+            );
+            exit(1);
         }
-        None => engine,
+        _ => engine,
     };
 
     info!(
@@ -70,28 +162,161 @@ fn main() -> Result<()> {
     );
     info!("Listening on: {}", opts.addr.clone());
 
-    fs::write(&engine_config, format!("{}", opts.engine))?;
+    curr_engine.write_marker(&curr_dir)?;
 
     match curr_engine {
-        Engine::Kvs => start_server_with(&opts.addr, KvStore::open(curr_dir)?),
-        Engine::Sled => start_server_with(&opts.addr, SledKvsEngine::open(curr_dir)?),
+        Engine::Kvs => start_server_with(
+            &opts,
+            KvStore::open_with_options(
+                curr_dir,
+                KvStoreOptions {
+                    durability: opts.durability,
+                    limits: limits(&opts),
+                    ..KvStoreOptions::default()
+                },
+            )?,
+        ),
+        Engine::Sled => start_server_with(
+            &opts,
+            SledKvsEngine::open_with_options(curr_dir, sled_options(&opts))?,
+        ),
+        Engine::Memory => {
+            let engine = MemoryKvsEngine::open(curr_dir)?.with_limits(limits(&opts));
+            if let Some(secs) = opts.snapshot_interval {
+                engine.snapshot_every(Duration::from_secs(secs));
+            }
+            start_server_with(&opts, engine.clone())?;
+            // keeps what was written since the last periodic snapshot
+            engine.snapshot()
+        }
+        Engine::Lsm => start_server_with(
+            &opts,
+            LsmKvsEngine::open_with_options(
+                curr_dir,
+                LsmOptions {
+                    durability: opts.durability,
+                    limits: limits(&opts),
+                    ..LsmOptions::default()
+                },
+            )?,
+        ),
+    }
+}
+
+fn sled_options(opts: &Options) -> SledOptions {
+    let mut options = SledOptions {
+        use_compression: opts.sled_compression,
+        durability: opts.durability,
+        limits: limits(opts),
+        ..SledOptions::default()
+    };
+    if let Some(capacity) = opts.sled_cache_capacity {
+        options.cache_capacity = capacity;
+    }
+    if let Some(ms) = opts.sled_flush_every_ms {
+        options.flush_every_ms = if ms == 0 { None } else { Some(ms) };
     }
+    if let Some(mode) = opts.sled_mode {
+        options.mode = mode;
+    }
+    options
 }
 
-fn start_server_with<E: KvsEngine>(addr: &str, engine: E) -> Result<()> {
-    let server = Server::new(addr, engine);
+fn limits(opts: &Options) -> Limits {
+    let mut limits = Limits::default();
+    if let Some(size) = opts.max_key_size {
+        limits.max_key_size = size;
+    }
+    if let Some(size) = opts.max_value_size {
+        limits.max_value_size = size;
+    }
+    limits
+}
+
+fn parse_mode(s: &str) -> std::result::Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s, 8)
+}
+
+fn start_server_with<E: KvsEngine>(opts: &Options, engine: E) -> Result<()> {
+    let server = match opts.socket_mode {
+        Some(mode) => Server::new_with_socket_mode(&opts.addr, engine, mode)?,
+        None => Server::new(&opts.addr, engine)?,
+    };
+    let mut server = server.with_limits(limits(opts));
+    if let Some(max_keyspaces) = opts.max_keyspaces {
+        server = server.with_max_keyspaces(max_keyspaces);
+    }
+    if let (Some(cert), Some(key)) = (&opts.tls_cert, &opts.tls_key) {
+        info!("Serving TLS with certificate {}", cert.display());
+        server = server.with_tls(&ServerTlsOptions {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: opts.tls_client_ca.clone(),
+        })?;
+    }
+    if let Some(path) = &opts.acl {
+        info!("Enforcing ACL {}", path.display());
+        server = server.with_acl(Acl::open(path)?);
+    }
+    if let Some(addr) = &opts.resp_addr {
+        info!("Serving RESP on: {}", addr);
+        server = server.with_resp(addr)?;
+    }
+    if let Some(addr) = &opts.memcached_addr {
+        info!("Serving memcached on: {}", addr);
+        server = server.with_memcached(addr)?;
+    }
+    if let Some(addr) = &opts.http_addr {
+        info!("Serving HTTP on: {}", addr);
+        server = server.with_http(addr)?;
+    }
+    #[cfg(unix)]
+    stop_on_signal(server.shutdown_handle())?;
     server.serve()?;
+    info!("Kvs server shutting down.");
     Ok(())
 }
 
-fn previous_engine_type() -> Result<Option<Engine>> {
-    let engine_config_path = env::current_dir()?.join(CONFIG_FILENAME);
-    if !engine_config_path.exists() {
-        Ok(None)
-    } else {
-        match fs::read_to_string(engine_config_path) {
-            Ok(engine) => Ok(Some(Engine::from_str(&engine)?)),
-            Err(_) => Ok(None),
+// Stops the server when the process gets SIGINT or SIGTERM, so that `main`
+// returns and the server and engine are dropped as usual. The signal
+// handler only writes to a pipe, a thread waiting on it does the rest.
+#[cfg(unix)]
+fn stop_on_signal(handle: kvs::ShutdownHandle) -> Result<()> {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_signal(signal: libc::c_int) {
+        // only async-signal-safe calls are allowed here
+        let byte = signal as u8;
+        let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+    SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+    thread::spawn(move || {
+        let mut signal = [0];
+        if signals.read_exact(&mut signal).is_ok() {
+            info!("Got signal {}, stopping", signal[0]);
+            if let Err(e) = handle.shutdown() {
+                error!("Failed to stop the server: {}", e);
+            }
         }
+    });
+
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
     }
+    Ok(())
 }
@@ -1,62 +1,541 @@
-use crate::network::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
+use crate::network::{
+    write_frame, AuthResponse, Codec, FrameErrorV1, FrameHeader, GetResponse, Hello, KeyResult,
+    MergeResponse, MultiGetResponse, MultiRemoveResponse, MultiSetResponse, Opcode, Protocol,
+    RemoveResponse, Request, Response, ResponseError, SetResponse, StatsResponse, WatchResponse,
+    Welcome, LEGACY_VERSION, TYPED_ERRORS_VERSION,
+};
+use crate::tls::{self, TlsStream};
+use crate::transport::{Address, Stream};
+use crate::{ClientTlsOptions, EngineStats, KvsError, MergeOperator, Result, WatchEvent};
 use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpStream, ToSocketAddrs};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::Shutdown;
+use std::thread;
 
 pub struct Client {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    keyspace: Option<String>,
+    protocol: Protocol,
+    // version of the framed protocol the messages are read in
+    version: u16,
+    next_id: u32,
 }
 
 impl Client {
+    /// Connects with the framed protocol, preferring the bincode codec.
     pub fn new<T>(addr: T) -> Result<Self>
     where
-        T: ToSocketAddrs,
+        T: Into<Address>,
     {
-        let reader_stream = TcpStream::connect(addr)?;
+        Self::connect(Stream::connect(&addr.into())?, codecs(None))
+    }
+
+    /// Connects with the given protocol. `Protocol::Legacy` talks to
+    /// servers that predate the framed protocol.
+    pub fn with_protocol<T>(addr: T, protocol: Protocol) -> Result<Self>
+    where
+        T: Into<Address>,
+    {
+        Self::connect(Stream::connect(&addr.into())?, codecs(Some(protocol)))
+    }
+
+    /// Connects over TLS to a server verified with `options`, with the
+    /// given protocol or, when `None`, as `new` does.
+    pub fn with_tls<T>(
+        addr: T,
+        protocol: Option<Protocol>,
+        options: &ClientTlsOptions,
+    ) -> Result<Self>
+    where
+        T: Into<Address>,
+    {
+        let addr = addr.into();
+        let name = match (&options.server_name, &addr) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Tcp(addr)) => host(addr).to_owned(),
+            (None, Address::Unix(_)) => "localhost".to_owned(),
+        };
+        let stream = TlsStream::connect(
+            Stream::connect(&addr)?,
+            tls::client_config(options)?,
+            tls::server_name(&name)?,
+        )?;
+        Self::connect(Stream::Tls(Box::new(stream)), codecs(protocol))
+    }
+
+    // Negotiates one of `codecs` over `stream` unless it is `None`.
+    fn connect(reader_stream: Stream, codecs: Option<Vec<Codec>>) -> Result<Self> {
         let writer_stream = reader_stream.try_clone()?;
-        Ok(Self {
+        let mut client = Self {
             reader: BufReader::new(reader_stream),
             writer: BufWriter::new(writer_stream),
-        })
+            keyspace: None,
+            protocol: Protocol::Legacy,
+            version: LEGACY_VERSION,
+            next_id: 0,
+        };
+        if let Some(codecs) = codecs {
+            Hello::new(codecs).write_to(&mut client.writer)?;
+            client.writer.flush()?;
+            let welcome = Welcome::read_from(&mut client.reader)?;
+            client.protocol = Protocol::Framed(welcome.codec);
+            client.version = welcome.version;
+        }
+        Ok(client)
+    }
+
+    /// The protocol the connection speaks, with the codec the server
+    /// picked.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Sends all following requests to the named keyspace, or to the
+    /// default keyspace when `None`.
+    pub fn set_keyspace(&mut self, keyspace: Option<String>) {
+        self.keyspace = keyspace;
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
+        let request = Request::Get {
+            key,
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<GetResponse>(&request)?.into_result()
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<SetResponse>(&request)?.into_result()
+    }
 
-        let mut deserializer = Deserializer::new(IoRead::new(&mut self.reader));
-        let resp = GetResponse::deserialize(&mut deserializer)?;
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let request = Request::Remove {
+            key,
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<RemoveResponse>(&request)?.into_result()
+    }
 
-        match resp {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(err) => Err(KvsError::StringError(err)),
+    pub fn merge(
+        &mut self,
+        key: String,
+        operator: MergeOperator,
+        operand: String,
+    ) -> Result<String> {
+        let request = Request::Merge {
+            key,
+            operator,
+            operand,
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<MergeResponse>(&request)?.into_result()
+    }
+
+    /// Gets the values of several keys with a single request, in the order
+    /// of `keys`.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let request = Request::MultiGet {
+            keys,
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<MultiGetResponse>(&request)?.into_result()
+    }
+
+    /// Sets several keys with a single request and returns the result of
+    /// each pair.
+    ///
+    /// The whole batch must fit in the request size limit of the server,
+    /// see `Limits::max_request_size`.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let request = Request::MultiSet {
+            pairs,
+            keyspace: self.keyspace.clone(),
+        };
+        let results = self.call::<MultiSetResponse>(&request)?.into_result()?;
+        Ok(results.into_iter().map(KeyResult::into_result).collect())
+    }
+
+    /// Removes several keys with a single request and returns the result of
+    /// each key.
+    pub fn remove_many(&mut self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let request = Request::MultiRemove {
+            keys,
+            keyspace: self.keyspace.clone(),
+        };
+        let results = self.call::<MultiRemoveResponse>(&request)?.into_result()?;
+        Ok(results.into_iter().map(KeyResult::into_result).collect())
+    }
+
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let value = self.merge(key, MergeOperator::Add, delta.to_string())?;
+        value
+            .parse()
+            .map_err(|_| KvsError::InvalidMerge(format!("{:?} is not an integer", value)))
+    }
+
+    pub fn stats(&mut self) -> Result<EngineStats> {
+        let request = Request::Stats {
+            keyspace: self.keyspace.clone(),
+        };
+        self.call::<StatsResponse>(&request)?.into_result()
+    }
+
+    /// Starts a batch of requests that are sent together, saving a round
+    /// trip per request.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
         }
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
+    /// Authenticates as `user`, whose grants apply to the following
+    /// requests. The server closes the connection if `token` is wrong.
+    pub fn authenticate(&mut self, user: String, token: String) -> Result<()> {
+        self.call::<AuthResponse>(&Request::Auth { user, token })?
+            .into_result()
+    }
+
+    // Sends a request and reads its response.
+    fn call<R: Response>(&mut self, request: &Request) -> Result<R> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        write_request(&mut self.writer, self.protocol, id, request)?;
         self.writer.flush()?;
+        expect_response(&mut self.reader, self.protocol, self.version, id)?
+    }
 
-        let mut deserializer = Deserializer::new(IoRead::new(&mut self.reader));
-        let resp = SetResponse::deserialize(&mut deserializer)?;
+    /// Subscribes to changes of keys starting with `prefix`.
+    ///
+    /// The connection is dedicated to the subscription, so the client is
+    /// consumed.
+    pub fn watch(mut self, prefix: String) -> Result<WatchStream> {
+        let request = Request::Watch {
+            prefix,
+            keyspace: self.keyspace.take(),
+        };
+        let resp: WatchResponse = self.call(&request)?;
         match resp {
-            SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(err) => Err(KvsError::StringError(err)),
+            WatchResponse::Subscribed => Ok(WatchStream {
+                reader: self.reader,
+                protocol: self.protocol,
+                version: self.version,
+            }),
+            WatchResponse::Event(_) => Err(KvsError::StringError(
+                "unexpected event before subscription".to_owned(),
+            )),
+            WatchResponse::Err(err) => Err(err.into()),
         }
     }
+}
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+// The codecs to offer for `protocol`, `None` for the legacy protocol. The
+// default prefers bincode.
+fn codecs(protocol: Option<Protocol>) -> Option<Vec<Codec>> {
+    match protocol {
+        Some(Protocol::Legacy) => None,
+        Some(Protocol::Framed(codec)) => Some(vec![codec]),
+        None => Some(vec![Codec::Bincode, Codec::Json]),
+    }
+}
 
-        let mut deserializer = Deserializer::new(IoRead::new(&mut self.reader));
-        let resp = RemoveResponse::deserialize(&mut deserializer)?;
-        match resp {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(err) => Err(KvsError::StringError(err)),
+// The host of an `IP:PORT` or `NAME:PORT` address, without the brackets of
+// an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Requests queued to be sent with a single flush, built by
+/// `Client::pipeline`.
+///
+/// Requests use the keyspace the client had when they were queued.
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Request>,
+}
+
+/// The result of one request of a `Pipeline`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Set,
+    Get(Option<String>),
+    Remove,
+    Merge(String),
+    Stats(EngineStats),
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        let keyspace = self.client.keyspace.clone();
+        self.requests.push(Request::Set {
+            key,
+            value,
+            keyspace,
+        });
+        self
+    }
+
+    pub fn get(&mut self, key: String) -> &mut Self {
+        let keyspace = self.client.keyspace.clone();
+        self.requests.push(Request::Get { key, keyspace });
+        self
+    }
+
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        let keyspace = self.client.keyspace.clone();
+        self.requests.push(Request::Remove { key, keyspace });
+        self
+    }
+
+    pub fn merge(&mut self, key: String, operator: MergeOperator, operand: String) -> &mut Self {
+        let keyspace = self.client.keyspace.clone();
+        self.requests.push(Request::Merge {
+            key,
+            operator,
+            operand,
+            keyspace,
+        });
+        self
+    }
+
+    pub fn stats(&mut self) -> &mut Self {
+        let keyspace = self.client.keyspace.clone();
+        self.requests.push(Request::Stats { keyspace });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Sends the queued requests and returns their results in order.
+    ///
+    /// A request failing on the server does not stop the others. If the
+    /// connection fails instead, the error is returned and the client
+    /// cannot be used anymore.
+    pub fn execute(self) -> Result<Vec<Result<Reply>>> {
+        let Pipeline { client, requests } = self;
+        let protocol = client.protocol;
+        let version = client.version;
+        let first_id = client.next_id;
+        client.next_id = first_id.wrapping_add(requests.len() as u32);
+        let Client { reader, writer, .. } = client;
+        let requests = &requests;
+
+        thread::scope(|scope| {
+            // responses are read while the requests are written, as the
+            // server stops reading once the client stops reading
+            let sender = scope.spawn(move || -> Result<()> {
+                for (i, request) in requests.iter().enumerate() {
+                    write_request(writer, protocol, first_id.wrapping_add(i as u32), request)?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            let replies = requests
+                .iter()
+                .enumerate()
+                .map(|(i, request)| {
+                    let id = first_id.wrapping_add(i as u32);
+                    read_reply(reader, protocol, version, id, request)
+                })
+                .collect::<Result<Vec<_>>>();
+            if replies.is_err() {
+                // unblocks the sender
+                let _ = reader.get_ref().shutdown(Shutdown::Both);
+            }
+            let sent = sender.join().expect("pipeline sender panicked");
+            if sent.is_err() {
+                let _ = reader.get_ref().shutdown(Shutdown::Both);
+            }
+            sent?;
+            replies
+        })
+    }
+}
+
+/// The events of a `Client::watch` subscription.
+pub struct WatchStream {
+    reader: BufReader<Stream>,
+    protocol: Protocol,
+    version: u16,
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        match read_response(&mut self.reader, self.protocol, self.version, None) {
+            Ok(Some(Ok(WatchResponse::Event(event)))) => Some(Ok(event)),
+            Ok(Some(Ok(WatchResponse::Subscribed))) => Some(Err(KvsError::StringError(
+                "unexpected subscription acknowledgement".to_owned(),
+            ))),
+            Ok(Some(Ok(WatchResponse::Err(err)))) => Some(Err(err.into())),
+            // the server closed the connection
+            Ok(None) => None,
+            Ok(Some(Err(err))) | Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+// Turns a response into what the matching `Client` method returns.
+trait IntoResult {
+    type Output;
+
+    fn into_result(self) -> Result<Self::Output>;
+}
+
+macro_rules! into_result {
+    ($($response:ident => $output:ty),*) => {$(
+        impl IntoResult for $response {
+            type Output = $output;
+
+            fn into_result(self) -> Result<$output> {
+                match self {
+                    $response::Ok(value) => Ok(value),
+                    $response::Err(err) => Err(err.into()),
+                }
+            }
+        }
+    )*};
+}
+
+into_result!(
+    SetResponse => (),
+    GetResponse => Option<String>,
+    RemoveResponse => (),
+    MergeResponse => String,
+    StatsResponse => EngineStats,
+    MultiGetResponse => Vec<Option<String>>,
+    MultiSetResponse => Vec<KeyResult>,
+    MultiRemoveResponse => Vec<KeyResult>,
+    AuthResponse => ()
+);
+
+fn write_request(
+    writer: &mut BufWriter<Stream>,
+    protocol: Protocol,
+    id: u32,
+    request: &Request,
+) -> Result<()> {
+    match protocol {
+        Protocol::Legacy => serde_json::to_writer(writer, request)?,
+        Protocol::Framed(codec) => {
+            write_frame(writer, Opcode::Request, id, &codec.encode(request)?)?
+        }
+    }
+    Ok(())
+}
+
+// Reads the response to a request of a pipeline.
+fn read_reply(
+    reader: &mut BufReader<Stream>,
+    protocol: Protocol,
+    version: u16,
+    id: u32,
+    request: &Request,
+) -> Result<Result<Reply>> {
+    Ok(match request {
+        Request::Set { .. } => expect_response::<SetResponse>(reader, protocol, version, id)?
+            .and_then(IntoResult::into_result)
+            .map(|()| Reply::Set),
+        Request::Get { .. } => expect_response::<GetResponse>(reader, protocol, version, id)?
+            .and_then(IntoResult::into_result)
+            .map(Reply::Get),
+        Request::Remove { .. } => expect_response::<RemoveResponse>(reader, protocol, version, id)?
+            .and_then(IntoResult::into_result)
+            .map(|()| Reply::Remove),
+        Request::Merge { .. } => expect_response::<MergeResponse>(reader, protocol, version, id)?
+            .and_then(IntoResult::into_result)
+            .map(Reply::Merge),
+        Request::Stats { .. } => expect_response::<StatsResponse>(reader, protocol, version, id)?
+            .and_then(IntoResult::into_result)
+            .map(Reply::Stats),
+        Request::Watch { .. }
+        | Request::MultiGet { .. }
+        | Request::MultiSet { .. }
+        | Request::MultiRemove { .. }
+        | Request::Auth { .. } => {
+            unreachable!("batches, watches and authentication are not pipelined")
+        }
+    })
+}
+
+// Reads the response to request `id`, failing if the server closed the
+// connection.
+fn expect_response<R: Response>(
+    reader: &mut BufReader<Stream>,
+    protocol: Protocol,
+    version: u16,
+    id: u32,
+) -> Result<Result<R>> {
+    match read_response(reader, protocol, version, Some(id))? {
+        Some(resp) => Ok(resp),
+        None => Err(KvsError::Io(io::ErrorKind::UnexpectedEof.into())),
+    }
+}
+
+// Reads the next response, returning `None` if the server closed the
+// connection. Framed responses must answer request `id` when it is given.
+//
+// The inner result holds the error a framed server sends in place of a
+// response for a request it could not decode.
+fn read_response<R: Response>(
+    reader: &mut BufReader<Stream>,
+    protocol: Protocol,
+    version: u16,
+    id: Option<u32>,
+) -> Result<Option<Result<R>>> {
+    let typed = version >= TYPED_ERRORS_VERSION;
+    let codec = match protocol {
+        Protocol::Legacy => {
+            let mut deserializer = Deserializer::new(IoRead::new(reader));
+            return match R::V1::deserialize(&mut deserializer) {
+                Ok(resp) => Ok(Some(Ok(R::from_v1(resp)))),
+                Err(ref err) if err.is_eof() => Ok(None),
+                Err(err) => Err(err.into()),
+            };
+        }
+        Protocol::Framed(codec) => codec,
+    };
+    let header = match FrameHeader::read_from(reader)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let mut payload = vec![0; header.len as usize];
+    reader.read_exact(&mut payload)?;
+    if let Some(id) = id.filter(|&id| id != header.id) {
+        return Err(KvsError::StringError(format!(
+            "got a response to request {} instead of {}",
+            header.id, id
+        )));
+    }
+    match Opcode::from_byte(header.opcode) {
+        Some(Opcode::Response) if typed => Ok(Some(Ok(codec.decode(&payload)?))),
+        Some(Opcode::Response) => Ok(Some(Ok(R::from_v1(codec.decode(&payload)?)))),
+        Some(Opcode::Error) => {
+            let err = if typed {
+                codec.decode::<ResponseError>(&payload)?
+            } else {
+                codec.decode::<FrameErrorV1>(&payload)?.into()
+            };
+            Ok(Some(Err(err.into())))
         }
+        _ => Err(KvsError::StringError(format!(
+            "unexpected opcode {}",
+            header.opcode
+        ))),
     }
 }
@@ -0,0 +1,728 @@
+//! The filesystem `KvStore` and `LsmKvsEngine` keep their files in.
+//!
+//! `OsFileSystem` uses the real filesystem, `MemoryFileSystem` keeps every
+//! file in memory and `FaultyFileSystem` wraps either one to fail the
+//! operations a test scripts with `Fault`s. The last two are for tests and
+//! hidden from the documentation.
+
+use memmap::Mmap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// How `FileSystem::open` opens a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Reading an existing file.
+    Read,
+    /// Reading and appending, creating the file if needed.
+    Append,
+    /// Writing a new file, truncating it if it exists.
+    Create,
+}
+
+/// The operations on files and directories an engine needs.
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Names of the subdirectories of `path`.
+    fn list_dirs(&self, path: &Path) -> io::Result<Vec<String>>;
+
+    /// Names of the files in `path`.
+    fn list_files(&self, path: &Path) -> io::Result<Vec<String>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Moves a file, replacing the destination atomically if it exists.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.open(path, OpenMode::Read)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        let mut file = self.open(path, OpenMode::Create)?;
+        file.write_all(contents)?;
+        file.flush()
+    }
+}
+
+/// An open file of a `FileSystem`.
+pub trait FsFile: Read + Write + Seek + fmt::Debug + Send {
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    fn sync_data(&self) -> io::Result<()>;
+
+    /// Fills `buf` from the file starting at `offset`.
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>>;
+
+    /// Maps the current contents of the file read-only.
+    ///
+    /// Callers must only map files that are never truncated or rewritten
+    /// while the mapping is alive.
+    fn map(&self) -> io::Result<Mapping>;
+
+    /// Identifies the file across renames, `None` if the filesystem cannot
+    /// tell files apart.
+    fn id(&self) -> io::Result<Option<(u64, u64)>>;
+}
+
+/// A read-only view of the contents of a file.
+pub struct Mapping(Box<dyn AsRef<[u8]> + Send + Sync>);
+
+impl Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mapping({} bytes)", self.len())
+    }
+}
+
+/// The filesystem of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        let file = match mode {
+            OpenMode::Read => File::open(path)?,
+            OpenMode::Append => OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(path)?,
+            OpenMode::Create => File::create(path)?,
+        };
+        Ok(Box::new(OsFile(file)))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn list_dirs(&self, path: &Path) -> io::Result<Vec<String>> {
+        list_entries(path, |file_type| file_type.is_dir())
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<String>> {
+        list_entries(path, |file_type| file_type.is_file())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+// Names of the entries of the directory `path` of the wanted type.
+fn list_entries(path: &Path, wanted: fn(fs::FileType) -> bool) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if let Ok(name) = entry.file_name().into_string() {
+            if wanted(entry.file_type()?) {
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+#[derive(Debug)]
+struct OsFile(File);
+
+impl Read for OsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for OsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Seek for OsFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl FsFile for OsFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut file = &self.0;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(OsFile(self.0.try_clone()?)))
+    }
+
+    fn map(&self) -> io::Result<Mapping> {
+        // Safety: callers promise the mapped bytes do not change while the
+        // mapping is alive.
+        let mmap = unsafe { Mmap::map(&self.0)? };
+        Ok(Mapping(Box::new(mmap)))
+    }
+
+    #[cfg(unix)]
+    fn id(&self) -> io::Result<Option<(u64, u64)>> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = self.0.metadata()?;
+        Ok(Some((metadata.dev(), metadata.ino())))
+    }
+
+    #[cfg(not(unix))]
+    fn id(&self) -> io::Result<Option<(u64, u64)>> {
+        Ok(None)
+    }
+}
+
+/// A filesystem held in memory, shared by its clones.
+///
+/// Everything written is lost when the last clone is dropped, syncing does
+/// nothing.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    files: BTreeMap<PathBuf, Arc<Mutex<Vec<u8>>>>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl MemoryState {
+    fn has_parent(&self, path: &Path) -> bool {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") => self.dirs.contains(parent),
+            _ => true,
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        let mut state = self.state.lock().unwrap();
+        let data = match (state.files.get(path), mode) {
+            (Some(data), OpenMode::Create) => {
+                data.lock().unwrap().clear();
+                data.clone()
+            }
+            (Some(data), _) => data.clone(),
+            (None, OpenMode::Read) => return Err(not_found(path)),
+            (None, _) if !state.has_parent(path) => return Err(not_found(path)),
+            (None, _) => {
+                let data = Arc::new(Mutex::new(Vec::new()));
+                state.files.insert(path.to_owned(), data.clone());
+                data
+            }
+        };
+        Ok(Box::new(MemoryFile { data, pos: 0, mode }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        for dir in path.ancestors() {
+            if dir != Path::new("") {
+                state.dirs.insert(dir.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn list_dirs(&self, path: &Path) -> io::Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .dirs
+            .iter()
+            .filter(|dir| dir.parent() == Some(path))
+            .filter_map(|dir| dir.file_name()?.to_str().map(str::to_owned))
+            .collect())
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|file| file.parent() == Some(path))
+            .filter_map(|file| file.file_name()?.to_str().map(str::to_owned))
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.has_parent(to) {
+            return Err(not_found(to));
+        }
+        let data = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .files
+            .remove(path)
+            .map(drop)
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    pos: u64,
+    mode: OpenMode,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.mode == OpenMode::Read {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is opened for reading",
+            ));
+        }
+        let mut data = self.data.lock().unwrap();
+        if self.mode == OpenMode::Append {
+            self.pos = data.len() as u64;
+        }
+        let start = self.pos as usize;
+        if data.len() < start + buf.len() {
+            data.resize(start + buf.len(), 0);
+        }
+        data[start..start + buf.len()].copy_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(delta) => len + delta,
+            SeekFrom::Current(delta) => self.pos as i64 + delta,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl FsFile for MemoryFile {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.lock().unwrap().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.lock().unwrap();
+        let start = offset as usize;
+        match data.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(MemoryFile {
+            data: self.data.clone(),
+            pos: self.pos,
+            mode: self.mode,
+        }))
+    }
+
+    fn map(&self) -> io::Result<Mapping> {
+        Ok(Mapping(Box::new(self.data.lock().unwrap().clone())))
+    }
+
+    fn id(&self) -> io::Result<Option<(u64, u64)>> {
+        Ok(Some((0, Arc::as_ptr(&self.data) as u64)))
+    }
+}
+
+/// Filesystem operations a `Fault` can target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsOp {
+    Open,
+    Read,
+    Write,
+    Flush,
+    Sync,
+    SetLen,
+    Map,
+    CreateDir,
+    Rename,
+    RemoveFile,
+}
+
+/// How an operation hit by a `Fault` fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// Fails like a full disk (ENOSPC).
+    NoSpace,
+    /// Fails like a device error (EIO).
+    Io,
+    /// Writes only the given number of bytes before failing like a full
+    /// disk, other operations fail like `NoSpace`.
+    ShortWrite(usize),
+}
+
+impl FaultKind {
+    fn error(self) -> io::Error {
+        match self {
+            FaultKind::NoSpace | FaultKind::ShortWrite(_) => io::Error::new(
+                io::ErrorKind::StorageFull,
+                "no space left on device (injected)",
+            ),
+            FaultKind::Io => io::Error::other("input/output error (injected)"),
+        }
+    }
+}
+
+/// A scripted failure of a `FaultyFileSystem` operation.
+///
+/// By default it fails the first matching operation once.
+#[derive(Debug, Clone)]
+pub struct Fault {
+    op: FsOp,
+    kind: FaultKind,
+    file_name: Option<String>,
+    skip: usize,
+    times: usize,
+}
+
+impl Fault {
+    pub fn new(op: FsOp, kind: FaultKind) -> Fault {
+        Fault {
+            op,
+            kind,
+            file_name: None,
+            skip: 0,
+            times: 1,
+        }
+    }
+
+    /// Only matches operations on files with this name, either side of a
+    /// rename.
+    pub fn file_name(mut self, name: impl Into<String>) -> Fault {
+        self.file_name = Some(name.into());
+        self
+    }
+
+    /// Lets the first `count` matching operations succeed.
+    pub fn skip(mut self, count: usize) -> Fault {
+        self.skip = count;
+        self
+    }
+
+    /// Fails `count` matching operations instead of one.
+    pub fn times(mut self, count: usize) -> Fault {
+        self.times = count;
+        self
+    }
+
+    fn matches(&self, op: FsOp, paths: &[&Path]) -> bool {
+        self.op == op
+            && self.file_name.as_ref().is_none_or(|name| {
+                paths
+                    .iter()
+                    .any(|path| path.file_name().is_some_and(|file| file == name.as_str()))
+            })
+    }
+}
+
+type Faults = Arc<Mutex<Vec<Fault>>>;
+
+// Consumes the first fault matching the operation, returning how it fails.
+fn trigger(faults: &Faults, op: FsOp, paths: &[&Path]) -> Option<FaultKind> {
+    let mut faults = faults.lock().unwrap();
+    let index = faults.iter_mut().position(|fault| {
+        if !fault.matches(op, paths) {
+            return false;
+        }
+        if fault.skip > 0 {
+            fault.skip -= 1;
+            return false;
+        }
+        true
+    })?;
+    let kind = faults[index].kind;
+    faults[index].times -= 1;
+    if faults[index].times == 0 {
+        faults.remove(index);
+    }
+    Some(kind)
+}
+
+fn check(faults: &Faults, op: FsOp, paths: &[&Path]) -> io::Result<()> {
+    match trigger(faults, op, paths) {
+        Some(kind) => Err(kind.error()),
+        None => Ok(()),
+    }
+}
+
+/// A filesystem that fails the operations scripted with `inject` and passes
+/// every other one to the filesystem it wraps.
+#[derive(Debug, Clone)]
+pub struct FaultyFileSystem {
+    inner: Arc<dyn FileSystem>,
+    faults: Faults,
+}
+
+impl FaultyFileSystem {
+    pub fn new(inner: impl FileSystem + 'static) -> FaultyFileSystem {
+        FaultyFileSystem {
+            inner: Arc::new(inner),
+            faults: Arc::default(),
+        }
+    }
+
+    /// Schedules a failure, faults are matched in the order they were
+    /// injected.
+    pub fn inject(&self, fault: Fault) {
+        self.faults.lock().unwrap().push(fault);
+    }
+
+    /// Drops every fault that has not fired yet.
+    pub fn clear(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    /// Number of faults that have not fired yet.
+    pub fn pending(&self) -> usize {
+        self.faults.lock().unwrap().len()
+    }
+}
+
+impl FileSystem for FaultyFileSystem {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn FsFile>> {
+        check(&self.faults, FsOp::Open, &[path])?;
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.open(path, mode)?,
+            path: path.to_owned(),
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        check(&self.faults, FsOp::CreateDir, &[path])?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn list_dirs(&self, path: &Path) -> io::Result<Vec<String>> {
+        check(&self.faults, FsOp::Read, &[path])?;
+        self.inner.list_dirs(path)
+    }
+
+    fn list_files(&self, path: &Path) -> io::Result<Vec<String>> {
+        check(&self.faults, FsOp::Read, &[path])?;
+        self.inner.list_files(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        check(&self.faults, FsOp::Rename, &[from, to])?;
+        self.inner.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        check(&self.faults, FsOp::RemoveFile, &[path])?;
+        self.inner.remove_file(path)
+    }
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    inner: Box<dyn FsFile>,
+    path: PathBuf,
+    faults: Faults,
+}
+
+impl FaultyFile {
+    fn check(&self, op: FsOp) -> io::Result<()> {
+        check(&self.faults, op, &[&self.path])
+    }
+}
+
+impl Read for FaultyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check(FsOp::Read)?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match trigger(&self.faults, FsOp::Write, &[&self.path]) {
+            Some(FaultKind::ShortWrite(len)) => {
+                self.inner.write_all(&buf[..len.min(buf.len())])?;
+                Err(FaultKind::ShortWrite(len).error())
+            }
+            Some(kind) => Err(kind.error()),
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.check(FsOp::Flush)?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl FsFile for FaultyFile {
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.check(FsOp::SetLen)?;
+        self.inner.set_len(len)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.check(FsOp::Sync)?;
+        self.inner.sync_data()
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check(FsOp::Read)?;
+        self.inner.read_exact_at(buf, offset)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn FsFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.try_clone()?,
+            path: self.path.clone(),
+            faults: self.faults.clone(),
+        }))
+    }
+
+    fn map(&self) -> io::Result<Mapping> {
+        self.check(FsOp::Map)?;
+        self.inner.map()
+    }
+
+    fn id(&self) -> io::Result<Option<(u64, u64)>> {
+        self.inner.id()
+    }
+}
@@ -1,31 +1,109 @@
-use crate::KvsEngine;
+use super::filesystem::{FileSystem, FsFile, Mapping, OpenMode, OsFileSystem};
+use super::watch::Broadcaster;
+use super::{keyspace_dirs, validate_keyspace, KEYSPACES_DIR};
+use crate::{
+    Durability, EngineStats, KvsEngine, Limits, MergeOperator, MergeOperators, WatchEvent, Watcher,
+    DEFAULT_KEYSPACE,
+};
 use crate::{KvsError, Result};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
-use std::fs::{self, File};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
 use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::slice;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-const COMPACTION_THRESHOLD: u64 = 1024;
+// stale bytes the log may hold before it is compacted
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 const LOG_FILE_NAME: &str = "current.db";
+// Version of the on-disk layout, recorded in `FORMAT_FILE_NAME`:
+//
+// 1. JSON set and remove records in `current.db`, as written by the kvs-2
+//    CLI and by kvs-server before the layout was versioned. Such
+//    directories have no format file. Later unversioned builds also wrote
+//    checksums, merge records and named keyspaces.
+// 2. Every record carries a checksum and the format file is present.
+const FORMAT_VERSION: u32 = 2;
+const FORMAT_FILE_NAME: &str = "format";
+// longest run of merge records kept for a key before it is folded into a set
+const MAX_MERGE_CHAIN: usize = 32;
+// bytes the log has to grow past the mapped prefix before it is remapped
+const MMAP_REMAP_STEP: u64 = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct KvStore {
     inner: Arc<Mutex<InnerKvStore>>,
+    watchers: Arc<Broadcaster>,
+    keyspaces: Arc<Mutex<Keyspaces>>,
+    merge_operators: MergeOperators,
+}
+
+/// Configuration of a `KvStore`.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub durability: Durability,
+    /// Sets and merges over these limits fail with `KvsError::TooLarge`.
+    pub limits: Limits,
+    /// The filesystem holding the store, the one of the operating system by
+    /// default.
+    pub fs: Arc<dyn FileSystem>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            durability: Durability::default(),
+            limits: Limits::default(),
+            fs: Arc::new(OsFileSystem),
+        }
+    }
+}
+
+// Keyspaces opened so far, shared by every handle on the same directory.
+//
+// The default keyspace lives in the root of the directory, every other one
+// in its own subdirectory of `keyspaces`.
+#[derive(Debug)]
+struct Keyspaces {
+    root: PathBuf,
+    options: KvStoreOptions,
+    read_only: bool,
+    open: BTreeMap<String, (Arc<Mutex<InnerKvStore>>, Arc<Broadcaster>)>,
 }
 
 #[derive(Debug)]
 struct InnerKvStore {
+    fs: Arc<dyn FileSystem>,
     path: PathBuf,
-    log: File,
-    map: BTreeMap<String, LogPointer>,
+    durability: Durability,
+    limits: Limits,
+    read_only: bool,
+    // the operators of the merge records are looked up here when folded
+    merge_operators: MergeOperators,
+    log: Box<dyn FsFile>,
+    // offset up to which the log has been loaded into `map`
+    loaded: u64,
+    // read-only mapping of the sealed prefix of the log, records written
+    // after it was mapped are read from `log` until the next remap
+    mmap: RefCell<Option<Mapping>>,
+    map: BTreeMap<String, IndexEntry>,
+    // number of bytes in the log that compaction would reclaim
+    stale: u64,
+    compaction_count: u64,
+    compaction_time: Duration,
+    get_hits: u64,
+    get_misses: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 enum CommandType {
     Set,
     Rm,
+    Merge,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,6 +111,12 @@ struct Command {
     cmd: CommandType,
     key: String,
     value: String,
+    // only present on merge records, where `value` is the operand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    op: Option<MergeOperator>,
+    // older logs were written without checksums
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -41,166 +125,993 @@ struct LogPointer {
     len: u64,
 }
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let mut inner = self.inner.lock().unwrap();
+#[derive(Debug, Default)]
+struct IndexEntry {
+    // the last set of the key, if any
+    base: Option<LogPointer>,
+    // merge records written since `base`, oldest first
+    merges: Vec<LogPointer>,
+}
 
-        let offset = inner.log.seek(SeekFrom::End(0))?;
-        let command = Command {
-            cmd: CommandType::Set,
-            key: key.clone(),
+impl IndexEntry {
+    fn set(pointer: LogPointer) -> IndexEntry {
+        IndexEntry {
+            base: Some(pointer),
+            merges: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> u64 {
+        let base = self.base.as_ref().map_or(0, |pointer| pointer.len);
+        base + self.merges.iter().map(|pointer| pointer.len).sum::<u64>()
+    }
+}
+
+/// The operation recorded by a log record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogOp {
+    Set,
+    Remove,
+    Merge(MergeOperator),
+}
+
+/// A single record read back from a `KvStore` log.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    /// Byte offset of the record in the log file.
+    pub offset: u64,
+    /// Encoded length of the record in bytes.
+    pub len: u64,
+    pub op: LogOp,
+    pub key: String,
+    pub value: String,
+    /// `None` if the record was written without a checksum.
+    pub checksum_ok: Option<bool>,
+}
+
+/// The result of scanning a `KvStore` log from start to end.
+///
+/// Unreadable regions are skipped and reported in `corrupt` as
+/// `(offset, len)` pairs, so that the records around them can be salvaged.
+#[derive(Debug, Default)]
+pub struct LogScan {
+    pub records: Vec<LogRecord>,
+    pub corrupt: Vec<(u64, u64)>,
+    pub file_len: u64,
+}
+
+impl Command {
+    fn new(cmd: CommandType, key: String, value: String) -> Command {
+        let mut command = Command {
+            cmd,
+            key,
             value,
+            op: None,
+            checksum: None,
         };
-        // encoding before writing to log
-        serde_json::to_writer(&mut inner.log, &command)?;
-        inner.log.flush()?;
-        let current_offset = inner.log.seek(SeekFrom::End(0))?;
-        inner.map.insert(
+        command.checksum = Some(command.checksum());
+        command
+    }
+
+    fn merge(key: String, op: MergeOperator, operand: String) -> Command {
+        let mut command = Command {
+            cmd: CommandType::Merge,
             key,
-            LogPointer {
-                offset,
-                len: current_offset - offset,
-            },
-        );
-        if current_offset > COMPACTION_THRESHOLD {
-            inner.compact()?;
+            value: operand,
+            op: Some(op),
+            checksum: None,
+        };
+        command.checksum = Some(command.checksum());
+        command
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&[self.cmd as u8]);
+        // the built-in operators keep the tags they had as a fieldless enum
+        match &self.op {
+            Some(MergeOperator::Add) => hasher.update(&[0]),
+            Some(MergeOperator::Append) => hasher.update(&[1]),
+            Some(MergeOperator::Max) => hasher.update(&[2]),
+            Some(MergeOperator::JsonMerge) => hasher.update(&[3]),
+            Some(MergeOperator::Custom(name)) => {
+                hasher.update(&[4]);
+                hasher.update(name.as_bytes());
+                hasher.update(&[0]);
+            }
+            None => {}
         }
+        hasher.update(self.key.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(self.value.as_bytes());
+        hasher.finalize()
+    }
+
+    // Fails if the record at `offset` does not match its checksum. Records
+    // written without one cannot be checked.
+    fn verify(self, offset: u64) -> Result<Command> {
+        match self.checksum {
+            Some(sum) if sum != self.checksum() => Err(KvsError::StringError(format!(
+                "record at {} fails its checksum, `kvs-admin repair` drops it",
+                offset
+            ))),
+            _ => Ok(self),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
+        inner.limits.check_key(&key)?;
+        inner.limits.check_value(&value)?;
+
+        let command = Command::new(CommandType::Set, key.clone(), value);
+        let pointer = inner.append(&command)?;
+        if let Some(old) = inner.map.insert(key, IndexEntry::set(pointer)) {
+            inner.stale += old.len();
+        }
+        self.watchers.publish(WatchEvent::Set {
+            key: command.key,
+            value: command.value,
+        });
+        inner.compact_if_needed();
         Ok(())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.lock().unwrap().get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
         let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
 
         if !inner.map.contains_key(&key) {
-            return Ok(None);
+            return Err(KvsError::KeyNotFound);
+        }
+        let command = Command::new(CommandType::Rm, key.clone(), String::new());
+        let pointer = inner.append(&command)?;
+        if let Some(old) = inner.map.remove(&key) {
+            inner.stale += old.len() + pointer.len;
         }
+        self.watchers.publish(WatchEvent::Remove { key });
+        Ok(())
+    }
 
-        let pointer = inner.map.get(&key).unwrap();
-        let pos = SeekFrom::Start(pointer.offset);
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut inner = self.inner.lock().unwrap();
+        keys.iter().map(|key| inner.get(key)).collect()
+    }
 
-        inner.log.seek(pos)?;
-        let mut de = serde_json::Deserializer::from_reader(&inner.log);
-        let cmd: Command = serde::de::Deserialize::deserialize(&mut de)?;
-        Ok(Some(cmd.value))
+    // The records of a batch are written and synced together.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
+
+        let mut results = Vec::with_capacity(pairs.len());
+        let mut commands = Vec::new();
+        for (key, value) in pairs {
+            let checked = inner
+                .limits
+                .check_key(&key)
+                .and_then(|()| inner.limits.check_value(&value));
+            if checked.is_ok() {
+                commands.push(Command::new(CommandType::Set, key, value));
+            }
+            results.push(checked);
+        }
+        let pointers = inner.append_all(&commands)?;
+        for (command, pointer) in commands.into_iter().zip(pointers) {
+            if let Some(old) = inner
+                .map
+                .insert(command.key.clone(), IndexEntry::set(pointer))
+            {
+                inner.stale += old.len();
+            }
+            self.watchers.publish(WatchEvent::Set {
+                key: command.key,
+                value: command.value,
+            });
+        }
+        inner.compact_if_needed();
+        Ok(results)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
         let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
 
-        inner.log.seek(SeekFrom::End(0))?;
-        if inner.map.get(&key).is_none() {
-            return Err(KvsError::KeyNotFound);
+        let mut results = Vec::with_capacity(keys.len());
+        let mut commands = Vec::new();
+        let mut removed = HashSet::new();
+        for key in keys {
+            if inner.map.contains_key(&key) && removed.insert(key.clone()) {
+                commands.push(Command::new(CommandType::Rm, key, String::new()));
+                results.push(Ok(()));
+            } else {
+                results.push(Err(KvsError::KeyNotFound));
+            }
         }
-        let command = Command {
-            cmd: CommandType::Rm,
-            key: key.clone(),
-            value: String::new(),
+        let pointers = inner.append_all(&commands)?;
+        for (command, pointer) in commands.into_iter().zip(pointers) {
+            if let Some(old) = inner.map.remove(&command.key) {
+                inner.stale += old.len() + pointer.len;
+            }
+            self.watchers
+                .publish(WatchEvent::Remove { key: command.key });
+        }
+        Ok(results)
+    }
+
+    fn merge(&self, key: String, operator: MergeOperator, operand: String) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
+        inner.limits.check_key(&key)?;
+        inner.limits.check_value(&operand)?;
+
+        let (existing, chain) = match inner.map.get(&key) {
+            Some(entry) => (inner.value_of(entry)?, entry.merges.len()),
+            None => (None, 0),
         };
-        // encoding before writing to log
-        serde_json::to_writer(&mut inner.log, &command)?;
-        inner.log.flush()?;
-        inner.map.remove(&key);
-        Ok(())
+        // folding up front rejects operands that could never be read back
+        let value = self
+            .merge_operators
+            .apply(&operator, existing.as_deref(), &operand)?;
+        inner.limits.check_value(&value)?;
+
+        let command = if chain >= MAX_MERGE_CHAIN {
+            Command::new(CommandType::Set, key.clone(), value.clone())
+        } else {
+            Command::merge(key.clone(), operator, operand)
+        };
+        let pointer = inner.append(&command)?;
+        if command.cmd == CommandType::Set {
+            if let Some(old) = inner.map.insert(key.clone(), IndexEntry::set(pointer)) {
+                inner.stale += old.len();
+            }
+        } else {
+            inner
+                .map
+                .entry(key.clone())
+                .or_default()
+                .merges
+                .push(pointer);
+        }
+        self.watchers.publish(WatchEvent::Set {
+            key,
+            value: value.clone(),
+        });
+        inner.compact_if_needed();
+        Ok(value)
+    }
+
+    fn merge_operators(&self) -> &MergeOperators {
+        &self.merge_operators
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        // hold the store lock so no write is half published to the new watcher
+        let _inner = self.inner.lock().unwrap();
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn keyspace(&self, name: &str) -> Result<KvStore> {
+        validate_keyspace(name)?;
+        let mut keyspaces = self.keyspaces.lock().unwrap();
+
+        if !keyspaces.open.contains_key(name) {
+            let path = keyspaces.root.join(KEYSPACES_DIR).join(name);
+            let inner = if keyspaces.read_only {
+                InnerKvStore::open_read_only(path, &keyspaces.options, &self.merge_operators)?
+            } else {
+                InnerKvStore::open(path, &keyspaces.options, &self.merge_operators)?
+            };
+            let inner = Arc::new(Mutex::new(inner));
+            let watchers = Arc::new(Broadcaster::default());
+            keyspaces.open.insert(name.to_owned(), (inner, watchers));
+        }
+        let (inner, watchers) = &keyspaces.open[name];
+        Ok(KvStore {
+            inner: inner.clone(),
+            watchers: watchers.clone(),
+            keyspaces: self.keyspaces.clone(),
+            merge_operators: self.merge_operators.clone(),
+        })
+    }
+
+    fn has_keyspace(&self, name: &str) -> Result<bool> {
+        validate_keyspace(name)?;
+        let keyspaces = self.keyspaces.lock().unwrap();
+
+        let dir = keyspaces.root.join(KEYSPACES_DIR).join(name);
+        Ok(keyspaces.open.contains_key(name) || keyspaces.options.fs.exists(&dir))
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let keyspaces = self.keyspaces.lock().unwrap();
+
+        let mut names = keyspace_dirs(&*keyspaces.options.fs, &keyspaces.root)?;
+        names.extend(keyspaces.open.keys().cloned());
+        Ok(names.into_iter().collect())
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let inner = self.inner.lock().unwrap();
+
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let mut pairs = Vec::new();
+        for (key, entry) in inner.map.range::<str, _>((start, Bound::Unbounded)) {
+            if pairs.len() >= limit || !key.starts_with(prefix) {
+                break;
+            }
+            if let Some(value) = inner.value_of(entry)? {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let inner = self.inner.lock().unwrap();
+
+        let file_len = inner.log.len()?;
+        let live_bytes: u64 = inner.map.values().map(IndexEntry::len).sum();
+        Ok(EngineStats {
+            key_count: inner.map.len() as u64,
+            live_bytes,
+            stale_bytes: file_len - live_bytes,
+            segment_count: 1,
+            compaction_count: inner.compaction_count,
+            compaction_time: inner.compaction_time,
+            get_hits: inner.get_hits,
+            get_misses: inner.get_misses,
+        })
     }
 }
 
 impl KvStore {
+    /// Opens the store in the given directory, creating it if needed.
+    ///
+    /// Directories written by older versions are upgraded to the current
+    /// on-disk format first.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedFormat` if the directory was written
+    /// by a newer version.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_durability(path, Durability::default())
+    }
+
+    /// Opens the store like `open`, acknowledging writes according to the
+    /// given durability policy.
+    pub fn open_with_durability(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+    ) -> Result<KvStore> {
+        KvStore::open_with_options(
+            path,
+            KvStoreOptions {
+                durability,
+                ..KvStoreOptions::default()
+            },
+        )
+    }
+
+    /// Opens the store like `open` with the given configuration.
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path = path.into();
-        fs::create_dir_all(&*path)?;
-        let log = InnerKvStore::new_log_file(&path)?;
+        let fs = options.fs.clone();
+        let version = match read_format_version(&*fs, &path)? {
+            Some(version) if version > FORMAT_VERSION => {
+                return Err(KvsError::UnsupportedFormat(version))
+            }
+            Some(version) => Some(version),
+            None if fs.exists(&path.join(LOG_FILE_NAME)) => Some(1),
+            None => None,
+        };
 
+        let merge_operators = MergeOperators::default();
+        let inner = InnerKvStore::open(path.clone(), &options, &merge_operators)?;
+        let inner = Arc::new(Mutex::new(inner));
+        let watchers = Arc::new(Broadcaster::default());
+
+        let mut open = BTreeMap::new();
+        open.insert(
+            DEFAULT_KEYSPACE.to_owned(),
+            (inner.clone(), watchers.clone()),
+        );
+        let store = KvStore {
+            inner,
+            watchers,
+            keyspaces: Arc::new(Mutex::new(Keyspaces {
+                root: path.clone(),
+                options,
+                read_only: false,
+                open,
+            })),
+            merge_operators,
+        };
+        match version {
+            Some(FORMAT_VERSION) => {}
+            Some(version) => {
+                store.upgrade(version)?;
+                write_format_version(&*fs, &path)?;
+            }
+            None => write_format_version(&*fs, &path)?,
+        }
+        Ok(store)
+    }
+
+    /// Opens the store in the given directory without ever writing to it,
+    /// so it can be read while a server or another `KvStore` writes to the
+    /// same directory.
+    ///
+    /// The store is never compacted or upgraded, `set`, `remove` and `merge`
+    /// fail with `KvsError::ReadOnly`. It sees the records written before it
+    /// was opened until `refresh` is called.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Io` if the directory holds no log and
+    /// `KvsError::UnsupportedFormat` if it was written by a newer version.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_read_only_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store read-only like `open_read_only`, on the filesystem
+    /// and with the limits of `options`. Its durability is not used.
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        let path = path.into();
+        if let Some(version) = read_format_version(&*options.fs, &path)? {
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat(version));
+            }
+        }
+
+        let merge_operators = MergeOperators::default();
+        let inner = InnerKvStore::open_read_only(path.clone(), &options, &merge_operators)?;
+        let inner = Arc::new(Mutex::new(inner));
+        let watchers = Arc::new(Broadcaster::default());
+
+        let mut open = BTreeMap::new();
+        open.insert(
+            DEFAULT_KEYSPACE.to_owned(),
+            (inner.clone(), watchers.clone()),
+        );
+        Ok(KvStore {
+            inner,
+            watchers,
+            keyspaces: Arc::new(Mutex::new(Keyspaces {
+                root: path,
+                options,
+                read_only: true,
+                open,
+            })),
+            merge_operators,
+        })
+    }
+
+    /// Loads the records appended to this keyspace since it was opened or
+    /// last refreshed, starting over if the writer compacted the log in the
+    /// meantime. Other keyspaces are refreshed through their own handles.
+    ///
+    /// A store opened for writing is always up to date, so this does nothing.
+    pub fn refresh(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.read_only {
+            inner.refresh()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the directory from an older on-disk format to the current one.
+    fn upgrade(&self, from: u32) -> Result<()> {
+        if from < 2 {
+            // compaction rewrites every live record with a checksum
+            for name in self.keyspaces()? {
+                self.keyspace(&name)?.compact()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log so that it only contains live records.
+    pub fn compact(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.check_writable()?;
+        inner.compact()
+    }
+
+    /// Reads every record of the log in the given directory of `fs` without
+    /// opening the store, skipping over regions that cannot be decoded.
+    pub fn scan_log(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<LogScan> {
+        let bytes = fs.read(&path.as_ref().join(LOG_FILE_NAME))?;
+
+        let mut scan = LogScan {
+            file_len: bytes.len() as u64,
+            ..LogScan::default()
+        };
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let mut stream =
+                serde_json::Deserializer::from_slice(&bytes[pos..]).into_iter::<Command>();
+            let mut start = 0;
+            loop {
+                match stream.next() {
+                    Some(Ok(cmd)) => {
+                        let end = stream.byte_offset();
+                        let record =
+                            LogRecord::from_command((pos + start) as u64, cmd, (pos + end) as u64);
+                        scan.records.push(record);
+                        start = end;
+                    }
+                    Some(Err(_)) => break,
+                    None => {
+                        start = bytes.len() - pos;
+                        break;
+                    }
+                }
+            }
+            pos += start;
+            if pos >= bytes.len() {
+                break;
+            }
+            // resynchronise on the start of the next record
+            let skip_to = find_record_start(&bytes, pos + 1).unwrap_or(bytes.len());
+            scan.corrupt.push((pos as u64, (skip_to - pos) as u64));
+            pos = skip_to;
+        }
+        Ok(scan)
+    }
+}
+
+impl LogScan {
+    /// Replays the readable records in order and returns, for every key that
+    /// currently has a value, the records that value is folded from: its
+    /// last `set`, if any, followed by the merges written after it.
+    ///
+    /// Records that fail their checksum are ignored.
+    pub fn live_records(&self) -> BTreeMap<&str, Vec<&LogRecord>> {
+        let mut live: BTreeMap<&str, Vec<&LogRecord>> = BTreeMap::new();
+        for record in &self.records {
+            if record.checksum_ok == Some(false) {
+                continue;
+            }
+            match record.op {
+                LogOp::Set => {
+                    live.insert(record.key.as_str(), vec![record]);
+                }
+                LogOp::Remove => {
+                    live.remove(record.key.as_str());
+                }
+                LogOp::Merge(_) => live.entry(record.key.as_str()).or_default().push(record),
+            }
+        }
+        live
+    }
+
+    /// Returns the current value of every live key, along with the keys
+    /// whose merges cannot be folded.
+    ///
+    /// The log does not hold the functions of custom merge operators, so
+    /// keys merged with a `MergeOperator::Custom` are among the latter.
+    pub fn live_values(&self) -> (BTreeMap<String, String>, Vec<String>) {
+        let mut values = BTreeMap::new();
+        let mut unfolded = Vec::new();
+        for (key, records) in self.live_records() {
+            let mut value: Option<String> = None;
+            let folded = records.iter().try_for_each(|record| {
+                value = Some(match &record.op {
+                    LogOp::Merge(op) => op.apply(value.as_deref(), &record.value)?,
+                    _ => record.value.clone(),
+                });
+                Ok::<(), KvsError>(())
+            });
+            match (folded, value) {
+                (Ok(()), Some(value)) => {
+                    values.insert(key.to_owned(), value);
+                }
+                (Ok(()), None) => {}
+                (Err(_), _) => unfolded.push(key.to_owned()),
+            }
+        }
+        (values, unfolded)
+    }
+}
+
+fn read_format_version(fs: &dyn FileSystem, dir: &Path) -> Result<Option<u32>> {
+    let path = dir.join(FORMAT_FILE_NAME);
+    if !fs.exists(&path) {
+        return Ok(None);
+    }
+    let contents = String::from_utf8(fs.read(&path)?)?;
+    let version = contents.trim().parse().map_err(|_| {
+        KvsError::StringError(format!("invalid format version {:?}", contents.trim()))
+    })?;
+    Ok(Some(version))
+}
+
+// The file is replaced atomically so an interrupted upgrade is retried on
+// the next open.
+fn write_format_version(fs: &dyn FileSystem, dir: &Path) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", FORMAT_FILE_NAME));
+    fs.write(&tmp_path, FORMAT_VERSION.to_string().as_bytes())?;
+    fs.rename(&tmp_path, &dir.join(FORMAT_FILE_NAME))?;
+    Ok(())
+}
+
+fn find_record_start(bytes: &[u8], from: usize) -> Option<usize> {
+    const MARKER: &[u8] = b"{\"cmd\":";
+    if from >= bytes.len() {
+        return None;
+    }
+    bytes[from..]
+        .windows(MARKER.len())
+        .position(|w| w == MARKER)
+        .map(|i| from + i)
+}
+
+impl LogRecord {
+    fn from_command(offset: u64, cmd: Command, end: u64) -> LogRecord {
+        let checksum_ok = cmd.checksum.map(|sum| sum == cmd.checksum());
+        LogRecord {
+            offset,
+            len: end - offset,
+            op: match (cmd.cmd, cmd.op) {
+                (CommandType::Merge, Some(op)) => LogOp::Merge(op),
+                (CommandType::Rm, _) => LogOp::Remove,
+                _ => LogOp::Set,
+            },
+            key: cmd.key,
+            value: cmd.value,
+            checksum_ok,
+        }
+    }
+}
+
+impl InnerKvStore {
+    fn open(
+        path: PathBuf,
+        options: &KvStoreOptions,
+        merge_operators: &MergeOperators,
+    ) -> Result<InnerKvStore> {
+        options.fs.create_dir_all(&path)?;
+        let log = options
+            .fs
+            .open(&path.join(LOG_FILE_NAME), OpenMode::Append)?;
+        Self::load(path, options, merge_operators, log, false)
+    }
+
+    fn open_read_only(
+        path: PathBuf,
+        options: &KvStoreOptions,
+        merge_operators: &MergeOperators,
+    ) -> Result<InnerKvStore> {
+        let log = options.fs.open(&path.join(LOG_FILE_NAME), OpenMode::Read)?;
+        Self::load(path, options, merge_operators, log, true)
+    }
+
+    fn load(
+        path: PathBuf,
+        options: &KvStoreOptions,
+        merge_operators: &MergeOperators,
+        log: Box<dyn FsFile>,
+        read_only: bool,
+    ) -> Result<InnerKvStore> {
         let mut inner = InnerKvStore {
+            fs: options.fs.clone(),
             path,
+            durability: options.durability,
+            limits: options.limits,
+            read_only,
+            merge_operators: merge_operators.clone(),
             log,
+            loaded: 0,
+            mmap: RefCell::new(None),
             map: BTreeMap::new(),
+            stale: 0,
+            compaction_count: 0,
+            compaction_time: Duration::default(),
+            get_hits: 0,
+            get_misses: 0,
         };
 
         // Load from log files
         inner.load_from_log()?;
-
-        Ok(KvStore {
-            inner: Arc::new(Mutex::new(inner)),
-        })
+        Ok(inner)
     }
-}
 
-impl InnerKvStore {
+    // Indexes the records of the log past `loaded`.
     fn load_from_log(&mut self) -> Result<()> {
-        let mut reader = BufReader::new(File::open(&self.path.join(LOG_FILE_NAME))?);
-        let mut offset = reader.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(self.log.try_clone()?);
+        let start = reader.seek(SeekFrom::Start(self.loaded))?;
+        let mut offset = start;
         let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Command>();
 
         while let Some(cmd) = stream.next() {
-            let new_offset = stream.byte_offset() as u64;
+            let cmd = match cmd {
+                Ok(cmd) => cmd.verify(offset)?,
+                // the writer may be in the middle of appending this record
+                Err(e) if self.read_only && e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            let new_offset = start + stream.byte_offset() as u64;
+            let len = new_offset - offset;
             match cmd {
-                Ok(Command {
+                Command {
                     cmd: CommandType::Set,
                     key,
-                    value: _value,
-                }) => {
-                    self.map.insert(
-                        key,
-                        LogPointer {
-                            offset,
-                            len: new_offset - offset,
-                        },
-                    );
+                    ..
+                } => {
+                    let entry = IndexEntry::set(LogPointer { offset, len });
+                    if let Some(old) = self.map.insert(key, entry) {
+                        self.stale += old.len();
+                    }
                 }
-                Ok(Command {
+                Command {
                     cmd: CommandType::Rm,
                     key,
-                    value: _value,
-                }) => {
-                    self.map.remove(&key);
+                    ..
+                } => {
+                    if let Some(old) = self.map.remove(&key) {
+                        self.stale += old.len();
+                    }
+                    self.stale += len;
+                }
+                Command {
+                    cmd: CommandType::Merge,
+                    key,
+                    ..
+                } => {
+                    let pointer = LogPointer { offset, len };
+                    self.map.entry(key).or_default().merges.push(pointer);
                 }
-                _ => panic!(),
             }
             offset = new_offset;
         }
+        self.loaded = offset;
         Ok(())
     }
 
-    fn compact(&self) -> Result<()> {
-        let tmp_path: PathBuf = self.path.join("tmp.db");
-        let file_path: PathBuf = self.path.join(LOG_FILE_NAME);
-        let mut work_file = self.log.try_clone()?;
+    fn refresh(&mut self) -> Result<()> {
+        let log = self
+            .fs
+            .open(&self.path.join(LOG_FILE_NAME), OpenMode::Read)?;
+        let same_file = match (self.log.id()?, log.id()?) {
+            (Some(current), Some(latest)) => current == latest,
+            // without file ids every refresh reloads the whole log
+            _ => false,
+        };
+        if !same_file {
+            // the writer compacted the log into a new file
+            self.log = log;
+            self.mmap.replace(None);
+            self.map.clear();
+            self.stale = 0;
+            self.loaded = 0;
+        }
+        self.load_from_log()
+    }
 
-        let mut new_writer = BufWriter::new(
-            fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&tmp_path)?,
-        );
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // Compacts once enough of the log is stale, rather than once it is
+    // large, which would rewrite a store of live keys on every write. It
+    // runs after a write was appended, so a failure is only logged: the
+    // write went through, and the next write tries again.
+    fn compact_if_needed(&mut self) {
+        if self.stale > COMPACTION_THRESHOLD {
+            if let Err(e) = self.compact() {
+                warn!("Compaction of {} failed: {}", self.path.display(), e);
+            }
+        }
+    }
 
-        for (_, pointer) in self.map.iter() {
-            work_file.seek(SeekFrom::Start(pointer.offset))?;
-            let mut de = serde_json::Deserializer::from_reader(&work_file);
-            let cmd: Command = serde::de::Deserialize::deserialize(&mut de)?;
-            serde_json::to_writer(&mut new_writer, &cmd)?;
+    fn compact(&mut self) -> Result<()> {
+        let started = Instant::now();
+        let tmp_path = self.path.join("tmp.db");
+
+        // the current log stays in use until the new one fully replaced it
+        let written = self.write_compacted(&tmp_path).and_then(|compacted| {
+            self.fs.rename(&tmp_path, &self.path.join(LOG_FILE_NAME))?;
+            Ok(compacted)
+        });
+        let (new_map, new_len) = match written {
+            Ok(compacted) => compacted,
+            Err(e) => {
+                if let Err(remove_err) = self.fs.remove_file(&tmp_path) {
+                    warn!("Unable to remove {}: {}", tmp_path.display(), remove_err);
+                }
+                return Err(e);
+            }
+        };
+        // the old handle and mapping still refer to the replaced file, and
+        // the old index into it stays usable until the new log is open
+        self.log = self
+            .fs
+            .open(&self.path.join(LOG_FILE_NAME), OpenMode::Append)?;
+        self.map = new_map;
+        self.mmap.replace(None);
+        self.loaded = new_len;
+        self.stale = 0;
+        self.compaction_count += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+
+    // Writes every live key to a new log at `path`, returning its index and
+    // length.
+    fn write_compacted(&self, path: &Path) -> Result<(BTreeMap<String, IndexEntry>, u64)> {
+        let mut new_writer = BufWriter::new(self.fs.open(path, OpenMode::Create)?);
+
+        // merges are folded, so every key is rewritten as a single set,
+        // except for keys merged with operators that are not registered,
+        // whose records are copied as they are
+        let mut new_map = BTreeMap::new();
+        let mut new_offset = 0;
+        let mut write = |cmd: &Command| -> Result<LogPointer> {
+            let buf = serde_json::to_vec(cmd)?;
+            new_writer.write_all(&buf)?;
+            let pointer = LogPointer {
+                offset: new_offset,
+                len: buf.len() as u64,
+            };
+            new_offset += pointer.len;
+            Ok(pointer)
+        };
+        for (key, entry) in &self.map {
+            let merges = entry
+                .merges
+                .iter()
+                .map(|pointer| self.read_command(pointer))
+                .collect::<Result<Vec<_>>>()?;
+            let foldable = merges
+                .iter()
+                .all(|cmd| matches!(&cmd.op, Some(op) if self.merge_operators.resolves(op)));
+            let new_entry = if foldable {
+                let value = self.value_of(entry)?.unwrap_or_default();
+                IndexEntry::set(write(&Command::new(CommandType::Set, key.clone(), value))?)
+            } else {
+                let base = match &entry.base {
+                    Some(pointer) => Some(write(&self.read_command(pointer)?)?),
+                    None => None,
+                };
+                let merges = merges.iter().map(&mut write).collect::<Result<_>>()?;
+                IndexEntry { base, merges }
+            };
+            new_map.insert(key.clone(), new_entry);
+        }
+        new_writer.flush()?;
+        if self.durability == Durability::Sync {
+            new_writer.get_ref().sync_data()?;
         }
+        Ok((new_map, new_offset))
+    }
 
-        // TODO
-        // handle writes during or file locking, during compaction
-        // keep log/backup of file pre/post compaction, maybe by max file size
-        // fs::rename(work_file, self.path.join(format!("log-{}.db",i)))?;
-        fs::rename(tmp_path, file_path)?;
+    // Appends a record to the log. A record only partly written is cut off
+    // again, as it would hide every record appended after it.
+    fn append(&mut self, command: &Command) -> Result<LogPointer> {
+        Ok(self.append_all(slice::from_ref(command))?.remove(0))
+    }
+
+    // Appends records to the log with a single write, so that they are
+    // flushed and synced once. If the write fails, none of them is kept.
+    fn append_all(&mut self, commands: &[Command]) -> Result<Vec<LogPointer>> {
+        if commands.is_empty() {
+            return Ok(Vec::new());
+        }
+        let offset = self.log.seek(SeekFrom::End(0))?;
+        let mut buf = Vec::new();
+        let mut pointers = Vec::with_capacity(commands.len());
+        for command in commands {
+            let start = buf.len() as u64;
+            serde_json::to_writer(&mut buf, command)?;
+            pointers.push(LogPointer {
+                offset: offset + start,
+                len: buf.len() as u64 - start,
+            });
+        }
+        let written = self
+            .log
+            .write_all(&buf)
+            .map_err(KvsError::from)
+            .and_then(|()| self.flush_log());
+        if let Err(e) = written {
+            if let Err(truncate_err) = self.log.set_len(offset) {
+                error!(
+                    "Unable to remove a partly written record at {} of {}: {}",
+                    offset,
+                    self.path.join(LOG_FILE_NAME).display(),
+                    truncate_err
+                );
+            }
+            return Err(e);
+        }
+        Ok(pointers)
+    }
+
+    // Makes a record just appended to the log as durable as the policy asks.
+    fn flush_log(&mut self) -> Result<()> {
+        self.log.flush()?;
+        if self.durability == Durability::Sync {
+            self.log.sync_data()?;
+        }
         Ok(())
     }
 
-    fn log_path(path: &PathBuf, fname: String) -> PathBuf {
-        path.join(format!("{}", fname))
+    // Looks a key up, counting the lookup as a hit or a miss.
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let value = match self.map.get(key) {
+            Some(entry) => self.value_of(entry)?,
+            None => None,
+        };
+        match value {
+            Some(_) => self.get_hits += 1,
+            None => self.get_misses += 1,
+        }
+        Ok(value)
     }
 
-    fn new_log_file(path: &PathBuf) -> Result<File> {
-        let file = std::fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(Self::log_path(&path, LOG_FILE_NAME.to_string()))?;
+    fn read_command(&self, pointer: &LogPointer) -> Result<Command> {
+        let end = pointer.offset + pointer.len;
+        let mut mmap = self.mmap.borrow_mut();
+        let mapped = mmap.as_ref().map_or(0, |mmap| mmap.len() as u64);
+        if end > mapped + MMAP_REMAP_STEP {
+            // the mapped bytes never change while the mapping is alive:
+            // compaction replaces the log with a new file, and a failed
+            // append only cuts off what it wrote past the mapped length
+            *mmap = Some(self.log.map()?);
+        }
+        if let Some(mmap) = mmap.as_ref() {
+            if end <= mmap.len() as u64 {
+                let bytes = &mmap[pointer.offset as usize..end as usize];
+                return serde_json::from_slice::<Command>(bytes)?.verify(pointer.offset);
+            }
+        }
 
-        Ok(file)
+        let mut bytes = vec![0; pointer.len as usize];
+        self.log.read_exact_at(&mut bytes, pointer.offset)?;
+        serde_json::from_slice::<Command>(&bytes)?.verify(pointer.offset)
+    }
+
+    // Folds the merges of an entry onto its base value.
+    fn value_of(&self, entry: &IndexEntry) -> Result<Option<String>> {
+        let mut value = match &entry.base {
+            Some(pointer) => Some(self.read_command(pointer)?.value),
+            None => None,
+        };
+        for pointer in &entry.merges {
+            let cmd = self.read_command(pointer)?;
+            let op = cmd.op.ok_or_else(|| {
+                KvsError::StringError(format!(
+                    "merge record without operator at {}",
+                    pointer.offset
+                ))
+            })?;
+            value = Some(
+                self.merge_operators
+                    .apply(&op, value.as_deref(), &cmd.value)?,
+            );
+        }
+        Ok(value)
     }
 }
@@ -0,0 +1,626 @@
+//! A log-structured merge tree.
+//!
+//! Writes go to a write-ahead log and an in-memory memtable. Once the
+//! memtable is large enough it is written out as an immutable sorted table
+//! in level 0. Level 0 tables may overlap, deeper levels hold tables with
+//! disjoint key ranges and grow ten times larger per level. Compaction merges
+//! tables into the next level whenever a level outgrows its limit.
+//!
+//! The tables making up each level are listed in the `MANIFEST` file, which
+//! is replaced atomically after every flush and compaction.
+
+mod sstable;
+mod wal;
+
+use self::sstable::{SsTable, TableBuilder};
+use self::wal::Wal;
+use super::watch::Broadcaster;
+use super::{keyspace_dirs, validate_keyspace, FileSystem, OpenMode, OsFileSystem, KEYSPACES_DIR};
+use crate::{
+    Durability, EngineStats, KvsEngine, KvsError, Limits, MergeOperator, MergeOperators, Result,
+    WatchEvent, Watcher, DEFAULT_KEYSPACE,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const WAL_FILE_NAME: &str = "wal.log";
+const TABLE_EXTENSION: &str = "sst";
+// each level below level 1 may hold this many times more bytes
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+// A key and its value, `None` records a removed key.
+type Entry = (String, Option<String>);
+
+/// Configuration of an `LsmKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Size in bytes the memtable grows to before it is written as a table.
+    pub memtable_size: u64,
+    /// Size in bytes of the tables written by compaction.
+    pub table_size: u64,
+    /// Number of level 0 tables that triggers a compaction into level 1.
+    pub level0_tables: usize,
+    /// Maximum size in bytes of level 1, every deeper level holds ten
+    /// times more.
+    pub level_size_base: u64,
+    pub durability: Durability,
+    /// Sets and merges over these limits fail with `KvsError::TooLarge`.
+    pub limits: Limits,
+    /// The filesystem holding the engine, the one of the operating system
+    /// by default.
+    pub fs: Arc<dyn FileSystem>,
+}
+
+impl Default for LsmOptions {
+    fn default() -> LsmOptions {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level_size_base: 10 * 1024 * 1024,
+            durability: Durability::default(),
+            limits: Limits::default(),
+            fs: Arc::new(OsFileSystem),
+        }
+    }
+}
+
+/// A `KvsEngine` storing each keyspace in its own LSM tree.
+#[derive(Clone)]
+pub struct LsmKvsEngine {
+    tree: Arc<Mutex<LsmTree>>,
+    watchers: Arc<Broadcaster>,
+    limits: Limits,
+    keyspaces: Arc<Mutex<Keyspaces>>,
+    merge_operators: MergeOperators,
+}
+
+// Keyspaces opened so far, shared by every handle on the same directory.
+struct Keyspaces {
+    root: PathBuf,
+    options: LsmOptions,
+    open: BTreeMap<String, (Arc<Mutex<LsmTree>>, Arc<Broadcaster>)>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    next_id: u64,
+    // table ids of every level, newest first in level 0 and in key order
+    // below
+    levels: Vec<Vec<u64>>,
+}
+
+struct LsmTree {
+    fs: Arc<dyn FileSystem>,
+    dir: PathBuf,
+    options: LsmOptions,
+    wal: Wal,
+    memtable: BTreeMap<String, Option<String>>,
+    memtable_bytes: u64,
+    levels: Vec<Vec<Arc<SsTable>>>,
+    next_id: u64,
+    // position of the next table compacted out of each level, so that
+    // compactions rotate through the key space
+    cursors: Vec<usize>,
+    compaction_count: u64,
+    compaction_time: Duration,
+    get_hits: u64,
+    get_misses: u64,
+}
+
+impl LsmKvsEngine {
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmKvsEngine> {
+        LsmKvsEngine::open_with_options(path, LsmOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: LsmOptions,
+    ) -> Result<LsmKvsEngine> {
+        let path = path.into();
+        let tree = Arc::new(Mutex::new(LsmTree::open(path.clone(), options.clone())?));
+        let watchers = Arc::new(Broadcaster::default());
+
+        let mut open = BTreeMap::new();
+        open.insert(
+            DEFAULT_KEYSPACE.to_owned(),
+            (tree.clone(), watchers.clone()),
+        );
+        Ok(LsmKvsEngine {
+            tree,
+            watchers,
+            limits: options.limits,
+            keyspaces: Arc::new(Mutex::new(Keyspaces {
+                root: path,
+                options,
+                open,
+            })),
+            merge_operators: MergeOperators::default(),
+        })
+    }
+}
+
+impl KvsEngine for LsmKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
+        let mut tree = self.tree.lock().unwrap();
+        tree.put(key.clone(), Some(value.clone()))?;
+        self.watchers.publish(WatchEvent::Set { key, value });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let mut tree = self.tree.lock().unwrap();
+        let value = tree.get(&key)?;
+        match value {
+            Some(_) => tree.get_hits += 1,
+            None => tree.get_misses += 1,
+        }
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        if tree.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        tree.put(key.clone(), None)?;
+        self.watchers.publish(WatchEvent::Remove { key });
+        Ok(())
+    }
+
+    fn merge(&self, key: String, operator: MergeOperator, operand: String) -> Result<String> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&operand)?;
+        let mut tree = self.tree.lock().unwrap();
+        let existing = tree.get(&key)?;
+        let value = self
+            .merge_operators
+            .apply(&operator, existing.as_deref(), &operand)?;
+        self.limits.check_value(&value)?;
+        tree.put(key.clone(), Some(value.clone()))?;
+        self.watchers.publish(WatchEvent::Set {
+            key,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    fn merge_operators(&self) -> &MergeOperators {
+        &self.merge_operators
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        // hold the tree lock so no write is half published to the new watcher
+        let _tree = self.tree.lock().unwrap();
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    fn keyspace(&self, name: &str) -> Result<LsmKvsEngine> {
+        validate_keyspace(name)?;
+        let mut keyspaces = self.keyspaces.lock().unwrap();
+
+        if !keyspaces.open.contains_key(name) {
+            let path = keyspaces.root.join(KEYSPACES_DIR).join(name);
+            let tree = Arc::new(Mutex::new(LsmTree::open(path, keyspaces.options.clone())?));
+            let watchers = Arc::new(Broadcaster::default());
+            keyspaces.open.insert(name.to_owned(), (tree, watchers));
+        }
+        let (tree, watchers) = &keyspaces.open[name];
+        Ok(LsmKvsEngine {
+            tree: tree.clone(),
+            watchers: watchers.clone(),
+            limits: self.limits,
+            keyspaces: self.keyspaces.clone(),
+            merge_operators: self.merge_operators.clone(),
+        })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let keyspaces = self.keyspaces.lock().unwrap();
+
+        let mut names = keyspace_dirs(&*keyspaces.options.fs, &keyspaces.root)?;
+        names.extend(keyspaces.open.keys().cloned());
+        Ok(names.into_iter().collect())
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let tree = self.tree.lock().unwrap();
+
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.to_owned()),
+            _ => Bound::Included(prefix.to_owned()),
+        };
+        let mut pairs = Vec::new();
+        for entry in MergeIter::new(tree.sources(start)) {
+            let (key, value) = entry?;
+            if pairs.len() >= limit || !key.starts_with(prefix) {
+                break;
+            }
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let tree = self.tree.lock().unwrap();
+
+        let mut key_count = 0;
+        let mut live_bytes = 0;
+        for entry in MergeIter::new(tree.sources(Bound::Unbounded)) {
+            if let (key, Some(value)) = entry? {
+                key_count += 1;
+                live_bytes += (key.len() + value.len()) as u64;
+            }
+        }
+        let tables = tree.levels.iter().flatten();
+        let file_bytes =
+            tables.clone().map(|table| table.file_len()).sum::<u64>() + tree.wal.len()?;
+        Ok(EngineStats {
+            key_count,
+            live_bytes,
+            stale_bytes: file_bytes.saturating_sub(live_bytes),
+            segment_count: tables.count() as u64,
+            compaction_count: tree.compaction_count,
+            compaction_time: tree.compaction_time,
+            get_hits: tree.get_hits,
+            get_misses: tree.get_misses,
+        })
+    }
+}
+
+impl LsmTree {
+    fn open(dir: PathBuf, options: LsmOptions) -> Result<LsmTree> {
+        let fs = options.fs.clone();
+        fs.create_dir_all(&dir)?;
+        let manifest_path = dir.join(MANIFEST_FILE_NAME);
+        let manifest: Manifest = if fs.exists(&manifest_path) {
+            serde_json::from_slice(&fs.read(&manifest_path)?)?
+        } else {
+            Manifest::default()
+        };
+
+        let mut levels = Vec::new();
+        let mut live = BTreeSet::new();
+        for ids in &manifest.levels {
+            let mut level = Vec::new();
+            for &id in ids {
+                level.push(Arc::new(SsTable::open(&*fs, table_path(&dir, id), id)?));
+                live.insert(id);
+            }
+            levels.push(level);
+        }
+        // tables written by a flush or compaction that did not reach the
+        // manifest are garbage
+        for name in fs.list_files(&dir)? {
+            let path = dir.join(name);
+            if path.extension().is_some_and(|ext| ext == TABLE_EXTENSION) {
+                let id = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.parse().ok());
+                if !id.is_some_and(|id| live.contains(&id)) {
+                    fs.remove_file(&path)?;
+                }
+            }
+        }
+
+        let (wal, entries) = Wal::open(&*fs, &dir.join(WAL_FILE_NAME))?;
+        let mut tree = LsmTree {
+            fs,
+            dir,
+            options,
+            wal,
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            levels,
+            next_id: manifest.next_id,
+            cursors: Vec::new(),
+            compaction_count: 0,
+            compaction_time: Duration::default(),
+            get_hits: 0,
+            get_misses: 0,
+        };
+        for (key, value) in entries {
+            tree.memtable_bytes += entry_len(&key, &value);
+            tree.memtable.insert(key, value);
+        }
+        Ok(tree)
+    }
+
+    fn sync(&self) -> bool {
+        self.options.durability == Durability::Sync
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for (depth, level) in self.levels.iter().enumerate() {
+            if depth == 0 {
+                // level 0 tables overlap, the newest one wins
+                for table in level {
+                    if let Some(value) = table.get(key)? {
+                        return Ok(value);
+                    }
+                }
+                continue;
+            }
+            let i = level.partition_point(|table| table.last_key() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn put(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.wal.append(&key, value.as_deref(), self.sync())?;
+        self.memtable_bytes += entry_len(&key, &value);
+        self.memtable.insert(key, value);
+        if self.memtable_bytes >= self.options.memtable_size {
+            self.flush_memtable()?;
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Every entry from `start` on, one sorted source per overlapping run,
+    // newest first.
+    fn sources(&self, start: Bound<String>) -> Vec<Source<'_>> {
+        let mut sources: Vec<Source<'_>> = Vec::new();
+        let range = (start.as_ref().map(String::as_str), Bound::Unbounded);
+        sources.push(Box::new(
+            self.memtable
+                .range::<str, _>(range)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ));
+        for (depth, level) in self.levels.iter().enumerate() {
+            if depth == 0 {
+                for table in level {
+                    sources.push(Box::new(table.iter_from(start.clone())));
+                }
+            } else {
+                sources.push(level_iter(level, start.clone()));
+            }
+        }
+        sources
+    }
+
+    fn flush_memtable(&mut self) -> Result<()> {
+        let id = self.next_table_id();
+        let mut builder = TableBuilder::create(self.fs.clone(), table_path(&self.dir, id), id)?;
+        for (key, value) in &self.memtable {
+            builder.add(key.clone(), value.clone())?;
+        }
+        if let Some(table) = builder.finish(self.sync())? {
+            if self.levels.is_empty() {
+                self.levels.push(Vec::new());
+            }
+            self.levels[0].insert(0, Arc::new(table));
+        }
+        self.write_manifest()?;
+        // the entries are in a table now, replaying them again would be
+        // harmless but slow
+        self.wal.reset()?;
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+        Ok(())
+    }
+
+    // Compacts levels until every one is within its limit.
+    fn compact(&mut self) -> Result<()> {
+        loop {
+            let full = self.levels.iter().enumerate().position(|(depth, level)| {
+                if depth == 0 {
+                    level.len() >= self.options.level0_tables.max(1)
+                } else {
+                    level.iter().map(|table| table.file_len()).sum::<u64>()
+                        > self.level_limit(depth)
+                }
+            });
+            match full {
+                Some(depth) => self.compact_level(depth)?,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    fn level_limit(&self, depth: usize) -> u64 {
+        self.options.level_size_base * LEVEL_SIZE_MULTIPLIER.pow(depth as u32 - 1)
+    }
+
+    // Merges tables of `depth` with the overlapping tables of the next
+    // level: all of them for level 0, the next one in turn otherwise.
+    fn compact_level(&mut self, depth: usize) -> Result<()> {
+        let started = Instant::now();
+        let upper: Vec<Arc<SsTable>> = if depth == 0 {
+            self.levels[0].clone()
+        } else {
+            self.cursors.resize(self.levels.len(), 0);
+            let i = self.cursors[depth] % self.levels[depth].len();
+            self.cursors[depth] = i + 1;
+            vec![self.levels[depth][i].clone()]
+        };
+        if self.levels.len() == depth + 1 {
+            self.levels.push(Vec::new());
+        }
+        let first = upper
+            .iter()
+            .map(|table| table.first_key())
+            .min()
+            .unwrap()
+            .to_owned();
+        let last = upper
+            .iter()
+            .map(|table| table.last_key())
+            .max()
+            .unwrap()
+            .to_owned();
+        let (lower, mut kept): (Vec<_>, Vec<_>) =
+            self.levels[depth + 1].iter().cloned().partition(|table| {
+                table.last_key() >= first.as_str() && table.first_key() <= last.as_str()
+            });
+        // removals only need to be kept while older data may lie below
+        let bottom = self.levels[depth + 2..].iter().all(Vec::is_empty);
+
+        let mut sources: Vec<Source<'_>> = upper
+            .iter()
+            .map(|table| Box::new(table.iter_from(Bound::Unbounded)) as Source<'_>)
+            .collect();
+        sources.push(level_iter(&lower, Bound::Unbounded));
+        let mut outputs = Vec::new();
+        let mut builder: Option<TableBuilder> = None;
+        for entry in MergeIter::new(sources) {
+            let (key, value) = entry?;
+            if value.is_none() && bottom {
+                continue;
+            }
+            if builder.is_none() {
+                let id = self.next_table_id();
+                builder = Some(TableBuilder::create(
+                    self.fs.clone(),
+                    table_path(&self.dir, id),
+                    id,
+                )?);
+            }
+            let current = builder.as_mut().unwrap();
+            current.add(key, value)?;
+            if current.size() >= self.options.table_size {
+                outputs.extend(builder.take().unwrap().finish(self.sync())?);
+            }
+        }
+        if let Some(current) = builder {
+            outputs.extend(current.finish(self.sync())?);
+        }
+
+        let replaced: BTreeSet<u64> = upper.iter().chain(&lower).map(|table| table.id()).collect();
+        self.levels[depth].retain(|table| !replaced.contains(&table.id()));
+        kept.extend(outputs.into_iter().map(Arc::new));
+        kept.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+        self.levels[depth + 1] = kept;
+        self.write_manifest()?;
+        for table in upper.iter().chain(&lower) {
+            self.fs.remove_file(table.path())?;
+        }
+
+        self.compaction_count += 1;
+        self.compaction_time += started.elapsed();
+        Ok(())
+    }
+
+    fn next_table_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id()).collect())
+                .collect(),
+        };
+        let tmp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        let mut file = self.fs.open(&tmp_path, OpenMode::Create)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        if self.sync() {
+            file.sync_data()?;
+        }
+        drop(file);
+        self.fs
+            .rename(&tmp_path, &self.dir.join(MANIFEST_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, TABLE_EXTENSION))
+}
+
+fn entry_len(key: &str, value: &Option<String>) -> u64 {
+    (key.len() + value.as_ref().map_or(0, String::len)) as u64
+}
+
+type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+// The entries of a level below level 0, whose tables are disjoint and in
+// key order.
+fn level_iter(level: &[Arc<SsTable>], start: Bound<String>) -> Source<'static> {
+    let tables: Vec<Arc<SsTable>> = level
+        .iter()
+        .filter(|table| match &start {
+            Bound::Included(key) | Bound::Excluded(key) => table.last_key() >= key.as_str(),
+            Bound::Unbounded => true,
+        })
+        .cloned()
+        .collect();
+    Box::new(
+        tables
+            .into_iter()
+            .flat_map(move |table| table.iter_from(start.clone())),
+    )
+}
+
+// Merges sorted sources into one sorted stream. For a key found in several
+// sources, the entry of the earliest source wins.
+struct MergeIter<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<Source<'a>>) -> MergeIter<'a> {
+        MergeIter {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        let mut min: Option<(usize, String)> = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if min.as_ref().is_none_or(|(_, min_key)| key < min_key) => {
+                    min = Some((i, key.clone()));
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) => return source.next(),
+                None => {}
+            }
+        }
+
+        let (winner, key) = min?;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            if i != winner {
+                if let Some(Ok((other, _))) = source.peek() {
+                    if *other == key {
+                        source.next();
+                    }
+                }
+            }
+        }
+        self.sources[winner].next()
+    }
+}
@@ -0,0 +1,326 @@
+//! Immutable sorted tables.
+//!
+//! A table file holds a run of data blocks followed by the table metadata
+//! (block index, bloom filter and key range) and a fixed size footer:
+//!
+//! ```text
+//! | block 0 | block 1 | ... | metadata | metadata offset: u64 | magic: u64 |
+//! ```
+//!
+//! Blocks and metadata are bincode encoded, every block carries a crc32 in
+//! the index.
+
+use super::Entry;
+use crate::{FileSystem, FsFile, KvsError, Mapping, OpenMode, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// size in bytes a data block grows to before the next one is started
+const BLOCK_SIZE: usize = 4 * 1024;
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+const MAGIC: u64 = 0x4b56_534c_534d_0001;
+const FOOTER_LEN: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    first_key: String,
+    offset: u64,
+    len: u64,
+    checksum: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableMeta {
+    blocks: Vec<BlockHandle>,
+    last_key: String,
+    bloom: Bloom,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    fn new(key_hashes: &[u64]) -> Bloom {
+        let words = (key_hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(64);
+        let mut bloom = Bloom {
+            bits: vec![0; words.max(1)],
+            hashes: BLOOM_HASHES,
+        };
+        for &hash in key_hashes {
+            for bit in bloom.probes(hash) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    fn may_contain(&self, key: &str) -> bool {
+        self.probes(hash_key(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing on the two halves of the key hash
+    fn probes(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 64;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+// 64 bit FNV-1a, stable across builds unlike the standard library hasher
+fn hash_key(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn corrupt(path: &Path, what: &str) -> KvsError {
+    KvsError::StringError(format!("corrupt table {}: {}", path.display(), what))
+}
+
+/// An open, memory-mapped table.
+pub(super) struct SsTable {
+    id: u64,
+    path: PathBuf,
+    mmap: Mapping,
+    meta: TableMeta,
+}
+
+impl SsTable {
+    pub(super) fn open(fs: &dyn FileSystem, path: PathBuf, id: u64) -> Result<SsTable> {
+        // tables are never modified once written, and are only deleted
+        // after being replaced, which leaves the mapping valid
+        let mmap = fs.open(&path, OpenMode::Read)?.map()?;
+        if mmap.len() < FOOTER_LEN {
+            return Err(corrupt(&path, "file too short"));
+        }
+        let footer = &mmap[mmap.len() - FOOTER_LEN..];
+        let mut word = [0; 8];
+        word.copy_from_slice(&footer[8..]);
+        if u64::from_le_bytes(word) != MAGIC {
+            return Err(corrupt(&path, "bad magic number"));
+        }
+        word.copy_from_slice(&footer[..8]);
+        let meta_offset = u64::from_le_bytes(word) as usize;
+        if meta_offset > mmap.len() - FOOTER_LEN {
+            return Err(corrupt(&path, "bad metadata offset"));
+        }
+        let meta: TableMeta = bincode::deserialize(&mmap[meta_offset..mmap.len() - FOOTER_LEN])?;
+        if meta.blocks.is_empty() {
+            return Err(corrupt(&path, "no blocks"));
+        }
+        Ok(SsTable {
+            id,
+            path,
+            mmap,
+            meta,
+        })
+    }
+
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(super) fn file_len(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    pub(super) fn first_key(&self) -> &str {
+        &self.meta.blocks[0].first_key
+    }
+
+    pub(super) fn last_key(&self) -> &str {
+        &self.meta.last_key
+    }
+
+    /// Looks a key up, returning `None` if the table has no entry for it
+    /// and `Some(None)` if it records the key as removed.
+    pub(super) fn get(&self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key() || key > self.last_key() || !self.meta.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = self.read_block(self.block_for(key))?;
+        Ok(block
+            .binary_search_by(|(entry_key, _)| entry_key.as_str().cmp(key))
+            .ok()
+            .map(|i| block[i].1.clone()))
+    }
+
+    // index of the only block that can hold `key`
+    fn block_for(&self, key: &str) -> usize {
+        self.meta
+            .blocks
+            .partition_point(|block| block.first_key.as_str() <= key)
+            .saturating_sub(1)
+    }
+
+    fn read_block(&self, index: usize) -> Result<Vec<Entry>> {
+        let handle = &self.meta.blocks[index];
+        let bytes = &self.mmap[handle.offset as usize..(handle.offset + handle.len) as usize];
+        if crc32fast::hash(bytes) != handle.checksum {
+            return Err(corrupt(
+                &self.path,
+                &format!("checksum mismatch in block at {}", handle.offset),
+            ));
+        }
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    /// Iterates the entries of the table in key order, starting at `start`.
+    pub(super) fn iter_from(self: &Arc<Self>, start: Bound<String>) -> TableIter {
+        let next_block = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => self.block_for(key),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: self.clone(),
+            next_block,
+            entries: Vec::new().into_iter(),
+            start,
+        }
+    }
+}
+
+/// Iterator over the entries of a table, decoding one block at a time.
+pub(super) struct TableIter {
+    table: Arc<SsTable>,
+    next_block: usize,
+    entries: std::vec::IntoIter<Entry>,
+    // entries before this bound are skipped, it is cleared once passed
+    start: Bound<String>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                let passed = match &self.start {
+                    Bound::Included(start) => entry.0 >= *start,
+                    Bound::Excluded(start) => entry.0 > *start,
+                    Bound::Unbounded => true,
+                };
+                if passed {
+                    self.start = Bound::Unbounded;
+                    return Some(Ok(entry));
+                }
+                continue;
+            }
+            if self.next_block >= self.table.meta.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    self.next_block = self.table.meta.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+/// Writes a new table from entries added in increasing key order.
+pub(super) struct TableBuilder {
+    fs: Arc<dyn FileSystem>,
+    path: PathBuf,
+    id: u64,
+    writer: BufWriter<Box<dyn FsFile>>,
+    offset: u64,
+    blocks: Vec<BlockHandle>,
+    block: Vec<Entry>,
+    block_bytes: usize,
+    last_key: String,
+    key_hashes: Vec<u64>,
+}
+
+impl TableBuilder {
+    pub(super) fn create(fs: Arc<dyn FileSystem>, path: PathBuf, id: u64) -> Result<TableBuilder> {
+        let writer = BufWriter::new(fs.open(&path, OpenMode::Create)?);
+        Ok(TableBuilder {
+            fs,
+            path,
+            id,
+            writer,
+            offset: 0,
+            blocks: Vec::new(),
+            block: Vec::new(),
+            block_bytes: 0,
+            last_key: String::new(),
+            key_hashes: Vec::new(),
+        })
+    }
+
+    pub(super) fn add(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.block_bytes += key.len() + value.as_ref().map_or(0, String::len);
+        self.key_hashes.push(hash_key(&key));
+        self.last_key.clone_from(&key);
+        self.block.push((key, value));
+        if self.block_bytes >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    /// Approximate size of the table written so far.
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block_bytes as u64
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let bytes = bincode::serialize(&self.block)?;
+        self.writer.write_all(&bytes)?;
+        self.blocks.push(BlockHandle {
+            first_key: self.block[0].0.clone(),
+            offset: self.offset,
+            len: bytes.len() as u64,
+            checksum: crc32fast::hash(&bytes),
+        });
+        self.offset += bytes.len() as u64;
+        self.block.clear();
+        self.block_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes the metadata and opens the finished table, or removes the
+    /// file and returns `None` if no entry was added.
+    pub(super) fn finish(mut self, sync: bool) -> Result<Option<SsTable>> {
+        self.write_block()?;
+        if self.blocks.is_empty() {
+            drop(self.writer);
+            self.fs.remove_file(&self.path)?;
+            return Ok(None);
+        }
+        let meta = TableMeta {
+            blocks: self.blocks,
+            last_key: self.last_key,
+            bloom: Bloom::new(&self.key_hashes),
+        };
+        self.writer.write_all(&bincode::serialize(&meta)?)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        if sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        drop(self.writer);
+        Ok(Some(SsTable::open(&*self.fs, self.path, self.id)?))
+    }
+}
@@ -0,0 +1,79 @@
+//! Write-ahead log of the entries held in the memtable.
+//!
+//! Each record is a little endian `u32` length and `u32` crc32 followed by
+//! the bincode encoded entry. A torn record at the end of the log, left by a
+//! crash in the middle of a write, is dropped when the log is replayed.
+
+use super::Entry;
+use crate::{FileSystem, FsFile, OpenMode, Result};
+use std::path::Path;
+
+const HEADER_LEN: usize = 8;
+
+pub(super) struct Wal {
+    file: Box<dyn FsFile>,
+}
+
+impl Wal {
+    /// Opens the log at `path`, returning it with the entries it holds.
+    pub(super) fn open(fs: &dyn FileSystem, path: &Path) -> Result<(Wal, Vec<Entry>)> {
+        let bytes = if fs.exists(path) {
+            fs.read(path)?
+        } else {
+            Vec::new()
+        };
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while let Some(header) = bytes.get(pos..pos + HEADER_LEN) {
+            let mut word = [0; 4];
+            word.copy_from_slice(&header[..4]);
+            let len = u32::from_le_bytes(word) as usize;
+            word.copy_from_slice(&header[4..]);
+            let checksum = u32::from_le_bytes(word);
+
+            let start = pos + HEADER_LEN;
+            let record = match bytes.get(start..start + len) {
+                Some(record) if crc32fast::hash(record) == checksum => record,
+                _ => break,
+            };
+            entries.push(bincode::deserialize(record)?);
+            pos = start + len;
+        }
+
+        let file = fs.open(path, OpenMode::Append)?;
+        if pos < bytes.len() {
+            log::warn!(
+                "Dropping {} bytes of torn records at the end of {}",
+                bytes.len() - pos,
+                path.display()
+            );
+            file.set_len(pos as u64)?;
+        }
+        Ok((Wal { file }, entries))
+    }
+
+    pub(super) fn append(&mut self, key: &str, value: Option<&str>, sync: bool) -> Result<()> {
+        let record = bincode::serialize(&(key, value))?;
+        let mut buf = Vec::with_capacity(HEADER_LEN + record.len());
+        buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&record).to_le_bytes());
+        buf.extend_from_slice(&record);
+        // a single write so that a crash leaves at most one torn record
+        self.file.write_all(&buf)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Empties the log once its entries were written to a table.
+    pub(super) fn reset(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        Ok(())
+    }
+
+    pub(super) fn len(&self) -> Result<u64> {
+        Ok(self.file.len()?)
+    }
+}
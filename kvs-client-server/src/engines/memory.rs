@@ -0,0 +1,275 @@
+use super::validate_keyspace;
+use super::watch::Broadcaster;
+use crate::{
+    EngineStats, KvsEngine, KvsError, Limits, MergeOperator, MergeOperators, Result, WatchEvent,
+    Watcher, DEFAULT_KEYSPACE,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+const SNAPSHOT_FILE_NAME: &str = "memory.snapshot";
+
+/// A `KvsEngine` keeping all data in memory.
+///
+/// Data is lost when the last handle is dropped, unless the engine was
+/// opened on a directory with `open` and a snapshot was taken.
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    keyspace: Arc<MemoryKeyspace>,
+    shared: Arc<Shared>,
+    limits: Limits,
+}
+
+struct Shared {
+    keyspaces: Mutex<BTreeMap<String, Arc<MemoryKeyspace>>>,
+    snapshot_path: Option<PathBuf>,
+    merge_operators: MergeOperators,
+}
+
+#[derive(Default)]
+struct MemoryKeyspace {
+    map: RwLock<BTreeMap<String, String>>,
+    watchers: Broadcaster,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty engine without persistence.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::with_keyspaces(BTreeMap::new(), None)
+    }
+
+    /// Creates an engine that snapshots to the given directory, loading the
+    /// last snapshot written there if any.
+    pub fn open(path: impl Into<PathBuf>) -> Result<MemoryKvsEngine> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let snapshot_path = path.join(SNAPSHOT_FILE_NAME);
+
+        let mut keyspaces = BTreeMap::new();
+        if snapshot_path.exists() {
+            let snapshot: BTreeMap<String, BTreeMap<String, String>> =
+                serde_json::from_slice(&fs::read(&snapshot_path)?)?;
+            for (name, map) in snapshot {
+                let keyspace = MemoryKeyspace {
+                    map: RwLock::new(map),
+                    ..MemoryKeyspace::default()
+                };
+                keyspaces.insert(name, Arc::new(keyspace));
+            }
+        }
+        Ok(MemoryKvsEngine::with_keyspaces(
+            keyspaces,
+            Some(snapshot_path),
+        ))
+    }
+
+    fn with_keyspaces(
+        mut keyspaces: BTreeMap<String, Arc<MemoryKeyspace>>,
+        snapshot_path: Option<PathBuf>,
+    ) -> MemoryKvsEngine {
+        let keyspace = keyspaces
+            .entry(DEFAULT_KEYSPACE.to_owned())
+            .or_default()
+            .clone();
+        MemoryKvsEngine {
+            keyspace,
+            shared: Arc::new(Shared {
+                keyspaces: Mutex::new(keyspaces),
+                snapshot_path,
+                merge_operators: MergeOperators::default(),
+            }),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Makes sets and merges over `limits` fail with `KvsError::TooLarge`,
+    /// on this handle and the keyspaces opened from it.
+    pub fn with_limits(mut self, limits: Limits) -> MemoryKvsEngine {
+        self.limits = limits;
+        self
+    }
+
+    /// Writes every keyspace to the snapshot file of the directory the
+    /// engine was opened on.
+    ///
+    /// The file is replaced atomically. Engines created with `new` have
+    /// nowhere to write and return an error.
+    pub fn snapshot(&self) -> Result<()> {
+        let path = self.shared.snapshot_path.as_ref().ok_or_else(|| {
+            KvsError::StringError("engine was not opened on a directory".to_owned())
+        })?;
+        let snapshot: BTreeMap<String, BTreeMap<String, String>> = self
+            .shared
+            .keyspaces
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, keyspace)| (name.clone(), keyspace.map.read().unwrap().clone()))
+            .collect();
+
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&snapshot)?)?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Takes a snapshot every `interval` on a background thread, until the
+    /// last handle on the engine is dropped.
+    pub fn snapshot_every(&self, interval: Duration) {
+        let shared = Arc::downgrade(&self.shared);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let engine = match Weak::upgrade(&shared) {
+                Some(shared) => MemoryKvsEngine::from_shared(shared),
+                None => return,
+            };
+            if let Err(e) = engine.snapshot() {
+                log::error!("Snapshot failed: {}", e);
+            }
+        });
+    }
+
+    fn from_shared(shared: Arc<Shared>) -> MemoryKvsEngine {
+        let keyspace = shared.keyspaces.lock().unwrap()[DEFAULT_KEYSPACE].clone();
+        MemoryKvsEngine {
+            keyspace,
+            shared,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> MemoryKvsEngine {
+        MemoryKvsEngine::new()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
+        let mut map = self.keyspace.map.write().unwrap();
+        map.insert(key.clone(), value.clone());
+        self.keyspace
+            .watchers
+            .publish(WatchEvent::Set { key, value });
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let value = self.keyspace.map.read().unwrap().get(&key).cloned();
+        match value {
+            Some(_) => self.keyspace.get_hits.fetch_add(1, Ordering::Relaxed),
+            None => self.keyspace.get_misses.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut map = self.keyspace.map.write().unwrap();
+        map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        self.keyspace.watchers.publish(WatchEvent::Remove { key });
+        Ok(())
+    }
+
+    fn merge(&self, key: String, operator: MergeOperator, operand: String) -> Result<String> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&operand)?;
+        let mut map = self.keyspace.map.write().unwrap();
+        let value = self.shared.merge_operators.apply(
+            &operator,
+            map.get(&key).map(String::as_str),
+            &operand,
+        )?;
+        self.limits.check_value(&value)?;
+        map.insert(key.clone(), value.clone());
+        self.keyspace.watchers.publish(WatchEvent::Set {
+            key,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    fn merge_operators(&self) -> &MergeOperators {
+        &self.shared.merge_operators
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        // hold the write lock so no write is half published to the new watcher
+        let _map = self.keyspace.map.write().unwrap();
+        Ok(self.keyspace.watchers.subscribe(prefix))
+    }
+
+    fn keyspace(&self, name: &str) -> Result<MemoryKvsEngine> {
+        validate_keyspace(name)?;
+        let keyspace = self
+            .shared
+            .keyspaces
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+        Ok(MemoryKvsEngine {
+            keyspace,
+            shared: self.shared.clone(),
+            limits: self.limits,
+        })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        Ok(self
+            .shared
+            .keyspaces
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect())
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        Ok(self
+            .keyspace
+            .map
+            .read()
+            .unwrap()
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let map = self.keyspace.map.read().unwrap();
+        Ok(EngineStats {
+            key_count: map.len() as u64,
+            live_bytes: map
+                .iter()
+                .map(|(key, value)| (key.len() + value.len()) as u64)
+                .sum(),
+            get_hits: self.keyspace.get_hits.load(Ordering::Relaxed),
+            get_misses: self.keyspace.get_misses.load(Ordering::Relaxed),
+            ..EngineStats::default()
+        })
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// An associative operator that combines a key's current value with an
+/// operand.
+///
+/// Engines store merges as operands and fold them lazily, so every operator
+/// must give the same result however a run of merges is grouped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MergeOperator {
+    /// Adds an integer operand to an integer value, missing values count as 0.
+    Add,
+    /// Appends the operand to the value.
+    Append,
+    /// Keeps the larger of two integers.
+    Max,
+    /// Applies the operand as a JSON merge patch (RFC 7386) to the value.
+    JsonMerge,
+    /// The operator registered under this name in the `MergeOperators` of
+    /// the engine.
+    Custom(String),
+}
+
+impl MergeOperator {
+    /// Combines `existing` with `operand`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidMerge` if either side cannot be parsed
+    /// as the operator requires, and for every `Custom` operator, which
+    /// only `MergeOperators::apply` can look up.
+    pub fn apply(&self, existing: Option<&str>, operand: &str) -> Result<String> {
+        match self {
+            MergeOperator::Add => {
+                let sum = parse_int(existing.unwrap_or("0"))?
+                    .checked_add(parse_int(operand)?)
+                    .ok_or_else(|| KvsError::InvalidMerge("integer overflow".to_owned()))?;
+                Ok(sum.to_string())
+            }
+            MergeOperator::Append => Ok(format!("{}{}", existing.unwrap_or(""), operand)),
+            MergeOperator::Max => {
+                let operand = parse_int(operand)?;
+                let max = match existing {
+                    Some(existing) => parse_int(existing)?.max(operand),
+                    None => operand,
+                };
+                Ok(max.to_string())
+            }
+            MergeOperator::JsonMerge => {
+                let mut target = match existing {
+                    Some(existing) => parse_json(existing)?,
+                    None => Value::Null,
+                };
+                merge_patch(&mut target, parse_json(operand)?);
+                Ok(target.to_string())
+            }
+            MergeOperator::Custom(name) => Err(unregistered(name)),
+        }
+    }
+}
+
+impl FromStr for MergeOperator {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "add" => Ok(MergeOperator::Add),
+            "append" => Ok(MergeOperator::Append),
+            "max" => Ok(MergeOperator::Max),
+            "json" => Ok(MergeOperator::JsonMerge),
+            "" => Err(KvsError::InvalidMerge("empty operator name".to_owned())),
+            name => Ok(MergeOperator::Custom(name.to_owned())),
+        }
+    }
+}
+
+type MergeFn = dyn Fn(Option<&str>, &str) -> Result<String> + Send + Sync;
+
+/// The custom operators of an engine, which `MergeOperator::Custom` names.
+///
+/// Clones share their operators, so an operator registered through one
+/// handle on an engine applies to every handle and keyspace of it.
+#[derive(Clone, Default)]
+pub struct MergeOperators {
+    functions: Arc<RwLock<BTreeMap<String, Arc<MergeFn>>>>,
+}
+
+impl MergeOperators {
+    /// Registers `function` as the operator `MergeOperator::Custom(name)`,
+    /// replacing the one registered under `name` before.
+    ///
+    /// `function` combines the current value of a key, `None` if it has
+    /// none, with an operand. Like the built-in operators it must be
+    /// associative, and fail with `KvsError::InvalidMerge` on input it
+    /// cannot combine.
+    pub fn register<F>(&self, name: impl Into<String>, function: F)
+    where
+        F: Fn(Option<&str>, &str) -> Result<String> + Send + Sync + 'static,
+    {
+        let mut functions = self.functions.write().unwrap();
+        functions.insert(name.into(), Arc::new(function));
+    }
+
+    /// Whether `operator` is built in or registered.
+    pub fn resolves(&self, operator: &MergeOperator) -> bool {
+        match operator {
+            MergeOperator::Custom(name) => self.functions.read().unwrap().contains_key(name),
+            _ => true,
+        }
+    }
+
+    /// Combines `existing` with `operand` like `MergeOperator::apply`,
+    /// looking `Custom` operators up among the registered ones.
+    pub fn apply(
+        &self,
+        operator: &MergeOperator,
+        existing: Option<&str>,
+        operand: &str,
+    ) -> Result<String> {
+        let name = match operator {
+            MergeOperator::Custom(name) => name,
+            operator => return operator.apply(existing, operand),
+        };
+        // released before the call, which may take a while
+        let function = self.functions.read().unwrap().get(name).cloned();
+        match function {
+            Some(function) => function(existing, operand),
+            None => Err(unregistered(name)),
+        }
+    }
+}
+
+impl fmt::Debug for MergeOperators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let functions = self.functions.read().unwrap();
+        f.debug_set().entries(functions.keys()).finish()
+    }
+}
+
+fn unregistered(name: &str) -> KvsError {
+    KvsError::InvalidMerge(format!("unknown operator {}", name))
+}
+
+fn parse_int(value: &str) -> Result<i64> {
+    value
+        .parse()
+        .map_err(|_| KvsError::InvalidMerge(format!("{:?} is not an integer", value)))
+}
+
+fn parse_json(value: &str) -> Result<Value> {
+    serde_json::from_str(value)
+        .map_err(|_| KvsError::InvalidMerge(format!("{:?} is not valid JSON", value)))
+}
+
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            if let Value::Object(target) = target {
+                for (key, value) in patch {
+                    if value.is_null() {
+                        target.remove(&key);
+                    } else {
+                        merge_patch(target.entry(key).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
@@ -1,4 +1,172 @@
-use crate::Result;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Name of the keyspace engines use when none is selected.
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+/// File recording which engine a data directory was written by.
+pub const ENGINE_MARKER: &str = "engine";
+
+/// The storage engines a data directory can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Kvs,
+    Sled,
+    Memory,
+    Lsm,
+}
+
+impl Engine {
+    /// Reads the engine marker of a data directory, returning `None` if the
+    /// directory has none.
+    pub fn read_marker(dir: &Path) -> Result<Option<Engine>> {
+        let path = dir.join(ENGINE_MARKER);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_to_string(path)?.trim().parse()?))
+    }
+
+    /// Records this engine in the marker of a data directory.
+    ///
+    /// The marker is replaced atomically, so readers see either the old
+    /// engine or the new one.
+    pub fn write_marker(self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", ENGINE_MARKER));
+        fs::write(&tmp_path, self.to_string())?;
+        fs::rename(tmp_path, dir.join(ENGINE_MARKER))?;
+        Ok(())
+    }
+}
+
+impl FromStr for Engine {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            "memory" => Ok(Engine::Memory),
+            "lsm" => Ok(Engine::Lsm),
+            _ => Err(KvsError::EngineNotFound),
+        }
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+            Engine::Memory => write!(f, "memory"),
+            Engine::Lsm => write!(f, "lsm"),
+        }
+    }
+}
+
+/// When a write is acknowledged relative to it reaching the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Every write is synced to disk before it is acknowledged.
+    Sync,
+    /// Every write is handed to the operating system before it is
+    /// acknowledged, so it survives a crash of the process but not of the
+    /// machine. sled cannot flush without syncing, so it behaves like `Sync`.
+    #[default]
+    Flush,
+    /// Writes are acknowledged once the engine has buffered them and are
+    /// flushed in the background, so a crash loses the most recent ones.
+    /// `KvStore` and `LsmKvsEngine` do not buffer writes, so they behave like
+    /// `Flush`.
+    Background,
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sync" => Ok(Durability::Sync),
+            "flush" => Ok(Durability::Flush),
+            "background" => Ok(Durability::Background),
+            _ => Err(KvsError::StringError(format!(
+                "unknown durability policy {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Maximum sizes in bytes of the keys and values an engine or server
+/// accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_size: u64,
+    pub max_value_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_key_size: 64 * 1024,
+            max_value_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+/// What went over a size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LimitKind {
+    Key,
+    Value,
+    Request,
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitKind::Key => write!(f, "key"),
+            LimitKind::Value => write!(f, "value"),
+            LimitKind::Request => write!(f, "request"),
+        }
+    }
+}
+
+impl Limits {
+    /// Maximum size of an encoded request carrying a key and a value within
+    /// the limits. JSON escapes a byte as at most six.
+    ///
+    /// Batches are held to it as a whole, so the pairs of a `MultiSet` may
+    /// together be no larger than one key and one value at their limits.
+    pub fn max_request_size(&self) -> u64 {
+        (self.max_key_size + self.max_value_size) * 6 + 4096
+    }
+
+    pub(crate) fn check_key(&self, key: &str) -> Result<()> {
+        check(LimitKind::Key, key.len(), self.max_key_size)
+    }
+
+    pub(crate) fn check_value(&self, value: &str) -> Result<()> {
+        check(LimitKind::Value, value.len(), self.max_value_size)
+    }
+}
+
+fn check(what: LimitKind, size: usize, limit: u64) -> Result<()> {
+    if size as u64 > limit {
+        return Err(KvsError::TooLarge {
+            what,
+            size: size as u64,
+            limit,
+        });
+    }
+    Ok(())
+}
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -18,10 +186,192 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Gets the values of several keys, in the order of `keys`.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Sets several keys in order and returns the result of each pair.
+    ///
+    /// A pair failing, for instance by being over the size limits, does not
+    /// keep the others from being set.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| self.set(key, value))
+            .collect())
+    }
+
+    /// Removes several keys in order and returns the result of each key,
+    /// `KvsError::KeyNotFound` for the keys that do not exist.
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        Ok(keys.into_iter().map(|key| self.remove(key)).collect())
+    }
+
+    /// Atomically combines the value of a key with `operand` and returns
+    /// the new value.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidMerge` if the current value or the
+    /// operand is not valid input for `operator`, or if `operator` is a
+    /// `MergeOperator::Custom` not registered in `merge_operators`.
+    fn merge(&self, key: String, operator: MergeOperator, operand: String) -> Result<String>;
+
+    /// The custom merge operators of the store, shared by all its handles
+    /// and keyspaces.
+    ///
+    /// Engines that fold merges lazily, like `KvStore`, need the operators
+    /// of the merges they hold registered before those keys are read.
+    fn merge_operators(&self) -> &MergeOperators;
+
+    /// Atomically adds `delta` to an integer key, treating a missing key as
+    /// 0, and returns the new value.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let value = self.merge(key, MergeOperator::Add, delta.to_string())?;
+        value
+            .parse()
+            .map_err(|_| KvsError::InvalidMerge(format!("{:?} is not an integer", value)))
+    }
+
+    /// Subscribes to changes of every key starting with `prefix`.
+    ///
+    /// The returned `Watcher` yields one event per `set` or `remove` made
+    /// after this call returns. It ends if it falls more than
+    /// `WATCH_BACKLOG` events behind.
+    fn watch(&self, prefix: String) -> Result<Watcher>;
+
+    /// Returns a handle on the named keyspace of the same store, creating
+    /// the keyspace if needed.
+    ///
+    /// Keyspaces are independent: each has its own keys, watchers and
+    /// statistics. `DEFAULT_KEYSPACE` names the keyspace that `open` returns.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidKeyspace` if the name is empty, longer
+    /// than 64 bytes, contains characters other than ASCII letters, digits,
+    /// `-` and `_`, or starts with `__sled__`, which sled reserves for its
+    /// own trees.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Returns whether the named keyspace exists, without creating it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidKeyspace` for the names `keyspace`
+    /// refuses.
+    fn has_keyspace(&self, name: &str) -> Result<bool> {
+        validate_keyspace(name)?;
+        Ok(self.keyspaces()?.iter().any(|existing| existing == name))
+    }
+
+    /// Returns the names of every keyspace in the store, including
+    /// `DEFAULT_KEYSPACE`, in sorted order.
+    fn keyspaces(&self) -> Result<Vec<String>>;
+
+    /// Returns up to `limit` key-value pairs whose key starts with
+    /// `prefix`, in key order.
+    ///
+    /// Passing the last key of a previous call as `after` continues the
+    /// listing where that call stopped.
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>>;
+
+    /// Returns a snapshot of the engine's statistics.
+    fn stats(&self) -> Result<EngineStats>;
+}
+
+/// Statistics reported by a `KvsEngine`.
+///
+/// Counters that an engine cannot observe are left at zero.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// Number of live keys.
+    pub key_count: u64,
+    /// Bytes on disk holding live data.
+    pub live_bytes: u64,
+    /// Bytes on disk that compaction can reclaim.
+    pub stale_bytes: u64,
+    /// Number of log segments or files backing the store.
+    pub segment_count: u64,
+    /// Number of compactions run since the engine was opened.
+    pub compaction_count: u64,
+    /// Total time spent compacting since the engine was opened.
+    pub compaction_time: Duration,
+    /// `get` calls that found their key.
+    pub get_hits: u64,
+    /// `get` calls for a key that does not exist.
+    pub get_misses: u64,
+}
+
+impl EngineStats {
+    /// Fraction of `get` calls that found their key, or `None` before the
+    /// first `get`.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.get_hits + self.get_misses;
+        if total == 0 {
+            None
+        } else {
+            Some(self.get_hits as f64 / total as f64)
+        }
+    }
+}
+
+// Prefix of the names sled gives its own trees, such as `__sled__default`.
+const SLED_RESERVED_PREFIX: &str = "__sled__";
+
+fn validate_keyspace(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with(SLED_RESERVED_PREFIX)
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidKeyspace(name.to_owned()))
+    }
+}
+
+// Engines that keep each named keyspace in its own directory put them under
+// this subdirectory of their root.
+const KEYSPACES_DIR: &str = "keyspaces";
+
+// Names of the keyspace directories found under `root`.
+fn keyspace_dirs(fs: &dyn FileSystem, root: &Path) -> Result<BTreeSet<String>> {
+    let dir = root.join(KEYSPACES_DIR);
+    if !fs.exists(&dir) {
+        return Ok(BTreeSet::new());
+    }
+    Ok(fs
+        .list_dirs(&dir)?
+        .into_iter()
+        .filter(|name| validate_keyspace(name).is_ok())
+        .collect())
 }
 
+mod filesystem;
 mod kvs;
+mod lsm;
+mod memory;
+mod merge;
 mod sled;
+mod watch;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::filesystem::{FileSystem, FsFile, Mapping, OpenMode, OsFileSystem};
+// scaffolding for the tests, not part of the API
+#[doc(hidden)]
+pub use self::filesystem::{Fault, FaultKind, FaultyFileSystem, FsOp, MemoryFileSystem};
+pub use self::kvs::{KvStore, KvStoreOptions, LogOp, LogRecord, LogScan};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
+pub use self::merge::{MergeOperator, MergeOperators};
+pub use self::sled::{SledKvsEngine, SledMode, SledOptions};
+pub use self::watch::{WatchEvent, Watcher, WATCH_BACKLOG};
@@ -1,38 +1,374 @@
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use super::validate_keyspace;
+use super::watch::Broadcaster;
+use crate::{
+    Durability, EngineStats, KvsEngine, KvsError, Limits, MergeOperator, MergeOperators, Result,
+    WatchEvent, Watcher, DEFAULT_KEYSPACE,
+};
+use sled::{Batch, Db, Event, SegmentMode, Subscriber, Tree};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Bound;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
 
+/// Keyspaces other than the default one are stored as separate sled trees.
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    tree: Db,
+    db: Db,
+    tree: Tree,
+    durability: Durability,
+    limits: Limits,
+    state: Arc<KeyspaceState>,
+    // state of every keyspace opened so far, shared by all handles
+    keyspaces: Arc<Mutex<HashMap<String, Arc<KeyspaceState>>>>,
+    merge_operators: MergeOperators,
+}
+
+// What the handles on a keyspace share besides its tree.
+#[derive(Default)]
+struct KeyspaceState {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    watchers: Broadcaster,
+    // whether the first watch of the keyspace started forwarding its events
+    forwarding: Mutex<bool>,
+}
+
+/// How sled lays out its segment files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SledMode {
+    /// Reuses segments once their contents were relocated.
+    LowSpace,
+    /// Always writes to the end of the log.
+    HighThroughput,
+}
+
+impl FromStr for SledMode {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "low-space" => Ok(SledMode::LowSpace),
+            "high-throughput" => Ok(SledMode::HighThroughput),
+            _ => Err(KvsError::StringError(format!("unknown sled mode {}", s))),
+        }
+    }
+}
+
+/// Configuration of a `SledKvsEngine`.
+#[derive(Debug, Clone)]
+pub struct SledOptions {
+    /// Maximum size in bytes of sled's page cache.
+    pub cache_capacity: u64,
+    /// Milliseconds between background flushes, `None` disables them.
+    pub flush_every_ms: Option<u64>,
+    /// Compresses stored data with zstd. This needs the `compression`
+    /// feature, opening the engine fails without it.
+    pub use_compression: bool,
+    pub mode: SledMode,
+    pub durability: Durability,
+    /// Sets and merges over these limits fail with `KvsError::TooLarge`.
+    pub limits: Limits,
+}
+
+impl Default for SledOptions {
+    fn default() -> SledOptions {
+        SledOptions {
+            cache_capacity: 1024 * 1024 * 1024,
+            flush_every_ms: Some(500),
+            use_compression: false,
+            mode: SledMode::LowSpace,
+            durability: Durability::default(),
+            limits: Limits::default(),
+        }
+    }
 }
 
 impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let tree = sled::open(path.into())?;
-        Ok(SledKvsEngine { tree })
+        SledKvsEngine::open_with_options(path, SledOptions::default())
+    }
+
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: SledOptions,
+    ) -> Result<SledKvsEngine> {
+        let segment_mode = match options.mode {
+            SledMode::LowSpace => SegmentMode::Gc,
+            SledMode::HighThroughput => SegmentMode::Linear,
+        };
+        let db = sled::Config::new()
+            .path(path.into())
+            .cache_capacity(options.cache_capacity)
+            .flush_every_ms(options.flush_every_ms)
+            .use_compression(options.use_compression)
+            .segment_mode(segment_mode)
+            .open()?;
+        let state = Arc::new(KeyspaceState::default());
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(DEFAULT_KEYSPACE.to_owned(), state.clone());
+        Ok(SledKvsEngine {
+            tree: (*db).clone(),
+            db,
+            durability: options.durability,
+            limits: options.limits,
+            state,
+            keyspaces: Arc::new(Mutex::new(keyspaces)),
+            merge_operators: MergeOperators::default(),
+        })
+    }
+
+    // sled flushes in the background on its own, waiting for it is only
+    // needed when writes must be durable once acknowledged
+    fn flush(&self) -> Result<()> {
+        if self.durability != Durability::Background {
+            self.tree.flush()?;
+        }
+        Ok(())
+    }
+
+    // Removes `key` if it is there. A plain sled remove reports the removal
+    // of a missing key to subscribers too.
+    fn remove_existing(&self, key: &str) -> Result<bool> {
+        loop {
+            let old = match self.tree.get(key)? {
+                Some(old) => old,
+                None => return Ok(false),
+            };
+            if self
+                .tree
+                .compare_and_swap(key, Some(old), None::<&[u8]>)?
+                .is_ok()
+            {
+                return Ok(true);
+            }
+        }
     }
 }
 
 impl KvsEngine for SledKvsEngine {
     fn set(&self, key: String, value: String) -> Result<()> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&value)?;
         self.tree.insert(key, value.as_bytes())?;
-        self.tree.flush()?;
-        Ok(())
+        self.flush()
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self
-            .tree
-            .get(key)?
+        let value = self.tree.get(key)?;
+        match value {
+            Some(_) => self.state.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.state.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        Ok(value
             .map(|v| v.to_vec())
             .map(String::from_utf8)
             .transpose()?)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        self.tree.flush()?;
-        Ok(())
+        if !self.remove_existing(&key)? {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.flush()
+    }
+
+    // The pairs go through a sled batch, so they reach the disk together.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<Vec<Result<()>>> {
+        let mut batch = Batch::default();
+        let mut results = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let checked = self
+                .limits
+                .check_key(&key)
+                .and_then(|()| self.limits.check_value(&value));
+            if checked.is_ok() {
+                batch.insert(key.into_bytes(), value.into_bytes());
+            }
+            results.push(checked);
+        }
+        self.tree.apply_batch(batch)?;
+        self.flush()?;
+        Ok(results)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            results.push(if self.remove_existing(&key)? {
+                Ok(())
+            } else {
+                Err(KvsError::KeyNotFound)
+            });
+        }
+        self.flush()?;
+        Ok(results)
+    }
+
+    fn merge(&self, key: String, operator: MergeOperator, operand: String) -> Result<String> {
+        self.limits.check_key(&key)?;
+        self.limits.check_value(&operand)?;
+        // sled retries the closure on contention, so only the error of the
+        // final attempt is kept
+        let error = RefCell::new(None);
+        let merged = self.tree.update_and_fetch(key, |old| {
+            let existing = old.map(|old| String::from_utf8_lossy(old));
+            let merged = self
+                .merge_operators
+                .apply(&operator, existing.as_deref(), &operand)
+                .and_then(|value| self.limits.check_value(&value).map(|()| value));
+            match merged {
+                Ok(value) => {
+                    error.replace(None);
+                    Some(value.into_bytes())
+                }
+                Err(err) => {
+                    error.replace(Some(err));
+                    old.map(|old| old.to_vec())
+                }
+            }
+        })?;
+        if let Some(err) = error.into_inner() {
+            return Err(err);
+        }
+        self.flush()?;
+        let merged = merged.expect("merge always produces a value");
+        Ok(String::from_utf8(merged.to_vec())?)
+    }
+
+    fn watch(&self, prefix: String) -> Result<Watcher> {
+        // a single sled subscription feeds every watcher of the keyspace,
+        // in the order the tree made the writes, so a new watcher may also
+        // see writes made just before it that were still being forwarded
+        let mut forwarding = self.state.forwarding.lock().unwrap();
+        if !*forwarding {
+            let events = self.tree.watch_prefix(Vec::new());
+            let state = Arc::downgrade(&self.state);
+            thread::spawn(move || forward(events, state));
+            *forwarding = true;
+        }
+        Ok(self.state.watchers.subscribe(prefix))
+    }
+
+    fn merge_operators(&self) -> &MergeOperators {
+        &self.merge_operators
+    }
+
+    fn keyspace(&self, name: &str) -> Result<SledKvsEngine> {
+        validate_keyspace(name)?;
+        let tree = if name == DEFAULT_KEYSPACE {
+            (*self.db).clone()
+        } else {
+            self.db.open_tree(name)?
+        };
+        let state = self
+            .keyspaces
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            tree,
+            durability: self.durability,
+            limits: self.limits,
+            state,
+            keyspaces: self.keyspaces.clone(),
+            merge_operators: self.merge_operators.clone(),
+        })
+    }
+
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let default_name = self.db.name();
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            if name == default_name {
+                names.push(DEFAULT_KEYSPACE.to_owned());
+            } else if let Ok(name) = String::from_utf8(name.to_vec()) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn scan(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, String)>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after.as_bytes()),
+            _ => Bound::Included(prefix.as_bytes()),
+        };
+        let mut pairs = Vec::new();
+        for entry in self.tree.range::<&[u8], _>((start, Bound::Unbounded)) {
+            let (key, value) = entry?;
+            if pairs.len() >= limit || !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            pairs.push((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ));
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut key_count = 0;
+        let mut live_bytes = 0;
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            key_count += 1;
+            live_bytes += (key.len() + value.len()) as u64;
+        }
+        // sled does not expose its segment or compaction counters, and all
+        // trees share one file, so stale bytes are only known per database
+        let stale_bytes = if self.tree.name() == self.db.name() {
+            let mut total_live = 0;
+            for name in self.db.tree_names() {
+                for entry in self.db.open_tree(name)?.iter() {
+                    let (key, value) = entry?;
+                    total_live += (key.len() + value.len()) as u64;
+                }
+            }
+            self.db.size_on_disk()?.saturating_sub(total_live)
+        } else {
+            0
+        };
+        Ok(EngineStats {
+            key_count,
+            live_bytes,
+            stale_bytes,
+            get_hits: self.state.hits.load(Ordering::Relaxed),
+            get_misses: self.state.misses.load(Ordering::Relaxed),
+            ..EngineStats::default()
+        })
+    }
+}
+
+// Publishes the events of a sled subscription to the watchers of a
+// keyspace, until the keyspace or its tree is dropped.
+fn forward(events: Subscriber, state: Weak<KeyspaceState>) {
+    for event in events {
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        state.watchers.publish(match event {
+            Event::Insert(key, value) => WatchEvent::Set {
+                key: String::from_utf8_lossy(&key).into_owned(),
+                value: String::from_utf8_lossy(&value).into_owned(),
+            },
+            Event::Remove(key) => WatchEvent::Remove {
+                key: String::from_utf8_lossy(&key).into_owned(),
+            },
+        });
     }
 }
@@ -0,0 +1,100 @@
+use crossbeam::crossbeam_channel::{bounded, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Number of events a `Watcher` may fall behind before it is dropped.
+pub const WATCH_BACKLOG: usize = 1024;
+
+/// A change made to a watched key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl WatchEvent {
+    /// Returns the key this event refers to.
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// A blocking stream of `WatchEvent`s for keys under a prefix.
+///
+/// The stream ends when the engine it was created from is dropped, or when
+/// the watcher falls more than `WATCH_BACKLOG` events behind.
+pub struct Watcher {
+    receiver: Receiver<WatchEvent>,
+    // the subscribers it is removed from when dropped
+    home: Weak<Subscribers>,
+    id: u64,
+}
+
+impl Watcher {
+    pub(crate) fn receiver(&self) -> &Receiver<WatchEvent> {
+        &self.receiver
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(home) = self.home.upgrade() {
+            home.lock()
+                .unwrap()
+                .retain(|subscriber| subscriber.id != self.id);
+        }
+    }
+}
+
+type Subscribers = Mutex<Vec<Subscriber>>;
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    prefix: String,
+    sender: Sender<WatchEvent>,
+}
+
+/// Fans events out to every in-process `Watcher` whose prefix matches.
+#[derive(Debug, Default)]
+pub(crate) struct Broadcaster {
+    subscribers: Arc<Subscribers>,
+    next_id: AtomicU64,
+}
+
+impl Broadcaster {
+    pub(crate) fn subscribe(&self, prefix: String) -> Watcher {
+        let (sender, receiver) = bounded(WATCH_BACKLOG);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber { id, prefix, sender });
+        Watcher {
+            receiver,
+            home: Arc::downgrade(&self.subscribers),
+            id,
+        }
+    }
+
+    pub(crate) fn publish(&self, event: WatchEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // watchers too slow to keep up are forgotten, which ends their
+        // stream once they read what they were sent
+        subscribers.retain(|subscriber| {
+            !event.key().starts_with(subscriber.prefix.as_str())
+                || subscriber.sender.try_send(event.clone()).is_ok()
+        });
+    }
+}
@@ -1,3 +1,7 @@
+// the impls `failure` derives are nested in a const
+#![allow(non_local_definitions)]
+
+use crate::{LimitKind, Permission};
 use failure::Fail;
 use std::io;
 use std::string;
@@ -21,6 +25,45 @@ pub enum KvsError {
     // Sled error.
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
+    /// Binary encoding or decoding error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// A merge operand or the value it applies to has the wrong form.
+    #[fail(display = "Invalid merge: {}", _0)]
+    InvalidMerge(String),
+    /// Keyspace names are limited to ASCII letters, digits, `-` and `_`.
+    #[fail(display = "Invalid keyspace name: {:?}", _0)]
+    InvalidKeyspace(String),
+    /// A keyspace was to be created while the store has as many as a
+    /// server allows.
+    #[fail(display = "No more than {} keyspaces may be created", _0)]
+    TooManyKeyspaces(u64),
+    /// The directory was written by a newer version of `KvStore`.
+    #[fail(display = "Unsupported on-disk format version {}", _0)]
+    UnsupportedFormat(u32),
+    /// A write was attempted on a store opened read-only.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    /// A key, value or request is over its configured size limit.
+    #[fail(
+        display = "{} of {} bytes is over the limit of {} bytes",
+        what, size, limit
+    )]
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+    /// The session lacks a permission on a key, or on every key with a
+    /// prefix.
+    #[fail(display = "Permission denied: {} on {:?}", permission, key)]
+    PermissionDenied { permission: Permission, key: String },
+    /// The user or token was refused by the server.
+    #[fail(display = "Authentication failed")]
+    AuthenticationFailed,
+    /// TLS configuration or handshake error.
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(String),
     /// Utf8 error.
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[fail(cause)] string::FromUtf8Error),
@@ -38,6 +81,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> KvsError {
         KvsError::Sled(err)
@@ -0,0 +1,475 @@
+//! An HTTP/1.1 front-end exposing the engine of a `Server` as a JSON API,
+//! for web services and scripts using curl.
+//!
+//! - `GET`, `PUT` and `DELETE /keys/{key}` read, write and remove a key. PUT
+//!   takes `{"value": v}` and GET answers `{"key": k, "value": v}`.
+//! - `GET /keys?prefix=&after=&limit=` lists keys and values in key order as
+//!   `{"items": [{"key": k, "value": v}], "next": k}`.
+//! - `POST /batch/get` takes `{"keys": [k]}` and answers `{"values": [v]}`,
+//!   with null for missing keys.
+//! - `POST /batch/set` takes `{"items": [{"key": k, "value": v}]}` and
+//!   `POST /batch/delete` takes `{"keys": [k]}`. Both answer
+//!   `{"results": [{"key": k, "ok": bool, "error": e}]}`.
+//! - `GET /health` answers `{"status": "ok"}` while the engine is usable.
+//!
+//! Keys are the percent-decoded rest of the path, so they may contain `/`.
+//! A listing is continued by passing its `next` key as `after`; `next` is
+//! null on the last page. Failures are answered with `{"error": message}`.
+
+use crate::resp::read_line;
+use crate::transport::Stream;
+use crate::{KvsEngine, KvsError, Limits, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// headers a request may have
+const MAX_HEADERS: usize = 100;
+// bytes reserved for a body before any of it is read, it grows from there
+const INITIAL_BODY_CAPACITY: u64 = 64 * 1024;
+// pairs a listing returns when no limit is given, and at most
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// Serves one HTTP connection until either side closes it.
+pub(crate) fn handle_client<E: KvsEngine>(engine: E, limits: Limits, stream: Stream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!("HTTP connection established from {}", peer_addr);
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    loop {
+        let head = match read_head(&mut reader) {
+            Ok(Some(head)) => head,
+            Ok(None) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("Bad HTTP request from {}: {}", peer_addr, e);
+                Response::error(400, e.to_string()).write_to(&mut writer, true)?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // the body is not read, so the connection cannot be reused
+        if head.content_length > limits.max_request_size() {
+            Response::error(413, "Request body is too large".to_owned())
+                .write_to(&mut writer, true)?;
+            return Ok(());
+        }
+        let request = head.read_body(&mut reader, &mut writer)?;
+        debug!(
+            "HTTP request from {}: {} {}",
+            peer_addr, request.method, request.target
+        );
+        let response = route(&engine, &limits, &request);
+        debug!("HTTP response to {}: {}", peer_addr, response.status);
+        response.write_to(&mut writer, request.close)?;
+        if request.close {
+            return Ok(());
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    // the path and query as sent
+    target: String,
+    body: Vec<u8>,
+    // whether the client does not keep the connection alive
+    close: bool,
+}
+
+impl Request {
+    fn path(&self) -> &str {
+        self.target.split('?').next().unwrap()
+    }
+
+    // The decoded value of a query parameter.
+    fn query(&self, name: &str) -> std::result::Result<Option<String>, Response> {
+        let query = match self.target.split_once('?') {
+            Some((_, query)) => query,
+            None => return Ok(None),
+        };
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if decode(key, true)? == name {
+                return decode(value, true).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn json<'a, T: Deserialize<'a>>(&'a self) -> std::result::Result<T, Response> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| Response::error(400, format!("Invalid JSON body: {}", e)))
+    }
+}
+
+struct Response {
+    status: u16,
+    // `None` for responses without content
+    body: Option<Value>,
+    // methods allowed on the resource, sent with 405
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response {
+            status: 200,
+            body: Some(body),
+            allow: None,
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: String) -> Response {
+        Response {
+            status,
+            body: Some(json!({ "error": message })),
+            allow: None,
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Response {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "Method not allowed".to_owned())
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> io::Result<()> {
+        let body = match &self.body {
+            Some(body) => body.to_string(),
+            None => String::new(),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.body.is_some() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if close {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        writer.flush()
+    }
+}
+
+impl From<KvsError> for Response {
+    fn from(e: KvsError) -> Response {
+        let status = match e {
+            KvsError::KeyNotFound => 404,
+            KvsError::TooLarge { .. } => 413,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn bad_request(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+// The request line and headers of a request.
+struct Head {
+    method: String,
+    target: String,
+    close: bool,
+    content_length: u64,
+    expect_continue: bool,
+}
+
+// Reads the head of the next request, returning `None` at the end of the
+// stream. Malformed requests fail with `InvalidData`.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Head>> {
+    let line = match read_line(reader)? {
+        Some(line) => String::from_utf8(line).map_err(|_| bad_request("invalid request line"))?,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method.to_owned(), target.to_owned(), version)
+        }
+        _ => return Err(bad_request("invalid request line")),
+    };
+
+    // HTTP/1.1 keeps connections alive unless told otherwise, 1.0 does not
+    let mut head = Head {
+        method,
+        target,
+        close: version == "HTTP/1.0",
+        content_length: 0,
+        expect_continue: false,
+    };
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.is_empty() {
+            return Ok(Some(head));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("invalid header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                head.content_length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => head.close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => head.close = false,
+            "expect" if value.eq_ignore_ascii_case("100-continue") => head.expect_continue = true,
+            "transfer-encoding" => return Err(bad_request("chunked bodies are not supported")),
+            _ => {}
+        }
+    }
+    Err(bad_request("too many headers"))
+}
+
+impl Head {
+    fn read_body<R: BufRead, W: Write>(
+        self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> io::Result<Request> {
+        // curl waits for this before sending larger bodies
+        if self.expect_continue && self.content_length > 0 {
+            writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            writer.flush()?;
+        }
+        // the length is only a claim until the bytes arrive
+        let mut body = Vec::with_capacity(self.content_length.min(INITIAL_BODY_CAPACITY) as usize);
+        reader.take(self.content_length).read_to_end(&mut body)?;
+        if (body.len() as u64) < self.content_length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Request {
+            method: self.method,
+            target: self.target,
+            body,
+            close: self.close,
+        })
+    }
+}
+
+fn route<E: KvsEngine>(engine: &E, limits: &Limits, request: &Request) -> Response {
+    let method = request.method.as_str();
+    let path = request.path();
+    let result = if let Some(key) = path.strip_prefix("/keys/") {
+        match method {
+            "GET" => decode(key, false).and_then(|key| get(engine, limits, key)),
+            "PUT" => decode(key, false).and_then(|key| put(engine, limits, key, request)),
+            "DELETE" => decode(key, false).and_then(|key| delete(engine, limits, key)),
+            _ => Err(Response::method_not_allowed("GET, PUT, DELETE")),
+        }
+    } else {
+        match (method, path) {
+            ("GET", "/keys") => list(engine, limits, request),
+            (_, "/keys") => Err(Response::method_not_allowed("GET")),
+            ("POST", "/batch/get") => batch_get(engine, limits, request),
+            ("POST", "/batch/set") => batch_set(engine, limits, request),
+            ("POST", "/batch/delete") => batch_delete(engine, limits, request),
+            (_, "/batch/get") | (_, "/batch/set") | (_, "/batch/delete") => {
+                Err(Response::method_not_allowed("POST"))
+            }
+            ("GET", "/health") => health(engine),
+            (_, "/health") => Err(Response::method_not_allowed("GET")),
+            _ => Err(Response::error(404, format!("No such resource {}", path))),
+        }
+    };
+    result.unwrap_or_else(|response| response)
+}
+
+type Handled = std::result::Result<Response, Response>;
+
+fn get<E: KvsEngine>(engine: &E, limits: &Limits, key: String) -> Handled {
+    limits.check_key(&key)?;
+    match engine.get(key.clone())? {
+        Some(value) => Ok(Response::ok(json!({ "key": key, "value": value }))),
+        None => Err(KvsError::KeyNotFound.into()),
+    }
+}
+
+fn put<E: KvsEngine>(engine: &E, limits: &Limits, key: String, request: &Request) -> Handled {
+    #[derive(Deserialize)]
+    struct Body {
+        value: String,
+    }
+
+    let body: Body = request.json()?;
+    limits.check_key(&key)?;
+    limits.check_value(&body.value)?;
+    engine.set(key, body.value)?;
+    Ok(Response::no_content())
+}
+
+fn delete<E: KvsEngine>(engine: &E, limits: &Limits, key: String) -> Handled {
+    limits.check_key(&key)?;
+    engine.remove(key)?;
+    Ok(Response::no_content())
+}
+
+#[derive(Serialize, Deserialize)]
+struct Item {
+    key: String,
+    value: String,
+}
+
+fn list<E: KvsEngine>(engine: &E, limits: &Limits, request: &Request) -> Handled {
+    let prefix = request.query("prefix")?.unwrap_or_default();
+    let after = request.query("after")?;
+    let limit = match request.query("limit")? {
+        Some(limit) => match limit.parse() {
+            Ok(limit) if limit > 0 && limit <= MAX_LIST_LIMIT => limit,
+            _ => {
+                return Err(Response::error(
+                    400,
+                    format!("limit must be between 1 and {}", MAX_LIST_LIMIT),
+                ))
+            }
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+    limits.check_key(&prefix)?;
+    let page = engine.scan(&prefix, after.as_deref(), limit)?;
+    let next = match page.last() {
+        Some((key, _)) if page.len() == limit => Some(key.clone()),
+        _ => None,
+    };
+    let items: Vec<Item> = page
+        .into_iter()
+        .map(|(key, value)| Item { key, value })
+        .collect();
+    Ok(Response::ok(json!({ "items": items, "next": next })))
+}
+
+#[derive(Deserialize)]
+struct Keys {
+    keys: Vec<String>,
+}
+
+fn batch_get<E: KvsEngine>(engine: &E, limits: &Limits, request: &Request) -> Handled {
+    let body: Keys = request.json()?;
+    for key in &body.keys {
+        limits.check_key(key)?;
+    }
+    let values = engine.get_many(body.keys)?;
+    Ok(Response::ok(json!({ "values": values })))
+}
+
+// A batch with any key or value over the limits is rejected whole, as the
+// native protocol does.
+fn batch_set<E: KvsEngine>(engine: &E, limits: &Limits, request: &Request) -> Handled {
+    #[derive(Deserialize)]
+    struct Body {
+        items: Vec<Item>,
+    }
+
+    let body: Body = request.json()?;
+    for item in &body.items {
+        limits.check_key(&item.key)?;
+        limits.check_value(&item.value)?;
+    }
+    let keys: Vec<String> = body.items.iter().map(|item| item.key.clone()).collect();
+    let pairs = body
+        .items
+        .into_iter()
+        .map(|item| (item.key, item.value))
+        .collect();
+    let results = engine.set_many(pairs)?;
+    Ok(Response::ok(
+        json!({ "results": key_results(keys, results) }),
+    ))
+}
+
+fn batch_delete<E: KvsEngine>(engine: &E, limits: &Limits, request: &Request) -> Handled {
+    let body: Keys = request.json()?;
+    for key in &body.keys {
+        limits.check_key(key)?;
+    }
+    let results = engine.remove_many(body.keys.clone())?;
+    Ok(Response::ok(
+        json!({ "results": key_results(body.keys, results) }),
+    ))
+}
+
+fn key_results(keys: Vec<String>, results: Vec<Result<()>>) -> Vec<Value> {
+    keys.into_iter()
+        .zip(results)
+        .map(|(key, result)| match result {
+            Ok(()) => json!({ "key": key, "ok": true }),
+            Err(e) => json!({ "key": key, "ok": false, "error": e.to_string() }),
+        })
+        .collect()
+}
+
+fn health<E: KvsEngine>(engine: &E) -> Handled {
+    match engine.stats() {
+        Ok(stats) => Ok(Response::ok(
+            json!({ "status": "ok", "keys": stats.key_count }),
+        )),
+        Err(e) => Err(Response::error(503, e.to_string())),
+    }
+}
+
+// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn decode(s: &str, query: bool) -> std::result::Result<String, Response> {
+    let invalid = || Response::error(400, format!("Invalid percent-encoding in {:?}", s));
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).ok_or_else(invalid)?;
+                // from_str_radix would take a sign as in "%+f"
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(invalid());
+                }
+                let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+                decoded.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+                i += 3;
+            }
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| invalid())
+}
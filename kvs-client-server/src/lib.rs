@@ -1,13 +1,30 @@
-pub use client::Client;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use auth::{Acl, Grant, Permission, User};
+pub use client::{Client, Pipeline, Reply, WatchStream};
+pub use engines::{
+    Durability, Engine, EngineStats, FileSystem, FsFile, KvStore, KvStoreOptions, KvsEngine,
+    LimitKind, Limits, LogOp, LogRecord, LogScan, LsmKvsEngine, LsmOptions, Mapping,
+    MemoryKvsEngine, MergeOperator, MergeOperators, OpenMode, OsFileSystem, SledKvsEngine,
+    SledMode, SledOptions, WatchEvent, Watcher, DEFAULT_KEYSPACE, ENGINE_MARKER, WATCH_BACKLOG,
+};
+// scaffolding for the tests, not part of the API
+#[doc(hidden)]
+pub use engines::{Fault, FaultKind, FaultyFileSystem, FsOp, MemoryFileSystem};
 pub use error::{KvsError, Result};
-pub use network::Request;
-pub use server::Server;
+pub use network::{Codec, ErrorCode, Protocol, Request, ResponseError};
+pub use server::{Server, ShutdownHandle};
 pub use thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
+pub use tls::{ClientTlsOptions, ServerTlsOptions};
+pub use transport::Address;
 
+mod auth;
 mod client;
 mod engines;
 mod error;
+mod http;
+mod memcached;
 mod network;
+mod resp;
 mod server;
 mod thread_pool;
+mod tls;
+mod transport;
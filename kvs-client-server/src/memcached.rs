@@ -0,0 +1,445 @@
+//! A front-end speaking the memcached text protocol, so that services using
+//! a memcached client can use the engine of a `Server`.
+//!
+//! get, gets, set, add, replace, cas, delete, incr, decr, version and quit
+//! are understood. Values are stored in the engine as they are, so the other
+//! front-ends see them too, while the flags, expiry time and cas unique of
+//! an item are kept under its key in the `memcached` keyspace with a
+//! checksum of the value they belong to. Metadata whose value was since
+//! rewritten through another front-end no longer matches it and is dropped.
+//! Expired items are removed when they are next read.
+//!
+//! The metadata records are values in the engine like any other, of up to
+//! 63 bytes, so the value size limit must leave room for them.
+
+use crate::resp::read_line;
+use crate::transport::Stream;
+use crate::{KvsEngine, KvsError, Limits, Result};
+use log::{debug, warn};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// keyspace holding the metadata of the items
+const META_KEYSPACE: &str = "memcached";
+// key in the metadata keyspace of the last cas unique given out, which no
+// memcached key can clash with as keys have no spaces
+const LAST_CAS_KEY: &str = " last cas";
+// longest key memcached clients may send
+const MAX_KEY_LEN: usize = 250;
+// longer expiry times are Unix times rather than seconds from now
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format\r\n";
+
+// An item is a value and its metadata, which the engine cannot update
+// together, and cas needs a compare-and-swap the engine does not have. So
+// memcached commands run one at a time; writes made through the other
+// front-ends are not ordered with them.
+static ITEM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Serves one memcached connection until the client closes it.
+pub(crate) fn handle_client<E: KvsEngine>(engine: E, limits: Limits, stream: Stream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!("memcached connection established from {}", peer_addr);
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let session = Session {
+        meta: engine.keyspace(META_KEYSPACE)?,
+        engine,
+        limits,
+    };
+
+    loop {
+        // pipelined commands are answered with a single flush
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        let line = match read_line(&mut reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("memcached protocol error from {}: {}", peer_addr, e);
+                writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let line = match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => {
+                writer.write_all(BAD_FORMAT.as_bytes())?;
+                continue;
+            }
+        };
+        let args: Vec<&str> = line.split_ascii_whitespace().collect();
+        if args.first() == Some(&"quit") {
+            writer.flush()?;
+            return Ok(());
+        }
+        match session.execute(&args, &mut reader)? {
+            Some(reply) => {
+                debug!("memcached reply to {}: {:?}", peer_addr, reply);
+                writer.write_all(reply.as_bytes())?;
+            }
+            None => debug!("memcached command from {} asked for no reply", peer_addr),
+        }
+    }
+}
+
+// A value with the metadata memcached clients see.
+struct Item {
+    value: String,
+    flags: u32,
+    // Unix time the item expires at, 0 if it never does
+    expires: u64,
+    // number of the write that stored the item, from a counter shared by
+    // all items
+    cas_unique: u64,
+}
+
+impl Item {
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= now()
+    }
+
+    // The metadata record, `<flags> <expires> <cas unique> <checksum>`.
+    fn meta(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.flags,
+            self.expires,
+            self.cas_unique,
+            crc32fast::hash(self.value.as_bytes())
+        )
+    }
+}
+
+struct Session<E: KvsEngine> {
+    engine: E,
+    meta: E,
+    limits: Limits,
+}
+
+impl<E: KvsEngine> Session<E> {
+    // Runs the command in `args`, reading its data block from `reader` if
+    // it has one. Returns `None` if the client asked for no reply.
+    fn execute<R: BufRead>(&self, args: &[&str], reader: &mut R) -> io::Result<Option<String>> {
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(Some("ERROR\r\n".to_owned())),
+        };
+        let (args, noreply) = match args.split_last() {
+            Some((&"noreply", args)) => (args, true),
+            _ => (args, false),
+        };
+
+        let result = match name {
+            "get" | "gets" if !args.is_empty() => self.retrieve(args, name == "gets"),
+            "set" | "add" | "replace" | "cas" => {
+                let command = match Storage::parse(name, args) {
+                    Some(command) => command,
+                    None => return Ok(Some(BAD_FORMAT.to_owned())),
+                };
+                match read_data(reader, command.bytes, &self.limits)? {
+                    Ok(value) => self.store(command, value),
+                    Err(reply) => Ok(reply.to_owned()),
+                }
+            }
+            "delete" if args.len() == 1 => self.delete(args[0]),
+            "incr" | "decr" if args.len() == 2 => match args[1].parse() {
+                Ok(delta) => self.increment(args[0], delta, name == "incr"),
+                Err(_) => Ok("CLIENT_ERROR invalid numeric delta argument\r\n".to_owned()),
+            },
+            "version" => Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))),
+            "get" | "gets" | "delete" | "incr" | "decr" => Ok(BAD_FORMAT.to_owned()),
+            _ => Ok("ERROR\r\n".to_owned()),
+        };
+        let reply = match result {
+            Ok(reply) => reply,
+            Err(e @ KvsError::TooLarge { .. }) => format!("CLIENT_ERROR {}\r\n", e),
+            Err(e) => format!("SERVER_ERROR {}\r\n", e),
+        };
+        Ok(if noreply { None } else { Some(reply) })
+    }
+
+    fn retrieve(&self, keys: &[&str], with_cas: bool) -> Result<String> {
+        let _lock = lock();
+        let mut reply = String::new();
+        for &key in keys {
+            if !valid_key(key) {
+                return Ok(BAD_FORMAT.to_owned());
+            }
+            self.limits.check_key(key)?;
+            if let Some(item) = self.item(key)? {
+                reply.push_str(&format!(
+                    "VALUE {} {} {}",
+                    key,
+                    item.flags,
+                    item.value.len()
+                ));
+                if with_cas {
+                    reply.push_str(&format!(" {}", item.cas_unique));
+                }
+                reply.push_str(&format!("\r\n{}\r\n", item.value));
+            }
+        }
+        reply.push_str("END\r\n");
+        Ok(reply)
+    }
+
+    fn store(&self, command: Storage, value: String) -> Result<String> {
+        if !valid_key(command.key) {
+            return Ok(BAD_FORMAT.to_owned());
+        }
+        self.limits.check_key(command.key)?;
+        let _lock = lock();
+        let current = match command.name {
+            "set" => None,
+            _ => self.item(command.key)?,
+        };
+        let stored = match (command.name, current) {
+            ("add", current) => current.is_none(),
+            ("replace", current) => current.is_some(),
+            ("cas", None) => return Ok("NOT_FOUND\r\n".to_owned()),
+            ("cas", Some(current)) => current.cas_unique == command.cas_unique,
+            _ => true,
+        };
+        if !stored {
+            let reply = if command.name == "cas" {
+                "EXISTS\r\n"
+            } else {
+                "NOT_STORED\r\n"
+            };
+            return Ok(reply.to_owned());
+        }
+
+        let item = Item {
+            value,
+            flags: command.flags,
+            expires: expiry_time(command.exptime),
+            cas_unique: self.next_cas_unique()?,
+        };
+        if item.is_expired() {
+            self.remove(command.key)?;
+            return Ok("STORED\r\n".to_owned());
+        }
+        self.write(command.key, &item)?;
+        Ok("STORED\r\n".to_owned())
+    }
+
+    fn delete(&self, key: &str) -> Result<String> {
+        if !valid_key(key) {
+            return Ok(BAD_FORMAT.to_owned());
+        }
+        self.limits.check_key(key)?;
+        let _lock = lock();
+        if self.item(key)?.is_none() {
+            return Ok("NOT_FOUND\r\n".to_owned());
+        }
+        self.remove(key)?;
+        Ok("DELETED\r\n".to_owned())
+    }
+
+    // Adds `delta` to an item holding a 64-bit unsigned integer, wrapping
+    // around on incr and stopping at 0 on decr as memcached does.
+    fn increment(&self, key: &str, delta: u64, incr: bool) -> Result<String> {
+        if !valid_key(key) {
+            return Ok(BAD_FORMAT.to_owned());
+        }
+        self.limits.check_key(key)?;
+        let _lock = lock();
+        let item = match self.item(key)? {
+            Some(item) => item,
+            None => return Ok("NOT_FOUND\r\n".to_owned()),
+        };
+        let current: u64 = match item.value.parse() {
+            Ok(current) => current,
+            Err(_) => {
+                return Ok(
+                    "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_owned(),
+                )
+            }
+        };
+        let value = if incr {
+            current.wrapping_add(delta)
+        } else {
+            current.saturating_sub(delta)
+        };
+        let item = Item {
+            value: value.to_string(),
+            cas_unique: self.next_cas_unique()?,
+            ..item
+        };
+        self.write(key, &item)?;
+        Ok(format!("{}\r\n", value))
+    }
+
+    // Reads an item, removing it if it has expired. Callers hold the lock.
+    //
+    // A value without metadata of its own, because it was written through
+    // another front-end, is given fresh metadata so that it has a cas
+    // unique. A value rewritten with the same bytes keeps the metadata it
+    // had, as it cannot be told apart from the one the metadata is for.
+    fn item(&self, key: &str) -> Result<Option<Item>> {
+        let value = match self.engine.get(key.to_owned())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let meta = self.meta.get(key.to_owned())?;
+        let item = match meta.as_deref().and_then(|meta| parse_meta(meta, &value)) {
+            Some((flags, expires, cas_unique)) => Item {
+                value,
+                flags,
+                expires,
+                cas_unique,
+            },
+            None => {
+                let item = Item {
+                    value,
+                    flags: 0,
+                    expires: 0,
+                    cas_unique: self.next_cas_unique()?,
+                };
+                self.meta.set(key.to_owned(), item.meta())?;
+                item
+            }
+        };
+        if item.is_expired() {
+            self.remove(key)?;
+            return Ok(None);
+        }
+        Ok(Some(item))
+    }
+
+    // Stores the value of an item and then its metadata. Callers hold the
+    // lock.
+    fn write(&self, key: &str, item: &Item) -> Result<()> {
+        self.engine.set(key.to_owned(), item.value.clone())?;
+        self.meta.set(key.to_owned(), item.meta())
+    }
+
+    // Numbers a write, higher than any before it. Callers hold the lock.
+    fn next_cas_unique(&self) -> Result<u64> {
+        let last = match self.meta.get(LAST_CAS_KEY.to_owned())? {
+            Some(last) => last.parse().unwrap_or(0),
+            None => 0,
+        };
+        let next = last + 1;
+        self.meta.set(LAST_CAS_KEY.to_owned(), next.to_string())?;
+        Ok(next)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        ignore_missing(self.engine.remove(key.to_owned()))?;
+        ignore_missing(self.meta.remove(key.to_owned()))
+    }
+}
+
+// The command line of set, add, replace and cas.
+struct Storage<'a> {
+    name: &'a str,
+    key: &'a str,
+    flags: u32,
+    exptime: i64,
+    bytes: u64,
+    cas_unique: u64,
+}
+
+impl<'a> Storage<'a> {
+    // Parses `<key> <flags> <exptime> <bytes>`, followed by `<cas unique>`
+    // for cas.
+    fn parse(name: &'a str, args: &[&'a str]) -> Option<Storage<'a>> {
+        let expected = if name == "cas" { 5 } else { 4 };
+        if args.len() != expected {
+            return None;
+        }
+        Some(Storage {
+            name,
+            key: args[0],
+            flags: args[1].parse().ok()?,
+            exptime: args[2].parse().ok()?,
+            bytes: args[3].parse().ok()?,
+            cas_unique: match args.get(4) {
+                Some(cas_unique) => cas_unique.parse().ok()?,
+                None => 0,
+            },
+        })
+    }
+}
+
+// Reads the data block of a storage command. A value over the limits is
+// discarded and its error reply returned instead.
+fn read_data<R: BufRead>(
+    reader: &mut R,
+    bytes: u64,
+    limits: &Limits,
+) -> io::Result<std::result::Result<String, &'static str>> {
+    let mut block = (&mut *reader).take(bytes.saturating_add(2));
+    if bytes > limits.max_value_size {
+        io::copy(&mut block, &mut io::sink())?;
+        return Ok(Err("SERVER_ERROR object too large for cache\r\n"));
+    }
+    let mut data = Vec::with_capacity(bytes as usize + 2);
+    block.read_to_end(&mut data)?;
+    if data.len() as u64 != bytes + 2 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if !data.ends_with(b"\r\n") {
+        // skip to the end of the line the block was meant to end on
+        if !data.ends_with(b"\n") {
+            read_line(reader)?;
+        }
+        return Ok(Err("CLIENT_ERROR bad data chunk\r\n"));
+    }
+    data.truncate(bytes as usize);
+    Ok(String::from_utf8(data).map_err(|_| "CLIENT_ERROR value must be valid UTF-8\r\n"))
+}
+
+fn valid_key(key: &str) -> bool {
+    key.len() <= MAX_KEY_LEN && !key.bytes().any(|b| b.is_ascii_control())
+}
+
+// Parses the flags, expiry time and cas unique of a metadata record,
+// unless it is for another value than `value`.
+fn parse_meta(meta: &str, value: &str) -> Option<(u32, u64, u64)> {
+    let mut fields = meta.split(' ').map(str::parse::<u64>);
+    match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(Ok(flags)), Some(Ok(expires)), Some(Ok(cas_unique)), Some(Ok(checksum)))
+            if checksum == u64::from(crc32fast::hash(value.as_bytes())) =>
+        {
+            Some((flags as u32, expires, cas_unique))
+        }
+        _ => None,
+    }
+}
+
+// Turns the exptime of a command into the Unix time the item expires at.
+// A negative exptime expires the item at once.
+fn expiry_time(exptime: i64) -> u64 {
+    match exptime {
+        0 => 0,
+        exptime if exptime < 0 => 1,
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => now() + exptime as u64,
+        exptime => exptime as u64,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn lock() -> MutexGuard<'static, ()> {
+    ITEM_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        result => result,
+    }
+}
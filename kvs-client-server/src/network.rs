@@ -1,27 +1,882 @@
+//! Messages exchanged by `Client` and `Server`, and the two ways they are
+//! put on the wire.
+//!
+//! Legacy clients send bare JSON requests back to back and read bare JSON
+//! responses. Framed clients start with a handshake:
+//!
+//! ```text
+//! client: MAGIC | min version: u16 | max version: u16 | count: u8 | codecs: [u8]
+//! server: MAGIC | version: u16 | codec: u8
+//! ```
+//!
+//! The server picks the newest version both sides support and the first
+//! codec of the client it supports, or answers with version 0 and closes
+//! the connection. Every message then travels in a frame:
+//!
+//! ```text
+//! length: u32 | opcode: u8 | request id: u32 | payload: [u8; length]
+//! ```
+//!
+//! Integers are big-endian. A response carries the id of its request, and
+//! a request frame that cannot be decoded is answered with an `Error` frame
+//! without closing the connection.
+//!
+//! Credentials do not travel in the handshake, which only settles the
+//! version and codec. With either protocol, a client with credentials sends
+//! `Request::Auth` first, as `Client::authenticate` does. Until then the
+//! connection is anonymous, and a later `Request::Auth` switches users.
+//!
+//! Version 2 sends failures as a `ResponseError`. Version 1, which legacy
+//! connections also speak, sends them as their message, except for size
+//! limits and denied permissions.
+
+use crate::{EngineStats, KvsError, LimitKind, MergeOperator, Permission, Result, WatchEvent};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Bytes a framed connection starts with. Legacy clients start with the `{`
+/// of a JSON request instead.
+pub const MAGIC: [u8; 4] = *b"KVSP";
+
+/// Newest version of the framed protocol.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Oldest version of the framed protocol still spoken.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Version of the framed protocol whose messages legacy connections carry.
+pub const LEGACY_VERSION: u16 = 1;
+
+/// First version of the framed protocol sending failures as a
+/// `ResponseError`.
+pub const TYPED_ERRORS_VERSION: u16 = 2;
+
+const HEADER_LEN: usize = 9;
+
+/// How requests and responses are encoded on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Bare JSON messages, as spoken before framing was introduced.
+    Legacy,
+    /// Length-prefixed frames with payloads in the given codec.
+    Framed(Codec),
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "legacy" => Ok(Protocol::Legacy),
+            s => Ok(Protocol::Framed(s.parse()?)),
+        }
+    }
+}
+
+/// Encoding of the payload of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Bincode,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Bincode => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            1 => Some(Codec::Json),
+            2 => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            Codec::Bincode => Ok(bincode::serialize(value)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            Codec::Bincode => Ok(bincode::deserialize(bytes)?),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "bincode" => Ok(Codec::Bincode),
+            _ => Err(KvsError::StringError(format!("unknown codec {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+/// What a client offers when opening a framed connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    /// Codecs in order of preference.
+    pub codecs: Vec<Codec>,
+}
+
+impl Hello {
+    pub fn new(codecs: Vec<Codec>) -> Hello {
+        Hello {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            codecs,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.min_version.to_be_bytes());
+        buf.extend_from_slice(&self.max_version.to_be_bytes());
+        buf.push(self.codecs.len() as u8);
+        buf.extend(self.codecs.iter().map(|codec| codec.to_byte()));
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Reads a hello, leaving out the codecs this side does not know.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Hello> {
+        let mut buf = [0; 9];
+        reader.read_exact(&mut buf)?;
+        check_magic(&buf[..4])?;
+        let mut codecs = vec![0; buf[8] as usize];
+        reader.read_exact(&mut codecs)?;
+        Ok(Hello {
+            min_version: u16::from_be_bytes([buf[4], buf[5]]),
+            max_version: u16::from_be_bytes([buf[6], buf[7]]),
+            codecs: codecs.into_iter().filter_map(Codec::from_byte).collect(),
+        })
+    }
+
+    /// Picks the newest version both sides speak and the first codec
+    /// offered, or `None` if there is no common version or codec.
+    pub fn negotiate(&self) -> Option<Welcome> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return None;
+        }
+        let codec = *self.codecs.first()?;
+        Some(Welcome { version, codec })
+    }
+}
+
+/// The server's answer to a `Hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Welcome {
+    pub version: u16,
+    pub codec: Codec,
+}
+
+impl Welcome {
+    /// Writes `welcome`, or a refusal if it is `None`.
+    pub fn write_to<W: Write>(welcome: Option<Welcome>, writer: &mut W) -> Result<()> {
+        let mut buf = MAGIC.to_vec();
+        match welcome {
+            Some(welcome) => {
+                buf.extend_from_slice(&welcome.version.to_be_bytes());
+                buf.push(welcome.codec.to_byte());
+            }
+            None => buf.extend_from_slice(&[0, 0, 0]),
+        }
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Welcome> {
+        let mut buf = [0; 7];
+        reader.read_exact(&mut buf)?;
+        check_magic(&buf[..4])?;
+        let version = u16::from_be_bytes([buf[4], buf[5]]);
+        match Codec::from_byte(buf[6]) {
+            Some(codec) if version != 0 => Ok(Welcome { version, codec }),
+            _ => Err(KvsError::StringError(
+                "server supports none of the offered protocol versions or codecs".to_owned(),
+            )),
+        }
+    }
+}
+
+fn check_magic(bytes: &[u8]) -> Result<()> {
+    if bytes != MAGIC {
+        return Err(KvsError::StringError(
+            "peer does not speak the framed protocol".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// What a frame carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    /// A `Request`.
+    Request,
+    /// The response matching the request, such as a `GetResponse`.
+    Response,
+    /// A `ResponseError`, for a request that could not be decoded or was
+    /// refused.
+    Error,
+}
+
+impl Opcode {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Opcode::Request => 1,
+            Opcode::Response => 2,
+            Opcode::Error => 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        match byte {
+            1 => Some(Opcode::Request),
+            2 => Some(Opcode::Response),
+            3 => Some(Opcode::Error),
+            _ => None,
+        }
+    }
+}
+
+/// The fixed size start of a frame.
+///
+/// The opcode is kept as sent, so that a frame with an unknown opcode can
+/// still be skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub len: u32,
+    pub opcode: u8,
+    pub id: u32,
+}
+
+impl FrameHeader {
+    /// Reads a header, returning `None` if the stream ends before it.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<FrameHeader>> {
+        let mut buf = [0; HEADER_LEN];
+        let mut read = 0;
+        while read < HEADER_LEN {
+            match reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => read += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(FrameHeader {
+            len: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            opcode: buf[4],
+            id: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]),
+        }))
+    }
+}
+
+/// Writes a frame, leaving the flushing to the caller.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    opcode: Opcode,
+    id: u32,
+    payload: &[u8],
+) -> Result<()> {
+    if payload.len() > u32::MAX as usize {
+        return Err(KvsError::TooLarge {
+            what: LimitKind::Request,
+            size: payload.len() as u64,
+            limit: u32::MAX as u64,
+        });
+    }
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    header[4] = opcode.to_byte();
+    header[5..].copy_from_slice(&id.to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// A failed request, in place of its response or as the payload of an
+/// `Opcode::Error` frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResponseError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Whether the same request may succeed if sent again.
+    pub retryable: bool,
+}
+
+/// What made a request fail, with the details the client needs to rebuild
+/// the `KvsError`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    KeyNotFound,
+    InvalidMerge(String),
+    InvalidKeyspace(String),
+    TooManyKeyspaces(u64),
+    ReadOnly,
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+    PermissionDenied {
+        permission: Permission,
+        key: String,
+    },
+    AuthenticationFailed,
+    /// The frame has an unknown opcode or a payload that does not decode.
+    Malformed,
+    /// The engine failed to read or write its files.
+    Io,
+    /// Any other failure of the server.
+    Internal,
+}
+
+impl ResponseError {
+    pub fn malformed(message: String) -> ResponseError {
+        ResponseError {
+            code: ErrorCode::Malformed,
+            message,
+            retryable: false,
+        }
+    }
+}
+
+impl From<KvsError> for ResponseError {
+    fn from(err: KvsError) -> ResponseError {
+        let message = format!("{}", err);
+        let code = match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::InvalidMerge(msg) => ErrorCode::InvalidMerge(msg),
+            KvsError::InvalidKeyspace(name) => ErrorCode::InvalidKeyspace(name),
+            KvsError::TooManyKeyspaces(limit) => ErrorCode::TooManyKeyspaces(limit),
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::TooLarge { what, size, limit } => ErrorCode::TooLarge { what, size, limit },
+            KvsError::PermissionDenied { permission, key } => {
+                ErrorCode::PermissionDenied { permission, key }
+            }
+            KvsError::AuthenticationFailed => ErrorCode::AuthenticationFailed,
+            KvsError::Io(_) | KvsError::Sled(sled::Error::Io(_)) => ErrorCode::Io,
+            _ => ErrorCode::Internal,
+        };
+        ResponseError {
+            // I/O errors of the server, such as a full disk, may pass
+            retryable: code == ErrorCode::Io,
+            code,
+            message,
+        }
+    }
+}
+
+impl From<ResponseError> for KvsError {
+    fn from(err: ResponseError) -> KvsError {
+        match err.code {
+            ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+            ErrorCode::InvalidMerge(msg) => KvsError::InvalidMerge(msg),
+            ErrorCode::InvalidKeyspace(name) => KvsError::InvalidKeyspace(name),
+            ErrorCode::TooManyKeyspaces(limit) => KvsError::TooManyKeyspaces(limit),
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::TooLarge { what, size, limit } => KvsError::TooLarge { what, size, limit },
+            ErrorCode::PermissionDenied { permission, key } => {
+                KvsError::PermissionDenied { permission, key }
+            }
+            ErrorCode::AuthenticationFailed => KvsError::AuthenticationFailed,
+            ErrorCode::Io => KvsError::Io(io::Error::other(err.message)),
+            ErrorCode::Malformed | ErrorCode::Internal => KvsError::StringError(err.message),
+        }
+    }
+}
 
 /// The command client sends to server.
+///
+/// Requests without a `keyspace` apply to the default keyspace.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    Get {
+        key: String,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    Remove {
+        key: String,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    Merge {
+        key: String,
+        operator: MergeOperator,
+        operand: String,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    Stats {
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    Watch {
+        prefix: String,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    MultiGet {
+        keys: Vec<String>,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    MultiSet {
+        pairs: Vec<(String, String)>,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    MultiRemove {
+        keys: Vec<String>,
+        #[serde(default)]
+        keyspace: Option<String>,
+    },
+    /// Switches the session to `user`. The server closes the connection if
+    /// the token is wrong.
+    Auth { user: String, token: String },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(ResponseError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(ResponseError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MergeResponse {
+    Ok(String),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(EngineStats),
+    Err(ResponseError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(ResponseError),
+}
+
+/// Values of a `Request::MultiGet`, in the order of its keys.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MultiGetResponse {
+    Ok(Vec<Option<String>>),
+    Err(ResponseError),
+}
+
+/// Results of a `Request::MultiSet`, one per pair.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MultiSetResponse {
+    Ok(Vec<KeyResult>),
+    Err(ResponseError),
+}
+
+/// Results of a `Request::MultiRemove`, one per key.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MultiRemoveResponse {
+    Ok(Vec<KeyResult>),
+    Err(ResponseError),
+}
+
+/// Outcome for one key of a batch write.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KeyResult {
+    Ok,
+    Err(ResponseError),
+}
+
+impl KeyResult {
+    pub fn into_result(self) -> Result<()> {
+        match self {
+            KeyResult::Ok => Ok(()),
+            KeyResult::Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl From<Result<()>> for KeyResult {
+    fn from(result: Result<()>) -> KeyResult {
+        match result {
+            Ok(()) => KeyResult::Ok,
+            Err(err) => KeyResult::Err(err.into()),
+        }
+    }
+}
+
+/// Responses streamed back for a `Request::Watch`.
+///
+/// `Subscribed` is sent once the watch is registered, followed by one
+/// `Event` per change until the connection is closed.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse {
+    Subscribed,
+    Event(WatchEvent),
+    Err(ResponseError),
+}
+
+macro_rules! error_response {
+    ($($response:ident),*) => {$(
+        impl From<KvsError> for $response {
+            fn from(err: KvsError) -> $response {
+                $response::Err(err.into())
+            }
+        }
+    )*};
+}
+
+error_response!(
+    SetResponse,
+    GetResponse,
+    RemoveResponse,
+    MergeResponse,
+    StatsResponse,
+    WatchResponse,
+    MultiGetResponse,
+    MultiSetResponse,
+    MultiRemoveResponse,
+    AuthResponse
+);
+
+/// A response, which version 1 puts on the wire as `Self::V1`.
+pub trait Response: Serialize + DeserializeOwned + fmt::Debug {
+    type V1: Serialize + DeserializeOwned;
+
+    fn into_v1(self) -> Self::V1;
+
+    fn from_v1(v1: Self::V1) -> Self;
+}
+
+/// A response of version 1. Failures are sent as their message, except for
+/// size limits and denied permissions.
+///
+/// Legacy clients are sent it in place of a response when a request is
+/// refused before it is handled, for being too large or not permitted. They
+/// read it as whichever response they expect.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ResponseV1<T> {
+    Ok(T),
     Err(String),
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+    PermissionDenied {
+        permission: Permission,
+        key: String,
+    },
+}
+
+impl<T> ResponseV1<T> {
+    pub fn new(result: std::result::Result<T, ResponseError>) -> ResponseV1<T> {
+        let err = match result {
+            Ok(value) => return ResponseV1::Ok(value),
+            Err(err) => err,
+        };
+        match err.code {
+            ErrorCode::TooLarge { what, size, limit } => ResponseV1::TooLarge { what, size, limit },
+            ErrorCode::PermissionDenied { permission, key } => {
+                ResponseV1::PermissionDenied { permission, key }
+            }
+            _ => ResponseV1::Err(err.message),
+        }
+    }
+
+    fn into_result(self) -> std::result::Result<T, ResponseError> {
+        match self {
+            ResponseV1::Ok(value) => Ok(value),
+            ResponseV1::Err(message) => Err(error_from_message(message).into()),
+            ResponseV1::TooLarge { what, size, limit } => {
+                Err(KvsError::TooLarge { what, size, limit }.into())
+            }
+            ResponseV1::PermissionDenied { permission, key } => {
+                Err(KvsError::PermissionDenied { permission, key }.into())
+            }
+        }
+    }
+}
+
+// Recovers the error a version 1 peer sent the message of. Messages of
+// other errors, or not written by this crate, stay strings.
+fn error_from_message(message: String) -> KvsError {
+    let parsed = match message.as_str() {
+        "Key not found" => Some(KvsError::KeyNotFound),
+        "Store is opened read-only" => Some(KvsError::ReadOnly),
+        "Authentication failed" => Some(KvsError::AuthenticationFailed),
+        _ => too_large_from_message(&message).or_else(|| denied_from_message(&message)),
+    };
+    parsed.unwrap_or(KvsError::StringError(message))
+}
+
+// Parses "{what} of {size} bytes is over the limit of {limit} bytes".
+fn too_large_from_message(message: &str) -> Option<KvsError> {
+    let (what, rest) = message.split_once(" of ")?;
+    let (size, rest) = rest.split_once(" bytes is over the limit of ")?;
+    let limit = rest.strip_suffix(" bytes")?;
+    let what = match what {
+        "key" => LimitKind::Key,
+        "value" => LimitKind::Value,
+        "request" => LimitKind::Request,
+        _ => return None,
+    };
+    Some(KvsError::TooLarge {
+        what,
+        size: size.parse().ok()?,
+        limit: limit.parse().ok()?,
+    })
+}
+
+// Parses "Permission denied: {permission} on {key:?}".
+fn denied_from_message(message: &str) -> Option<KvsError> {
+    let rest = message.strip_prefix("Permission denied: ")?;
+    let (permission, key) = rest.split_once(" on ")?;
+    Some(KvsError::PermissionDenied {
+        permission: serde_json::from_value(serde_json::Value::from(permission)).ok()?,
+        key: serde_json::from_str(key).ok()?,
+    })
+}
+
+macro_rules! response {
+    ($($response:ident => $output:ty),*) => {$(
+        impl Response for $response {
+            type V1 = ResponseV1<$output>;
+
+            fn into_v1(self) -> ResponseV1<$output> {
+                ResponseV1::new(match self {
+                    $response::Ok(value) => Ok(value),
+                    $response::Err(err) => Err(err),
+                })
+            }
+
+            fn from_v1(v1: ResponseV1<$output>) -> $response {
+                match v1.into_result() {
+                    Ok(value) => $response::Ok(value),
+                    Err(err) => $response::Err(err),
+                }
+            }
+        }
+    )*};
+}
+
+response!(
+    SetResponse => (),
+    GetResponse => Option<String>,
+    RemoveResponse => (),
+    MergeResponse => String,
+    StatsResponse => EngineStats,
+    MultiGetResponse => Vec<Option<String>>,
+    AuthResponse => ()
+);
+
+// Batch responses also carry a result per key.
+macro_rules! batch_response {
+    ($($response:ident),*) => {$(
+        impl Response for $response {
+            type V1 = ResponseV1<Vec<KeyResultV1>>;
+
+            fn into_v1(self) -> ResponseV1<Vec<KeyResultV1>> {
+                ResponseV1::new(match self {
+                    $response::Ok(results) => {
+                        Ok(results.into_iter().map(KeyResultV1::from).collect())
+                    }
+                    $response::Err(err) => Err(err),
+                })
+            }
+
+            fn from_v1(v1: ResponseV1<Vec<KeyResultV1>>) -> $response {
+                match v1.into_result() {
+                    Ok(results) => $response::Ok(results.into_iter().map(KeyResult::from).collect()),
+                    Err(err) => $response::Err(err),
+                }
+            }
+        }
+    )*};
+}
+
+batch_response!(MultiSetResponse, MultiRemoveResponse);
+
+/// A `KeyResult` of version 1.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum KeyResultV1 {
+    Ok,
+    Err(String),
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+}
+
+impl From<KeyResult> for KeyResultV1 {
+    fn from(result: KeyResult) -> KeyResultV1 {
+        let err = match result {
+            KeyResult::Ok => return KeyResultV1::Ok,
+            KeyResult::Err(err) => err,
+        };
+        match err.code {
+            ErrorCode::TooLarge { what, size, limit } => {
+                KeyResultV1::TooLarge { what, size, limit }
+            }
+            _ => KeyResultV1::Err(err.message),
+        }
+    }
+}
+
+impl From<KeyResultV1> for KeyResult {
+    fn from(result: KeyResultV1) -> KeyResult {
+        match result {
+            KeyResultV1::Ok => KeyResult::Ok,
+            KeyResultV1::Err(message) => KeyResult::Err(error_from_message(message).into()),
+            KeyResultV1::TooLarge { what, size, limit } => {
+                KeyResult::Err(KvsError::TooLarge { what, size, limit }.into())
+            }
+        }
+    }
+}
+
+/// A `WatchResponse` of version 1.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponseV1 {
+    Subscribed,
+    Event(WatchEvent),
+    Err(String),
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+    PermissionDenied {
+        permission: Permission,
+        key: String,
+    },
+}
+
+impl Response for WatchResponse {
+    type V1 = WatchResponseV1;
+
+    fn into_v1(self) -> WatchResponseV1 {
+        match self {
+            WatchResponse::Subscribed => WatchResponseV1::Subscribed,
+            WatchResponse::Event(event) => WatchResponseV1::Event(event),
+            WatchResponse::Err(err) => match ResponseV1::<()>::new(Err(err)) {
+                ResponseV1::TooLarge { what, size, limit } => {
+                    WatchResponseV1::TooLarge { what, size, limit }
+                }
+                ResponseV1::PermissionDenied { permission, key } => {
+                    WatchResponseV1::PermissionDenied { permission, key }
+                }
+                ResponseV1::Err(message) => WatchResponseV1::Err(message),
+                ResponseV1::Ok(()) => unreachable!("built from an error"),
+            },
+        }
+    }
+
+    fn from_v1(v1: WatchResponseV1) -> WatchResponse {
+        let v1 = match v1 {
+            WatchResponseV1::Subscribed => return WatchResponse::Subscribed,
+            WatchResponseV1::Event(event) => return WatchResponse::Event(event),
+            WatchResponseV1::Err(message) => ResponseV1::<()>::Err(message),
+            WatchResponseV1::TooLarge { what, size, limit } => {
+                ResponseV1::TooLarge { what, size, limit }
+            }
+            WatchResponseV1::PermissionDenied { permission, key } => {
+                ResponseV1::PermissionDenied { permission, key }
+            }
+        };
+        match v1.into_result() {
+            Ok(()) => unreachable!("built from an error"),
+            Err(err) => WatchResponse::Err(err),
+        }
+    }
+}
+
+/// Payload of an `Opcode::Error` frame of version 1.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FrameErrorV1 {
+    /// The frame has an unknown opcode or a payload that does not decode.
+    Malformed(String),
+    TooLarge {
+        what: LimitKind,
+        size: u64,
+        limit: u64,
+    },
+    /// The session lacks a permission the request needs.
+    PermissionDenied { permission: Permission, key: String },
+}
+
+impl From<ResponseError> for FrameErrorV1 {
+    fn from(err: ResponseError) -> FrameErrorV1 {
+        match err.code {
+            ErrorCode::TooLarge { what, size, limit } => {
+                FrameErrorV1::TooLarge { what, size, limit }
+            }
+            ErrorCode::PermissionDenied { permission, key } => {
+                FrameErrorV1::PermissionDenied { permission, key }
+            }
+            _ => FrameErrorV1::Malformed(err.message),
+        }
+    }
+}
+
+impl From<FrameErrorV1> for ResponseError {
+    fn from(err: FrameErrorV1) -> ResponseError {
+        match err {
+            FrameErrorV1::Malformed(message) => ResponseError::malformed(message),
+            FrameErrorV1::TooLarge { what, size, limit } => {
+                KvsError::TooLarge { what, size, limit }.into()
+            }
+            FrameErrorV1::PermissionDenied { permission, key } => {
+                KvsError::PermissionDenied { permission, key }.into()
+            }
+        }
+    }
 }
@@ -0,0 +1,522 @@
+//! A front-end speaking the Redis serialization protocol, so that redis-cli
+//! and Redis client libraries can use the engine of a `Server`.
+//!
+//! Connections start in RESP2 and switch to RESP3 with `HELLO 3`. Commands
+//! are read as arrays of bulk strings or as inline commands, and GET, SET,
+//! DEL, EXISTS, MGET, MSET, SCAN, PING, INFO, HELLO, COMMAND and QUIT are
+//! understood.
+
+use crate::transport::Stream;
+use crate::{EngineStats, KvsEngine, Limits, Result};
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// longest line other than a bulk string, such as an inline command
+const MAX_LINE: u64 = 64 * 1024;
+// most arguments a command may have, as in Redis
+const MAX_ARGS: usize = 1024 * 1024;
+// keys a SCAN returns when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+// SCAN cursors kept per connection before the oldest are forgotten
+const MAX_CURSORS: usize = 1024;
+
+/// Serves one RESP connection until the client closes it.
+pub(crate) fn handle_client<E: KvsEngine>(engine: E, limits: Limits, stream: Stream) -> Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    debug!("RESP connection established from {}", peer_addr);
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let mut session = Session::new(engine, limits);
+
+    loop {
+        // pipelined commands are answered with a single flush
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        let args = match read_command(&mut reader, &limits) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("RESP protocol error from {}: {}", peer_addr, e);
+                Reply::Error(format!("ERR Protocol error: {}", e))
+                    .write_to(&mut writer, session.version)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = session.execute(args);
+        debug!("RESP reply to {}: {:?}", peer_addr, reply);
+        reply.write_to(&mut writer, session.version)?;
+        if quit {
+            writer.flush()?;
+            return Ok(());
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Null,
+    Array(Vec<Reply>),
+    // sent to RESP2 clients as an array of keys and values
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK".to_owned())
+    }
+
+    fn bulk(value: Option<String>) -> Reply {
+        value.map_or(Reply::Null, Reply::Bulk)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, version: u8) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", single_line(s)),
+            Reply::Error(s) => write!(writer, "-{}\r\n", single_line(s)),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(s) => {
+                write!(writer, "${}\r\n", s.len())?;
+                writer.write_all(s.as_bytes())?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Null if version >= 3 => writer.write_all(b"_\r\n"),
+            Reply::Null => writer.write_all(b"$-1\r\n"),
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                items
+                    .iter()
+                    .try_for_each(|item| item.write_to(writer, version))
+            }
+            Reply::Map(pairs) => {
+                if version >= 3 {
+                    write!(writer, "%{}\r\n", pairs.len())?;
+                } else {
+                    write!(writer, "*{}\r\n", pairs.len() * 2)?;
+                }
+                pairs.iter().try_for_each(|(key, value)| {
+                    key.write_to(writer, version)?;
+                    value.write_to(writer, version)
+                })
+            }
+        }
+    }
+}
+
+// Simple strings and errors end at the first line break.
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads the arguments of the next command, returning `None` at the end of
+// the stream. Malformed input fails with `InvalidData`, and so does a
+// command with an argument longer than a key or value may be, with more
+// than `MAX_ARGS` arguments or longer in all than a request may be.
+fn read_command<R: BufRead>(reader: &mut R, limits: &Limits) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        // an inline command, as typed into telnet
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..])?;
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length".to_owned()));
+    }
+    let max_arg_len = limits.max_key_size.max(limits.max_value_size);
+    let mut total = 0;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&line[..1.min(line.len())])
+            )));
+        }
+        let len = parse_len(&line[1..])?;
+        if len as u64 > max_arg_len {
+            return Err(protocol_error("invalid bulk length".to_owned()));
+        }
+        total += len as u64;
+        if total > limits.max_request_size() {
+            return Err(protocol_error("too big command".to_owned()));
+        }
+        let mut arg = Vec::with_capacity(len + 2);
+        (&mut *reader).take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string without CRLF".to_owned()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Reads a line without its line break, returning `None` at the end of the
+// stream.
+pub(crate) fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        if line.len() as u64 == MAX_LINE {
+            return Err(protocol_error("too big inline request".to_owned()));
+        }
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> io::Result<usize> {
+    let len = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error("invalid length".to_owned()))?;
+    // a null array or bulk string carries nothing
+    Ok(len.max(0) as usize)
+}
+
+// State of one connection.
+struct Session<E: KvsEngine> {
+    engine: E,
+    limits: Limits,
+    version: u8,
+    // last key returned for each SCAN cursor handed out
+    cursors: BTreeMap<u64, String>,
+    next_cursor: u64,
+}
+
+impl<E: KvsEngine> Session<E> {
+    fn new(engine: E, limits: Limits) -> Self {
+        Session {
+            engine,
+            limits,
+            version: 2,
+            cursors: BTreeMap::new(),
+            next_cursor: 1,
+        }
+    }
+
+    fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let mut args = match args
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+        {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR arguments must be valid UTF-8".to_owned()),
+        };
+        let name = args.remove(0).to_ascii_lowercase();
+        match self.dispatch(&name, args) {
+            Ok(reply) => reply,
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn dispatch(&mut self, name: &str, mut args: Vec<String>) -> Result<Reply> {
+        let arity = match name {
+            "get" => args.len() == 1,
+            "set" => args.len() >= 2,
+            "del" | "exists" | "mget" | "scan" => !args.is_empty(),
+            "mset" => !args.is_empty() && args.len() % 2 == 0,
+            "ping" => args.len() <= 1,
+            "hello" | "info" | "command" | "quit" => true,
+            _ => return Ok(Reply::Error(format!("ERR unknown command '{}'", name))),
+        };
+        if !arity {
+            return Ok(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            )));
+        }
+
+        match name {
+            "get" => {
+                let key = args.remove(0);
+                self.limits.check_key(&key)?;
+                Ok(Reply::bulk(self.engine.get(key)?))
+            }
+            "set" => {
+                if args.len() > 2 {
+                    return Ok(Reply::Error("ERR syntax error".to_owned()));
+                }
+                let value = args.pop().unwrap();
+                let key = args.pop().unwrap();
+                self.limits.check_key(&key)?;
+                self.limits.check_value(&value)?;
+                self.engine.set(key, value)?;
+                Ok(Reply::ok())
+            }
+            "del" => {
+                self.check_keys(&args)?;
+                let mut removed = 0;
+                for result in self.engine.remove_many(args)? {
+                    match result {
+                        Ok(()) => removed += 1,
+                        Err(crate::KvsError::KeyNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(Reply::Integer(removed))
+            }
+            "exists" => {
+                self.check_keys(&args)?;
+                let values = self.engine.get_many(args)?;
+                Ok(Reply::Integer(
+                    values.iter().filter(|value| value.is_some()).count() as i64,
+                ))
+            }
+            "mget" => {
+                self.check_keys(&args)?;
+                let values = self.engine.get_many(args)?;
+                Ok(Reply::Array(values.into_iter().map(Reply::bulk).collect()))
+            }
+            "mset" => {
+                let mut pairs = Vec::with_capacity(args.len() / 2);
+                let mut args = args.into_iter();
+                while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                    self.limits.check_key(&key)?;
+                    self.limits.check_value(&value)?;
+                    pairs.push((key, value));
+                }
+                for result in self.engine.set_many(pairs)? {
+                    result?;
+                }
+                Ok(Reply::ok())
+            }
+            "scan" => self.scan(args),
+            "ping" => Ok(match args.pop() {
+                Some(message) => Reply::Bulk(message),
+                None => Reply::Simple("PONG".to_owned()),
+            }),
+            "info" => Ok(Reply::Bulk(info(
+                &self.engine.stats()?,
+                args.first().map(String::as_str),
+            ))),
+            "hello" => self.hello(args),
+            // redis-cli asks for command documentation on startup
+            "command" => Ok(Reply::Array(Vec::new())),
+            "quit" => Ok(Reply::ok()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn check_keys(&self, keys: &[String]) -> Result<()> {
+        keys.iter().try_for_each(|key| self.limits.check_key(key))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]
+    //
+    // Cursors are numbers standing for the last key returned, so they are
+    // only valid on the connection that received them.
+    fn scan(&mut self, args: Vec<String>) -> Result<Reply> {
+        let mut args = args.into_iter();
+        let cursor = args.next().unwrap();
+        let mut pattern = "*".to_owned();
+        let mut count = DEFAULT_SCAN_COUNT;
+        while let Some(option) = args.next() {
+            match (option.to_ascii_lowercase().as_str(), args.next()) {
+                ("match", Some(value)) => pattern = value,
+                ("count", Some(value)) => match value.parse() {
+                    Ok(value) if value > 0 => count = value,
+                    _ => return Ok(Reply::Error("ERR value is out of range".to_owned())),
+                },
+                _ => return Ok(Reply::Error("ERR syntax error".to_owned())),
+            }
+        }
+        let after = match cursor.as_str() {
+            "0" => None,
+            id => match id.parse().ok().and_then(|id| self.cursors.get(&id)) {
+                Some(key) => Some(key.clone()),
+                None => return Ok(Reply::Error("ERR invalid cursor".to_owned())),
+            },
+        };
+
+        // only the keys starting with the literal start of the pattern can
+        // match it
+        let prefix_len = pattern.find(['*', '?', '[', '\\']).unwrap_or(pattern.len());
+        let page = self
+            .engine
+            .scan(&pattern[..prefix_len], after.as_deref(), count)?;
+        let next = match page.last() {
+            Some((key, _)) if page.len() == count => {
+                let id = self.next_cursor;
+                self.next_cursor += 1;
+                self.cursors.insert(id, key.clone());
+                if self.cursors.len() > MAX_CURSORS {
+                    self.cursors.pop_first();
+                }
+                id.to_string()
+            }
+            _ => "0".to_owned(),
+        };
+        let keys = page
+            .into_iter()
+            .filter(|(key, _)| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .map(|(key, _)| Reply::Bulk(key))
+            .collect();
+        Ok(Reply::Array(vec![Reply::Bulk(next), Reply::Array(keys)]))
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn hello(&mut self, args: Vec<String>) -> Result<Reply> {
+        if let Some(version) = args.first() {
+            match version.as_str() {
+                "2" => self.version = 2,
+                "3" => self.version = 3,
+                _ => {
+                    return Ok(Reply::Error(
+                        "NOPROTO unsupported protocol version".to_owned(),
+                    ))
+                }
+            }
+        }
+        let field = |name: &str| Reply::Bulk(name.to_owned());
+        Ok(Reply::Map(vec![
+            (field("server"), field("kvs")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Reply::Integer(i64::from(self.version))),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Reply::Array(Vec::new())),
+        ]))
+    }
+}
+
+// The INFO text, using the field names of Redis where there is one.
+fn info(stats: &EngineStats, section: Option<&str>) -> String {
+    let sections = [
+        (
+            "server",
+            vec![("kvs_version", env!("CARGO_PKG_VERSION").to_owned())],
+        ),
+        (
+            "stats",
+            vec![
+                ("keyspace_hits", stats.get_hits.to_string()),
+                ("keyspace_misses", stats.get_misses.to_string()),
+            ],
+        ),
+        (
+            "storage",
+            vec![
+                ("live_bytes", stats.live_bytes.to_string()),
+                ("stale_bytes", stats.stale_bytes.to_string()),
+                ("segments", stats.segment_count.to_string()),
+                ("compactions", stats.compaction_count.to_string()),
+                (
+                    "compaction_time_ms",
+                    stats.compaction_time.as_millis().to_string(),
+                ),
+            ],
+        ),
+        (
+            "keyspace",
+            vec![(
+                "db0",
+                format!("keys={},expires=0,avg_ttl=0", stats.key_count),
+            )],
+        ),
+    ];
+    let section = section.map(str::to_ascii_lowercase);
+    let mut text = String::new();
+    for (name, fields) in sections.iter() {
+        let shown = match section.as_deref() {
+            None | Some("all") | Some("default") | Some("everything") => true,
+            Some(section) => section == *name,
+        };
+        if !shown {
+            continue;
+        }
+        if !text.is_empty() {
+            text.push_str("\r\n");
+        }
+        text.push_str(&format!("# {}{}\r\n", name[..1].to_uppercase(), &name[1..]));
+        for (field, value) in fields {
+            text.push_str(&format!("{}:{}\r\n", field, value));
+        }
+    }
+    text
+}
+
+// Matches `text` against a glob pattern with `*`, `?`, `[...]` classes and
+// `\` escapes, as the MATCH option of Redis does.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            let rest = &rest[rest.iter().take_while(|&&b| b == b'*').count()..];
+            rest.is_empty() || (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) if rest.contains(&b']') => {
+            let end = rest.iter().position(|&b| b == b']').unwrap();
+            let (negated, class) = match rest[..end].split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, &rest[..end]),
+            };
+            match text.split_first() {
+                Some((&c, text)) => {
+                    in_class(class, c) != negated && glob_match(&rest[end + 1..], text)
+                }
+                None => false,
+            }
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+fn in_class(class: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            if (low..=high).contains(&c) {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
@@ -1,94 +1,804 @@
-use crate::network::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsEngine, Result, SharedQueueThreadPool, ThreadPool};
-use log::{debug, error, info};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use crate::auth::Session;
+use crate::network::{
+    write_frame, AuthResponse, FrameErrorV1, FrameHeader, GetResponse, Hello, KeyResult,
+    MergeResponse, MultiGetResponse, MultiRemoveResponse, MultiSetResponse, Opcode, Protocol,
+    RemoveResponse, Request, Response, ResponseError, ResponseV1, SetResponse, StatsResponse,
+    WatchResponse, Welcome, LEGACY_VERSION, MAGIC, TYPED_ERRORS_VERSION,
+};
+use crate::tls::{self, TlsStream};
+use crate::transport::{Address, Listener, PeerAddr, Stream};
+use crate::{http, memcached, resp};
+use crate::{
+    Acl, EngineStats, KvsEngine, KvsError, LimitKind, Limits, Result, ServerTlsOptions,
+    SharedQueueThreadPool, ThreadPool, Watcher,
+};
+use crossbeam::crossbeam_channel::{bounded, select};
+use log::{debug, error, info, warn};
+use rustls::ServerConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::de::{Deserializer, IoRead};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// how long the rest of an oversized request is discarded before closing
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+// keyspaces a server lets its clients have unless told otherwise
+const DEFAULT_MAX_KEYSPACES: usize = 1024;
+
+// Serves one connection of a front-end.
+type Handler<E> = fn(E, Limits, Stream) -> Result<()>;
 
 pub struct Server<E: KvsEngine> {
-    listener: TcpListener,
+    listener: Listener,
+    // file of the Unix socket listened on, removed when the server is dropped
+    socket_path: Option<PathBuf>,
     engine: E,
+    limits: Limits,
+    max_keyspaces: usize,
+    // set when the native protocol is served over TLS
+    tls: Option<Arc<ServerConfig>>,
+    // set when requests are checked against the grants of their session
+    acl: Option<Arc<Acl>>,
+    // listeners speaking other protocols than the native one
+    frontends: Vec<(Listener, Handler<E>)>,
+    // set once `serve` should return
+    stopping: Arc<AtomicBool>,
+    // where to connect to wake up `serve` when it is stopped
+    local_addr: Address,
+}
+
+/// Stops the `Server` it was taken from, from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stopping: Arc<AtomicBool>,
+    local_addr: Address,
+}
+
+impl ShutdownHandle {
+    /// Makes `serve` return without accepting more connections. The
+    /// connections being served are not waited for.
+    pub fn shutdown(&self) -> Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        // the listener only looks at the flag when a connection comes in
+        Stream::connect(&self.local_addr)?;
+        Ok(())
+    }
 }
 
 impl<E: KvsEngine> Server<E> {
     // TIL generic types
     // Defines a function `new` that takes a generic type `T` which
-    // must implement trait `ToSocketAddr`.
-    pub fn new<T>(addr: T, engine: E) -> Self
+    // must convert into an `Address`, either `IP:PORT` or `unix:PATH`.
+    pub fn new<T>(addr: T, engine: E) -> Result<Self>
+    where
+        T: Into<Address>,
+    {
+        Self::bind(addr.into(), engine, None)
+    }
+
+    /// Listens like `new`, with the permissions of a Unix socket set to
+    /// `mode`, such as `0o660` to let only the owner and group of the server
+    /// connect. The socket is never reachable with other permissions. Has
+    /// no effect on TCP listeners.
+    pub fn new_with_socket_mode<T>(addr: T, engine: E, mode: u32) -> Result<Self>
+    where
+        T: Into<Address>,
+    {
+        Self::bind(addr.into(), engine, Some(mode))
+    }
+
+    fn bind(addr: Address, engine: E, socket_mode: Option<u32>) -> Result<Self> {
+        let listener = match (&addr, socket_mode) {
+            #[cfg(unix)]
+            (Address::Unix(path), Some(mode)) => Listener::bind_unix_with_mode(path, mode)?,
+            _ => Listener::bind(&addr)?,
+        };
+        let local_addr = match (&addr, &listener) {
+            (Address::Tcp(_), Listener::Tcp(listener)) => {
+                let mut local_addr = listener.local_addr()?;
+                // a server listening on every interface is reached on loopback
+                if local_addr.ip().is_unspecified() {
+                    local_addr.set_ip(match local_addr.ip() {
+                        IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Address::from(local_addr)
+            }
+            _ => addr.clone(),
+        };
+        let socket_path = match addr {
+            Address::Unix(path) => Some(path),
+            Address::Tcp(_) => None,
+        };
+        Ok(Server {
+            listener,
+            socket_path,
+            engine,
+            limits: Limits::default(),
+            max_keyspaces: DEFAULT_MAX_KEYSPACES,
+            tls: None,
+            acl: None,
+            frontends: Vec::new(),
+            stopping: Arc::new(AtomicBool::new(false)),
+            local_addr,
+        })
+    }
+
+    /// Rejects requests with keys or values over `limits`, whatever limits
+    /// the engine enforces itself.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Lets requests create keyspaces while the store has fewer than
+    /// `max_keyspaces`, the default one included, 1024 by default. Past that
+    /// they fail with `KvsError::TooManyKeyspaces`.
+    pub fn with_max_keyspaces(mut self, max_keyspaces: usize) -> Self {
+        self.max_keyspaces = max_keyspaces;
+        self
+    }
+
+    /// Serves the native protocol over TLS only. The other front-ends stay
+    /// in plaintext.
+    pub fn with_tls(mut self, options: &ServerTlsOptions) -> Result<Self> {
+        self.tls = Some(tls::server_config(options)?);
+        Ok(self)
+    }
+
+    /// Lets connections do only what `acl` grants them. The other
+    /// front-ends cannot authenticate, so `serve` fails if any is added.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Also serves the engine to Redis clients on `addr`.
+    pub fn with_resp<T>(mut self, addr: T) -> Result<Self>
     where
         T: ToSocketAddrs,
     {
-        let listener = TcpListener::bind(addr).unwrap();
-        Server { listener, engine }
+        self.frontends.push((
+            Listener::Tcp(TcpListener::bind(addr)?),
+            resp::handle_client::<E>,
+        ));
+        Ok(self)
     }
 
+    /// Also serves the engine as an HTTP/JSON API on `addr`.
+    pub fn with_http<T>(mut self, addr: T) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+    {
+        self.frontends.push((
+            Listener::Tcp(TcpListener::bind(addr)?),
+            http::handle_client::<E>,
+        ));
+        Ok(self)
+    }
+
+    /// Also serves the engine to memcached clients on `addr`.
+    pub fn with_memcached<T>(mut self, addr: T) -> Result<Self>
+    where
+        T: ToSocketAddrs,
+    {
+        self.frontends.push((
+            Listener::Tcp(TcpListener::bind(addr)?),
+            memcached::handle_client::<E>,
+        ));
+        Ok(self)
+    }
+
+    /// A handle that makes `serve` return, so that the server and its
+    /// engine are dropped as usual.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stopping: self.stopping.clone(),
+            local_addr: self.local_addr.clone(),
+        }
+    }
+
+    /// Serves connections until stopped through a `ShutdownHandle`.
     pub fn serve(&self) -> Result<()> {
+        if self.acl.is_some() && !self.frontends.is_empty() {
+            return Err(KvsError::StringError(
+                "only the native protocol can be served with an ACL".to_owned(),
+            ));
+        }
         debug!("Waiting for connections...");
-        let thread_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-        let listnr = self.listener.try_clone().unwrap();
-        for stream in listnr.incoming() {
+        let thread_pool = Arc::new(SharedQueueThreadPool::new(num_cpus::get() as u32)?);
+        // front-ends accept on their own threads and share the pool
+        for (listener, handler) in &self.frontends {
+            let listener = listener.try_clone()?;
+            let thread_pool = thread_pool.clone();
             let engine = self.engine.clone();
-            thread_pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = Self::handle_client(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
-                }
-                Err(e) => error!("Connection failed, reason: {:?}", e),
-            })
+            let limits = self.limits;
+            let handler = *handler;
+            let stopping = self.stopping.clone();
+            thread::spawn(move || {
+                accept(
+                    &listener,
+                    &thread_pool,
+                    &engine,
+                    limits,
+                    None,
+                    &stopping,
+                    handler,
+                )
+            });
         }
+        let listnr = self.listener.try_clone().unwrap();
+        let acl = self.acl.clone();
+        let keyspaces = Arc::new(KeyspaceLimit {
+            max: self.max_keyspaces,
+            creating: Mutex::new(()),
+        });
+        accept(
+            &listnr,
+            &thread_pool,
+            &self.engine,
+            self.limits,
+            self.tls.as_ref(),
+            &self.stopping,
+            move |engine, limits, stream| {
+                let session = Session::new(acl.clone());
+                Self::handle_client(engine, limits, &keyspaces, session, stream)
+            },
+        );
+        debug!("Server stopped");
         Ok(())
     }
 
-    fn handle_client(engine: E, stream: TcpStream) -> Result<()> {
+    fn handle_client(
+        engine: E,
+        limits: Limits,
+        keyspaces: &KeyspaceLimit,
+        session: Session,
+        stream: Stream,
+    ) -> Result<()> {
         debug!(
             "Connection established from {}, waiting for data...",
             stream.peer_addr()?
         );
         let peer_addr = stream.peer_addr()?;
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
-
-        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
-
-        //TIL macros_rules!
-        macro_rules! send_response {
-            ($resp:expr) => {{
-                let resp = $resp;
-                serde_json::to_writer(&mut writer, &resp)?;
-                writer.flush()?;
-                info!("Response sent to {}: {:?}", peer_addr, resp);
-            };};
-        }
-
-        for req in req_reader {
-            let req = req?;
-            debug!("Received request from {}: {:?}", peer_addr, req);
-            match req {
-                Request::Get { key } => {
-                    let engine_response = match engine.get(key) {
-                        Ok(value) => GetResponse::Ok(value),
-                        Err(err) => GetResponse::Err(format!("{}", err)),
-                    };
-                    send_response!(engine_response);
+        let mut reader = BufReader::new(&stream);
+        let writer = BufWriter::new(&stream);
+        let framed = match reader.fill_buf()?.first() {
+            Some(&byte) => byte == MAGIC[0],
+            None => return Ok(()),
+        };
+        if framed {
+            Self::serve_framed(engine, limits, keyspaces, session, &stream, reader, writer)
+        } else {
+            let out = ResponseWriter::new(writer, Protocol::Legacy, LEGACY_VERSION, peer_addr);
+            Self::serve_legacy(engine, limits, keyspaces, session, &stream, reader, out)
+        }
+    }
+
+    fn serve_legacy(
+        engine: E,
+        limits: Limits,
+        keyspaces: &KeyspaceLimit,
+        mut session: Session,
+        stream: &Stream,
+        reader: BufReader<&Stream>,
+        mut out: ResponseWriter<BufWriter<&Stream>>,
+    ) -> Result<()> {
+        let peer_addr = out.peer_addr;
+        let mut reader = LimitedReader::new(reader, limits.max_request_size());
+        loop {
+            // requests a client pipelined are answered with a single flush
+            if reader.inner.buffer().is_empty() {
+                out.flush()?;
+            }
+            reader.reset();
+            let req = match Request::deserialize(&mut Deserializer::new(IoRead::new(&mut reader))) {
+                Ok(req) => req,
+                // the client closed the connection
+                Err(ref e) if e.is_eof() => return Ok(()),
+                Err(_) if reader.exceeded() => {
+                    warn!("Request from {} is over the size limit", peer_addr);
+                    // the rest of the request cannot be told apart from the
+                    // next one, so the connection is closed
+                    out.refuse(KvsError::TooLarge {
+                        what: LimitKind::Request,
+                        size: reader.read,
+                        limit: reader.limit,
+                    })?;
+                    out.flush()?;
+                    // closing with unread data resets the connection, which
+                    // can drop the response before the client reads it
+                    stream.shutdown(Shutdown::Write)?;
+                    stream.set_read_timeout(Some(DRAIN_TIMEOUT))?;
+                    let mut rest = (&mut reader.inner).take(reader.limit);
+                    let _ = io::copy(&mut rest, &mut io::sink());
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(watcher) =
+                Self::handle_request(&engine, &limits, keyspaces, &mut session, req, &mut out)?
+            {
+                return out.stream_events(watcher, stream);
+            }
+        }
+    }
+
+    fn serve_framed(
+        engine: E,
+        limits: Limits,
+        keyspaces: &KeyspaceLimit,
+        mut session: Session,
+        stream: &Stream,
+        mut reader: BufReader<&Stream>,
+        mut writer: BufWriter<&Stream>,
+    ) -> Result<()> {
+        let peer_addr = stream.peer_addr()?;
+        let hello = Hello::read_from(&mut reader)?;
+        let welcome = hello.negotiate();
+        Welcome::write_to(welcome, &mut writer)?;
+        writer.flush()?;
+        let welcome = match welcome {
+            Some(welcome) => {
+                debug!("Negotiated {:?} with {}", welcome, peer_addr);
+                welcome
+            }
+            None => {
+                warn!("No common protocol with {}: {:?}", peer_addr, hello);
+                return Ok(());
+            }
+        };
+
+        let codec = welcome.codec;
+        let mut out =
+            ResponseWriter::new(writer, Protocol::Framed(codec), welcome.version, peer_addr);
+        loop {
+            if reader.buffer().is_empty() {
+                out.flush()?;
+            }
+            let header = match FrameHeader::read_from(&mut reader)? {
+                Some(header) => header,
+                None => return Ok(()),
+            };
+            out.id = header.id;
+            let len = u64::from(header.len);
+            // the frame says where the next one starts, so a bad frame is
+            // skipped and the connection kept
+            if len > limits.max_request_size() {
+                warn!("Request from {} is over the size limit", peer_addr);
+                io::copy(&mut (&mut reader).take(len), &mut io::sink())?;
+                out.refuse(KvsError::TooLarge {
+                    what: LimitKind::Request,
+                    size: len,
+                    limit: limits.max_request_size(),
+                })?;
+                continue;
+            }
+            let mut payload = vec![0; header.len as usize];
+            reader.read_exact(&mut payload)?;
+            if Opcode::from_byte(header.opcode) != Some(Opcode::Request) {
+                out.send_error(ResponseError::malformed(format!(
+                    "unexpected opcode {}",
+                    header.opcode
+                )))?;
+                continue;
+            }
+            let req = match codec.decode(&payload) {
+                Ok(req) => req,
+                Err(e) => {
+                    out.send_error(ResponseError::malformed(format!("invalid request: {}", e)))?;
+                    continue;
                 }
-                Request::Set { key, value } => {
-                    let engine_response = match engine.set(key, value) {
+            };
+            if let Some(watcher) =
+                Self::handle_request(&engine, &limits, keyspaces, &mut session, req, &mut out)?
+            {
+                return out.stream_events(watcher, stream);
+            }
+        }
+    }
+
+    // Answers a request, returning the watcher if it started a watch. A
+    // failed authentication ends the connection with an error.
+    fn handle_request<W: Write>(
+        engine: &E,
+        limits: &Limits,
+        keyspaces: &KeyspaceLimit,
+        session: &mut Session,
+        req: Request,
+        out: &mut ResponseWriter<W>,
+    ) -> Result<Option<Watcher>> {
+        if let Err(err) = check_limits(limits, &req) {
+            out.refuse(err)?;
+            return Ok(None);
+        }
+        // logged without the token
+        if let Request::Auth { user, token } = &req {
+            debug!("Authentication of {} as {}", out.peer_addr, user);
+            if !session.authenticate(user, token) {
+                out.send(AuthResponse::from(KvsError::AuthenticationFailed))?;
+                out.flush()?;
+                return Err(KvsError::AuthenticationFailed);
+            }
+            out.send(AuthResponse::Ok(()))?;
+            return Ok(None);
+        }
+        debug!("Received request from {}: {:?}", out.peer_addr, req);
+        if let Err(err) = session.check(&req) {
+            warn!("Request from {} refused: {}", out.peer_addr, err);
+            out.refuse(err)?;
+            return Ok(None);
+        }
+        match req {
+            Request::Get { key, keyspace } => {
+                let engine_response = match select_existing(engine, keyspace)
+                    .and_then(|e| e.map_or(Ok(None), |e| e.get(key)))
+                {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(err) => err.into(),
+                };
+                out.send(engine_response)?;
+            }
+            Request::Set {
+                key,
+                value,
+                keyspace,
+            } => {
+                let engine_response =
+                    match select(engine, keyspace, keyspaces).and_then(|e| e.set(key, value)) {
                         Ok(_) => SetResponse::Ok(()),
-                        Err(err) => SetResponse::Err(format!("{}", err)),
+                        Err(err) => err.into(),
                     };
-                    send_response!(engine_response);
-                }
-                Request::Remove { key } => {
-                    let engine_response = match engine.remove(key) {
-                        Ok(_) => RemoveResponse::Ok(()),
-                        Err(err) => RemoveResponse::Err(format!("{}", err)),
+                out.send(engine_response)?;
+            }
+            Request::Remove { key, keyspace } => {
+                let engine_response = match select_existing(engine, keyspace)
+                    .and_then(|e| e.map_or(Err(KvsError::KeyNotFound), |e| e.remove(key)))
+                {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(err) => err.into(),
+                };
+                out.send(engine_response)?;
+            }
+            Request::Merge {
+                key,
+                operator,
+                operand,
+                keyspace,
+            } => {
+                let engine_response = match select(engine, keyspace, keyspaces)
+                    .and_then(|e| e.merge(key, operator, operand))
+                {
+                    Ok(value) => MergeResponse::Ok(value),
+                    Err(err) => err.into(),
+                };
+                out.send(engine_response)?;
+            }
+            Request::Stats { keyspace } => {
+                let engine_response = match select_existing(engine, keyspace)
+                    .and_then(|e| e.map_or(Ok(EngineStats::default()), |e| e.stats()))
+                {
+                    Ok(stats) => StatsResponse::Ok(stats),
+                    Err(err) => err.into(),
+                };
+                out.send(engine_response)?;
+            }
+            Request::MultiGet { keys, keyspace } => {
+                let engine_response =
+                    match select_existing(engine, keyspace).and_then(|e| match e {
+                        Some(e) => e.get_many(keys),
+                        None => Ok(vec![None; keys.len()]),
+                    }) {
+                        Ok(values) => MultiGetResponse::Ok(values),
+                        Err(err) => err.into(),
+                    };
+                out.send(engine_response)?;
+            }
+            Request::MultiSet { pairs, keyspace } => {
+                let engine_response =
+                    match select(engine, keyspace, keyspaces).and_then(|e| e.set_many(pairs)) {
+                        Ok(results) => {
+                            MultiSetResponse::Ok(results.into_iter().map(KeyResult::from).collect())
+                        }
+                        Err(err) => err.into(),
+                    };
+                out.send(engine_response)?;
+            }
+            Request::MultiRemove { keys, keyspace } => {
+                let engine_response =
+                    match select_existing(engine, keyspace).and_then(|e| match e {
+                        Some(e) => e.remove_many(keys),
+                        None => Ok(keys.iter().map(|_| Err(KvsError::KeyNotFound)).collect()),
+                    }) {
+                        Ok(results) => MultiRemoveResponse::Ok(
+                            results.into_iter().map(KeyResult::from).collect(),
+                        ),
+                        Err(err) => err.into(),
                     };
-                    send_response!(engine_response);
+                out.send(engine_response)?;
+            }
+            Request::Watch { prefix, keyspace } => {
+                match select(engine, keyspace, keyspaces).and_then(|e| e.watch(prefix)) {
+                    Ok(watcher) => {
+                        out.send(WatchResponse::Subscribed)?;
+                        return Ok(Some(watcher));
+                    }
+                    Err(err) => out.send(WatchResponse::from(err))?,
                 }
             }
+            Request::Auth { .. } => unreachable!("authentication is answered above"),
+        }
+        Ok(None)
+    }
+}
+
+// Writes the responses to a connection in the protocol it speaks. They are
+// buffered until `flush`.
+struct ResponseWriter<W: Write> {
+    writer: W,
+    protocol: Protocol,
+    // version of the framed protocol the messages are written in
+    version: u16,
+    // id of the request being answered, for framed connections
+    id: u32,
+    peer_addr: PeerAddr,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    fn new(writer: W, protocol: Protocol, version: u16, peer_addr: PeerAddr) -> Self {
+        ResponseWriter {
+            writer,
+            protocol,
+            version,
+            id: 0,
+            peer_addr,
+        }
+    }
+
+    fn send<T: Response>(&mut self, resp: T) -> Result<()> {
+        info!("Response sent to {}: {:?}", self.peer_addr, resp);
+        if self.version < TYPED_ERRORS_VERSION {
+            self.write(Opcode::Response, &resp.into_v1())
+        } else {
+            self.write(Opcode::Response, &resp)
+        }
+    }
+
+    fn send_error(&mut self, err: ResponseError) -> Result<()> {
+        info!("Error sent to {}: {:?}", self.peer_addr, err);
+        if self.version < TYPED_ERRORS_VERSION {
+            self.write(Opcode::Error, &FrameErrorV1::from(err))
+        } else {
+            self.write(Opcode::Error, &err)
+        }
+    }
+
+    fn write<T: Serialize>(&mut self, opcode: Opcode, msg: &T) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => serde_json::to_writer(&mut self.writer, msg)?,
+            Protocol::Framed(codec) => {
+                write_frame(&mut self.writer, opcode, self.id, &codec.encode(msg)?)?
+            }
         }
+        Ok(())
+    }
 
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
         Ok(())
     }
+
+    // Answers a request that is refused before it is handled, for being
+    // over a size limit or not permitted.
+    fn refuse(&mut self, err: KvsError) -> Result<()> {
+        match self.protocol {
+            Protocol::Legacy => {
+                let resp = ResponseV1::<()>::new(Err(err.into()));
+                info!("Response sent to {}: {:?}", self.peer_addr, resp);
+                self.write(Opcode::Response, &resp)
+            }
+            Protocol::Framed(_) => self.send_error(err.into()),
+        }
+    }
+
+    // Streams the events of a watch on its own thread. A watch can last
+    // forever, so it does not hold on to a worker of the pool. It ends when
+    // the client closes the connection or falls too far behind.
+    fn stream_events(&mut self, watcher: Watcher, stream: &Stream) -> Result<()> {
+        self.flush()?;
+        let stream = stream.try_clone()?;
+        let mut out = ResponseWriter {
+            writer: BufWriter::new(stream.try_clone()?),
+            protocol: self.protocol,
+            version: self.version,
+            id: self.id,
+            peer_addr: self.peer_addr,
+        };
+        // the client sends nothing once watching, so its connection is only
+        // read to learn when it is closed
+        let (closing, closed) = bounded::<()>(0);
+        let reader = stream.try_clone()?;
+        thread::spawn(move || {
+            let _ = io::copy(&mut &reader, &mut io::sink());
+            drop(closing);
+        });
+        thread::spawn(move || {
+            loop {
+                select! {
+                    recv(watcher.receiver()) -> event => {
+                        let sent = match event {
+                            Ok(event) => out.send(WatchResponse::Event(event)),
+                            Err(_) => {
+                                debug!("Watch of {} ended by the engine", out.peer_addr);
+                                break;
+                            }
+                        };
+                        if let Err(e) = sent.and_then(|()| out.flush()) {
+                            debug!("Watch of {} ended: {}", out.peer_addr, e);
+                            break;
+                        }
+                    }
+                    recv(closed) -> _ => {
+                        debug!("Watch of {} ended: connection closed", out.peer_addr);
+                        break;
+                    }
+                }
+            }
+            // also ends the read of a connection still open
+            let _ = stream.shutdown(Shutdown::Both);
+        });
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> Drop for Server<E> {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// Hands every connection made to `listener` to `handler` on the pool, in a
+// TLS session when `tls` is set, until `stopping` is set.
+fn accept<E, H>(
+    listener: &Listener,
+    thread_pool: &SharedQueueThreadPool,
+    engine: &E,
+    limits: Limits,
+    tls: Option<&Arc<ServerConfig>>,
+    stopping: &AtomicBool,
+    handler: H,
+) where
+    E: KvsEngine,
+    H: Fn(E, Limits, Stream) -> Result<()> + Clone + Send + 'static,
+{
+    loop {
+        let stream = listener.accept();
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        let engine = engine.clone();
+        let tls = tls.cloned();
+        let handler = handler.clone();
+        thread_pool.spawn(move || match stream {
+            Ok(stream) => {
+                let served = match tls {
+                    Some(config) => TlsStream::accept(stream, config)
+                        .and_then(|stream| handler(engine, limits, Stream::Tls(Box::new(stream)))),
+                    None => handler(engine, limits, stream),
+                };
+                if let Err(e) = served {
+                    error!("Error on serving client: {}", e);
+                }
+            }
+            Err(e) => error!("Connection failed, reason: {:?}", e),
+        })
+    }
+}
+
+// Checks the keys and values of a request against the server limits. A
+// batch with any key or value over them is rejected whole.
+fn check_limits(limits: &Limits, req: &Request) -> Result<()> {
+    match req {
+        Request::MultiGet { keys, .. } | Request::MultiRemove { keys, .. } => {
+            keys.iter().try_for_each(|key| limits.check_key(key))
+        }
+        Request::MultiSet { pairs, .. } => pairs.iter().try_for_each(|(key, value)| {
+            limits.check_key(key)?;
+            limits.check_value(value)
+        }),
+        Request::Set { key, value, .. } => {
+            limits.check_key(key)?;
+            limits.check_value(value)
+        }
+        Request::Merge { key, operand, .. } => {
+            limits.check_key(key)?;
+            limits.check_value(operand)
+        }
+        Request::Get { key, .. } | Request::Remove { key, .. } => limits.check_key(key),
+        Request::Watch { prefix, .. } => limits.check_key(prefix),
+        Request::Stats { .. } | Request::Auth { .. } => Ok(()),
+    }
+}
+
+// Fails reads once more than `limit` bytes were read since the last
+// `reset`, so that a huge request is never buffered whole.
+struct LimitedReader<R> {
+    inner: R,
+    read: u64,
+    limit: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        LimitedReader {
+            inner,
+            read: 0,
+            limit,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.read = 0;
+    }
+
+    fn exceeded(&self) -> bool {
+        self.read > self.limit
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.exceeded() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request over the size limit",
+            ));
+        }
+        let len = self.inner.read(buf)?;
+        self.read += len as u64;
+        Ok(len)
+    }
+}
+
+// The keyspaces clients may create, shared by every connection.
+struct KeyspaceLimit {
+    max: usize,
+    // held from counting the keyspaces until a new one is created, so that
+    // two clients cannot both create the last one allowed
+    creating: Mutex<()>,
+}
+
+// Returns the engine handle for the keyspace a write or watch names,
+// creating the keyspace if it does not exist and there are fewer than
+// allowed.
+fn select<E: KvsEngine>(engine: &E, keyspace: Option<String>, limit: &KeyspaceLimit) -> Result<E> {
+    match keyspace {
+        Some(name) if engine.has_keyspace(&name)? => engine.keyspace(&name),
+        Some(name) => {
+            let _creating = limit.creating.lock().unwrap();
+            if !engine.has_keyspace(&name)? && engine.keyspaces()?.len() >= limit.max {
+                return Err(KvsError::TooManyKeyspaces(limit.max as u64));
+            }
+            engine.keyspace(&name)
+        }
+        None => Ok(engine.clone()),
+    }
+}
+
+// Returns the engine handle for the keyspace a read names, or `None` if it
+// does not exist, which reads do not create.
+fn select_existing<E: KvsEngine>(engine: &E, keyspace: Option<String>) -> Result<Option<E>> {
+    match keyspace {
+        Some(name) if !engine.has_keyspace(&name)? => Ok(None),
+        Some(name) => engine.keyspace(&name).map(Some),
+        None => Ok(Some(engine.clone())),
+    }
 }
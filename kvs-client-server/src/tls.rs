@@ -0,0 +1,247 @@
+//! TLS for the native protocol, on top of TCP or Unix domain sockets.
+
+use crate::transport::Stream;
+use crate::{KvsError, Result};
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::ServerConnection;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Certificates a `Server` serves TLS with.
+#[derive(Debug, Clone)]
+pub struct ServerTlsOptions {
+    /// PEM file with the certificate chain of the server.
+    pub cert: PathBuf,
+    /// PEM file with the private key of the certificate.
+    pub key: PathBuf,
+    /// PEM bundle of the CAs client certificates must be signed by. Clients
+    /// without such a certificate are refused when set.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTlsOptions {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> ServerTlsOptions {
+        ServerTlsOptions {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+        }
+    }
+}
+
+/// Certificates a `Client` connects over TLS with.
+#[derive(Debug, Clone)]
+pub struct ClientTlsOptions {
+    /// PEM bundle of the CAs trusted to sign the server certificate.
+    pub ca: PathBuf,
+    /// PEM files with the certificate chain and private key presented to
+    /// servers that require client certificates.
+    pub cert: Option<(PathBuf, PathBuf)>,
+    /// The name the server certificate must be valid for. Defaults to the
+    /// host of a TCP address and to `localhost` for Unix sockets.
+    pub server_name: Option<String>,
+}
+
+impl ClientTlsOptions {
+    pub fn new(ca: impl Into<PathBuf>) -> ClientTlsOptions {
+        ClientTlsOptions {
+            ca: ca.into(),
+            cert: None,
+            server_name: None,
+        }
+    }
+}
+
+pub(crate) fn server_config(options: &ServerTlsOptions) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(root_store(path)?), provider)
+                    .build()
+                    .map_err(|e| KvsError::Tls(e.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs(&options.cert)?, private_key(&options.key)?)
+        .map_err(tls_error)?;
+    Ok(Arc::new(config))
+}
+
+pub(crate) fn client_config(options: &ClientTlsOptions) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(root_store(&options.ca)?);
+    let config = match &options.cert {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(certs(cert)?, private_key(key)?)
+            .map_err(tls_error)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = open(path)?;
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(KvsError::Tls(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = open(path)?;
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| KvsError::Tls(format!("no private key in {}", path.display())))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| KvsError::Tls(format!("cannot open {}: {}", path.display(), e)))
+}
+
+fn root_store(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+fn tls_error(err: rustls::Error) -> KvsError {
+    KvsError::Tls(err.to_string())
+}
+
+pub(crate) fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_owned())
+        .map_err(|_| KvsError::Tls(format!("invalid server name {:?}", name)))
+}
+
+/// A TLS session over a socket.
+///
+/// Clones share the session, so that one thread can read while another
+/// writes, as they do with plain sockets. The socket is never read or
+/// written with the session locked.
+pub(crate) struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    // keeps the records of writers in the order the session made them
+    write_lock: Arc<Mutex<()>>,
+    sock: Stream,
+}
+
+impl TlsStream {
+    /// Starts a session with a client. The handshake completes on the first
+    /// read.
+    pub(crate) fn accept(sock: Stream, config: Arc<ServerConfig>) -> Result<TlsStream> {
+        let conn = ServerConnection::new(config).map_err(tls_error)?;
+        Ok(TlsStream::new(sock, conn.into()))
+    }
+
+    /// Starts a session with a server, returning once the server is
+    /// verified.
+    pub(crate) fn connect(
+        mut sock: Stream,
+        config: Arc<ClientConfig>,
+        name: ServerName<'static>,
+    ) -> Result<TlsStream> {
+        let mut conn = ClientConnection::new(config, name).map_err(tls_error)?;
+        conn.complete_io(&mut sock)?;
+        Ok(TlsStream::new(sock, conn.into()))
+    }
+
+    fn new(sock: Stream, conn: Connection) -> TlsStream {
+        TlsStream {
+            conn: Arc::new(Mutex::new(conn)),
+            write_lock: Arc::new(Mutex::new(())),
+            sock,
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            conn: self.conn.clone(),
+            write_lock: self.write_lock.clone(),
+            sock: self.sock.try_clone()?,
+        })
+    }
+
+    pub(crate) fn socket(&self) -> &Stream {
+        &self.sock
+    }
+
+    // Sends the records the session has queued.
+    fn send_pending(&self) -> io::Result<()> {
+        let _order = self.write_lock.lock().unwrap();
+        let mut records = Vec::new();
+        {
+            let mut conn = self.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut records)?;
+            }
+        }
+        (&self.sock).write_all(&records)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0; 16 * 1024];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Ok(n) => return Ok(n),
+                // a peer closing without close_notify ends the stream as
+                // it would a plain socket, the protocols frame their own
+                // messages
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            self.send_pending()?;
+            let n = (&self.sock).read(&mut records)?;
+            let mut records = &records[..n];
+            let processed = {
+                let mut conn = self.conn.lock().unwrap();
+                loop {
+                    conn.read_tls(&mut records)?;
+                    let state = conn.process_new_packets();
+                    if state.is_err() || records.is_empty() {
+                        break state;
+                    }
+                }
+            };
+            // alerts are sent before failing
+            self.send_pending()?;
+            processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.lock().unwrap().writer().write(buf)?;
+        self.send_pending()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.lock().unwrap().writer().flush()?;
+        self.send_pending()
+    }
+}
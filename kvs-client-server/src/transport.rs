@@ -0,0 +1,283 @@
+//! The connections a `Server` accepts and a `Client` makes, over TCP or
+//! over Unix domain sockets, optionally with TLS.
+
+use crate::tls::TlsStream;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Where a server listens or a client connects.
+///
+/// Addresses are written `IP:PORT` for TCP and `unix:PATH` for a Unix
+/// domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<&str> for Address {
+    fn from(s: &str) -> Address {
+        match s.strip_prefix("unix:") {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(s.to_owned()),
+        }
+    }
+}
+
+impl From<String> for Address {
+    fn from(s: String) -> Address {
+        Address::from(s.as_str())
+    }
+}
+
+impl From<&String> for Address {
+    fn from(s: &String) -> Address {
+        Address::from(s.as_str())
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Address {
+        Address::Tcp(addr.to_string())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/// The other end of a connection, for logging. Clients of a Unix socket
+/// have no address.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+/// A connection over TCP or a Unix domain socket.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    pub(crate) fn connect(addr: &Address) -> io::Result<Stream> {
+        match addr {
+            Address::Tcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr.as_str())?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unsupported()),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+            Stream::Tls(stream) => Ok(Stream::Tls(Box::new(stream.try_clone()?))),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.socket().shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.socket().set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<PeerAddr> {
+        match self {
+            Stream::Tcp(stream) => Ok(PeerAddr::Tcp(stream.peer_addr()?)),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(PeerAddr::Unix),
+            Stream::Tls(stream) => stream.socket().peer_addr(),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+            Stream::Tls(stream) => (&**stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+            Stream::Tls(stream) => (&**stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+            Stream::Tls(stream) => (&**stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// Removes the socket file at `path` if no server listens on it anymore.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    let stale = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type().is_socket() && UnixStream::connect(path).is_err(),
+        Err(_) => false,
+    };
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A listener for connections over TCP or a Unix domain socket.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds to `addr`. A socket file left behind by a server that is no
+    /// longer running is replaced.
+    pub(crate) fn bind(addr: &Address) -> io::Result<Listener> {
+        match addr {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr.as_str())?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unsupported()),
+        }
+    }
+
+    /// Binds a Unix socket at `path` that only `mode` lets connect, like
+    /// `bind` does. The socket is made in a directory only the server can
+    /// enter and moved to `path` once its permissions are set, so it is
+    /// never reachable with looser ones.
+    #[cfg(unix)]
+    pub(crate) fn bind_unix_with_mode(path: &Path, mode: u32) -> io::Result<Listener> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // tells apart the directories of binds made at the same time
+        static BINDS: AtomicUsize = AtomicUsize::new(0);
+
+        remove_stale_socket(path)?;
+        // the rename below would replace whatever is there
+        if std::fs::symlink_metadata(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let dir = parent.join(format!(
+            ".kvs-{}-{}",
+            std::process::id(),
+            BINDS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let private_path = dir.join("s");
+        let bound = UnixListener::bind(&private_path).and_then(|listener| {
+            std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+            std::fs::rename(&private_path, path)?;
+            Ok(listener)
+        });
+        if bound.is_err() {
+            let _ = std::fs::remove_file(&private_path);
+        }
+        std::fs::remove_dir(&dir)?;
+        Ok(Listener::Unix(bound?))
+    }
+
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => Ok(Stream::Tcp(listener.accept()?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Stream::Unix(listener.accept()?.0)),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => Ok(Listener::Tcp(listener.try_clone()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Listener::Unix(listener.try_clone()?)),
+        }
+    }
+}
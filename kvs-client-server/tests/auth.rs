@@ -0,0 +1,174 @@
+// Enforces an ACL on the native protocol of `kvs-server`.
+
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{start_server, Server};
+use kvs::{Client, KvsError, Permission, Protocol, Result};
+use predicates::str::contains;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+const ACL: &str = r#"{
+    "users": {
+        "admin": { "token": "root-token", "grants": [{ "prefix": "", "permission": "admin" }] },
+        "app": {
+            "token": "app-token",
+            "grants": [
+                { "prefix": "app:", "permission": "write" },
+                { "prefix": "config:", "permission": "read" },
+                { "prefix": "", "permission": "write", "keyspace": "scratch" }
+            ]
+        }
+    },
+    "anonymous": [{ "prefix": "public:", "permission": "read" }]
+}"#;
+
+fn start_acl_server(addr: &str, temp_dir: &TempDir) -> Server {
+    fs::write(temp_dir.path().join("acl.json"), ACL).unwrap();
+    start_server(
+        temp_dir.path(),
+        &["--engine", "kvs", "--addr", addr, "--acl", "acl.json"],
+    )
+}
+
+fn login(addr: &str, user: &str, token: &str) -> Result<Client> {
+    let mut client = Client::new(addr)?;
+    client.authenticate(user.to_owned(), token.to_owned())?;
+    Ok(client)
+}
+
+// Asserts that `result` is a denial of `permission` on `key`.
+fn assert_denied<T: std::fmt::Debug>(result: Result<T>, permission: Permission, key: &str) {
+    match result {
+        Err(KvsError::PermissionDenied {
+            permission: denied,
+            key: denied_key,
+        }) => assert_eq!((denied, denied_key.as_str()), (permission, key)),
+        other => panic!("expected a denial, got {:?}", other),
+    }
+}
+
+#[test]
+fn auth_grants() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_acl_server("127.0.0.1:4024", &temp_dir);
+    let addr = "127.0.0.1:4024";
+
+    let mut admin = login(addr, "admin", "root-token")?;
+    admin.set("public:motd".to_owned(), "hello".to_owned())?;
+    admin.set("config:mode".to_owned(), "fast".to_owned())?;
+    admin.set("secret".to_owned(), "42".to_owned())?;
+    assert_eq!(admin.stats()?.key_count, 3);
+    drop(admin);
+
+    let mut app = login(addr, "app", "app-token")?;
+    app.set("app:1".to_owned(), "one".to_owned())?;
+    assert_eq!(app.get("app:1".to_owned())?, Some("one".to_owned()));
+    assert_eq!(app.get("config:mode".to_owned())?, Some("fast".to_owned()));
+    assert_denied(
+        app.set("config:mode".to_owned(), "slow".to_owned()),
+        Permission::Write,
+        "config:mode",
+    );
+    assert_denied(app.get("secret".to_owned()), Permission::Read, "secret");
+    assert_denied(app.stats(), Permission::Admin, "");
+    // a batch with any key not granted is denied whole
+    assert_denied(
+        app.set_many(vec![
+            ("app:2".to_owned(), "two".to_owned()),
+            ("secret".to_owned(), "0".to_owned()),
+        ]),
+        Permission::Write,
+        "secret",
+    );
+    assert_eq!(app.get("app:2".to_owned())?, None);
+    // grants are per keyspace
+    app.set_keyspace(Some("scratch".to_owned()));
+    app.set("secret".to_owned(), "mine".to_owned())?;
+    drop(app);
+
+    // anonymous connections, legacy ones included, get the anonymous grants
+    for protocol in &["legacy", "json", "bincode"] {
+        let mut anonymous = Client::with_protocol(addr, protocol.parse::<Protocol>()?)?;
+        assert_eq!(
+            anonymous.get("public:motd".to_owned())?,
+            Some("hello".to_owned())
+        );
+        assert_denied(
+            anonymous.set("public:motd".to_owned(), "bye".to_owned()),
+            Permission::Write,
+            "public:motd",
+        );
+        assert_denied(anonymous.get("app:1".to_owned()), Permission::Read, "app:1");
+    }
+
+    // watches need read on every key under their prefix
+    let denied = Client::new(addr)?.watch("".to_owned());
+    assert_denied(denied.map(|_| ()), Permission::Read, "");
+    let watch = login(addr, "app", "app-token")?.watch("app:".to_owned());
+    assert!(watch.is_ok());
+    drop(watch);
+
+    // a wrong token ends the connection
+    let mut client = Client::new(addr)?;
+    match client.authenticate("app".to_owned(), "guess".to_owned()) {
+        Err(KvsError::AuthenticationFailed) => {}
+        other => panic!("expected a failed authentication, got {:?}", other),
+    }
+    assert!(client.get("public:motd".to_owned()).is_err());
+    drop(client);
+    assert!(matches!(
+        login(addr, "nobody", "root-token"),
+        Err(KvsError::AuthenticationFailed)
+    ));
+    Ok(())
+}
+
+#[test]
+fn auth_cli() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_acl_server("127.0.0.1:4025", &temp_dir);
+    let addr = "127.0.0.1:4025";
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "app:1", "one", "--addr", addr])
+        .args(["--user", "app", "--token", "app-token"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app:1", "--addr", addr, "--user", "app"])
+        .env("KVS_TOKEN", "app-token")
+        .assert()
+        .success()
+        .stdout("one\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "app:1", "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Permission denied: write on \"app:1\""));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "app:1", "--addr", addr])
+        .args(["--user", "app", "--token", "wrong"])
+        .assert()
+        .failure()
+        .stderr(contains("Authentication failed"));
+
+    // the other front-ends cannot authenticate
+    let other_dir = TempDir::new().unwrap();
+    fs::write(other_dir.path().join("acl.json"), ACL).unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4026", "--acl", "acl.json"])
+        .args(["--resp-addr", "127.0.0.1:4027"])
+        .current_dir(&other_dir)
+        .assert()
+        .failure()
+        .stderr(contains("only the native protocol"));
+    Ok(())
+}
@@ -1,12 +1,20 @@
 use assert_cmd::prelude::*;
+use kvs::{
+    Client, Codec, KvStore, KvsEngine, KvsError, LimitKind, MergeOperator, Protocol, Reply, Result,
+    SledKvsEngine, WatchEvent,
+};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+mod common;
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -20,28 +28,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +60,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +99,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +131,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +142,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +153,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +165,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +187,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +207,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +228,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +249,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +257,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +265,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +273,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +281,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +289,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,12 +297,76 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "--by", "-3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["merge", "counter", "10", "--op", "max", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("10\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not an integer"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "other", "--keyspace", "team", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--keyspace", "team", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("other\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "counter", "--keyspace", "team", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--keyspace", "../team", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid keyspace"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2"));
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -298,26 +374,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("server did not exit");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +412,691 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn admin_cli_verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("3 records, 0 problems"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2"));
+
+    // Corrupt the record holding key2
+    let log_path = temp_dir.path().join("current.db");
+    let log = fs::read_to_string(&log_path).unwrap();
+    fs::write(&log_path, log.replacen("\"value2\"", "\"value2", 1)).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("unreadable"));
+
+    let repaired = temp_dir.path().join("repaired");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["repair", repaired.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("salvaged 1 keys"));
+
+    let store = KvStore::open(&repaired)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn cli_watch_prefix() -> Result<()> {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = common::start_server(temp_dir.path(), &["--engine", "kvs", "--addr", addr]);
+
+    let mut events = Client::new(addr)?.watch("key".to_owned())?;
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "other", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    assert_eq!(
+        events.next().unwrap()?,
+        WatchEvent::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned()
+        }
+    );
+    assert_eq!(
+        events.next().unwrap()?,
+        WatchEvent::Remove {
+            key: "key1".to_owned()
+        }
+    );
+
+    Ok(())
+}
+
+#[test]
+fn admin_cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store
+        .keyspace("team")?
+        .set("key1".to_owned(), "team1".to_owned())?;
+    drop(store);
+    fs::write(temp_dir.path().join("engine"), "kvs").unwrap();
+
+    let dest = temp_dir.path().join("migrated");
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--to", "sled", dest.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("migrated 2001 keys from kvs to sled"));
+    assert_eq!(fs::read_to_string(dest.join("engine")).unwrap(), "sled");
+
+    // The destination must be fresh
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--to", "sled", dest.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let engine = SledKvsEngine::open(&dest)?;
+    assert_eq!(engine.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(
+        engine.get("key1999".to_owned())?,
+        Some("value1999".to_owned())
+    );
+    assert_eq!(
+        engine.keyspace("team")?.get("key1".to_owned())?,
+        Some("team1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_memory_engine() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // Without --snapshot-interval nothing is written to the directory
+    assert_eq!(temp_dir.path().read_dir().unwrap().count(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_stops_on_sigterm() {
+    let addr = "127.0.0.1:4035";
+    let temp_dir = TempDir::new().unwrap();
+    let start_server = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "memory", "--snapshot-interval", "3600"])
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    // the engine is dropped as usual, here taking a last snapshot
+    Command::new("kill")
+        .arg(child.id().to_string())
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let mut child = start_server();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_size_limits() -> Result<()> {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = common::start_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--max-key-size",
+            "16",
+            "--max-value-size",
+            "1024",
+        ],
+    );
+
+    let mut client = Client::new(addr)?;
+    match client.set("key1".to_owned(), "v".repeat(2000)) {
+        Err(KvsError::TooLarge {
+            what: LimitKind::Value,
+            size: 2000,
+            limit: 1024,
+        }) => {}
+        other => panic!("expected a too large value, got {:?}", other.err()),
+    }
+    match client.get("k".repeat(17)) {
+        Err(KvsError::TooLarge {
+            what: LimitKind::Key,
+            ..
+        }) => {}
+        other => panic!("expected a too large key, got {:?}", other.err()),
+    }
+    // the connection is still usable after a rejected request
+    client.set("key1".to_owned(), "v".repeat(1024))?;
+    assert_eq!(client.get("key1".to_owned())?, Some("v".repeat(1024)));
+    // the server may run a single worker, which serves one connection at a time
+    drop(client);
+
+    // a request too large to decode is rejected before it is read whole
+    let mut client = Client::new(addr)?;
+    match client.set("key2".to_owned(), "v".repeat(15_000)) {
+        Err(KvsError::TooLarge {
+            what: LimitKind::Request,
+            ..
+        }) => {}
+        other => panic!("expected a too large request, got {:?}", other.err()),
+    }
+    drop(client);
+    assert_eq!(Client::new(addr)?.get("key2".to_owned())?, None);
+
+    // a batch shares the request limit of one key and value at their limits
+    let batch = |count: usize| {
+        (0..count)
+            .map(|i| (format!("batch{}", i), "v".repeat(1000)))
+            .collect::<Vec<_>>()
+    };
+    let results = Client::new(addr)?.set_many(batch(5))?;
+    assert!(results.iter().all(Result::is_ok));
+    match Client::new(addr)?.set_many(batch(20)) {
+        Err(KvsError::TooLarge {
+            what: LimitKind::Request,
+            ..
+        }) => {}
+        other => panic!("expected a too large request, got {:?}", other.err()),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cli_protocols() -> Result<()> {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = common::start_server(temp_dir.path(), &["--engine", "kvs", "--addr", addr]);
+
+    let mut client = Client::new(addr)?;
+    assert_eq!(client.protocol(), Protocol::Framed(Codec::Bincode));
+    client.set_keyspace(Some("team".to_owned()));
+    client.set("key1".to_owned(), "bincode".to_owned())?;
+    assert_eq!(client.stats()?.key_count, 1);
+    drop(client);
+    for protocol in &["legacy", "json", "bincode"] {
+        let mut client = Client::with_protocol(addr, protocol.parse()?)?;
+        client.set(protocol.to_string(), protocol.to_string())?;
+        client.set_keyspace(Some("team".to_owned()));
+        assert_eq!(client.get("key1".to_owned())?, Some("bincode".to_owned()));
+        if *protocol == "legacy" {
+            // legacy servers only send the message of an error, which is
+            // mapped back when known
+            assert!(matches!(
+                client.remove("nope".to_owned()),
+                Err(KvsError::KeyNotFound)
+            ));
+            continue;
+        }
+        // errors of the server come back as the `KvsError` they were
+        assert!(matches!(
+            client.remove("nope".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert!(matches!(
+            client.merge("key1".to_owned(), MergeOperator::Add, "1".to_owned()),
+            Err(KvsError::InvalidMerge(_))
+        ));
+        let results = client.remove_many(vec!["nope".to_owned()])?;
+        assert!(matches!(results[0], Err(KvsError::KeyNotFound)));
+        client.set_keyspace(Some("no spaces".to_owned()));
+        match client.get("key1".to_owned()) {
+            Err(KvsError::InvalidKeyspace(name)) => assert_eq!(name, "no spaces"),
+            other => panic!("expected an invalid keyspace, got {:?}", other),
+        }
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "legacy", "--protocol", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("legacy\n");
+
+    // clients from before framing send bare JSON
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(br#"{"Get":{"key":"json"}}{"Remove":{"key":"nope"}}"#)?;
+    let mut responses = serde_json::Deserializer::from_reader(&stream).into_iter();
+    let get: serde_json::Value = responses.next().unwrap()?;
+    assert_eq!(get, serde_json::json!({ "Ok": "json" }));
+    let remove: serde_json::Value = responses.next().unwrap()?;
+    assert_eq!(remove, serde_json::json!({ "Err": "Key not found" }));
+    drop(stream);
+
+    // a frame that does not decode is answered without closing the
+    // connection
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP\x00\x01\x00\x05\x02\x07\x01")?;
+    let mut welcome = [0; 7];
+    stream.read_exact(&mut welcome)?;
+    assert_eq!(&welcome, b"KVSP\x00\x02\x01");
+    stream.write_all(b"\x00\x00\x00\x08\x01\x00\x00\x00\x07not json")?;
+    let (opcode, id, payload) = read_frame(&mut stream)?;
+    assert_eq!((opcode, id), (3, 7));
+    assert!(payload.starts_with(br#"{"code":"Malformed","message":"invalid request"#));
+    let request = br#"{"Get":{"key":"bincode"}}"#;
+    stream.write_all(&(request.len() as u32).to_be_bytes())?;
+    stream.write_all(b"\x01\x00\x00\x00\x08")?;
+    stream.write_all(request)?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        (2, 8, br#"{"Ok":"bincode"}"#.to_vec())
+    );
+    drop(stream);
+
+    // clients of version 1 get errors in the shapes of version 1
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP\x00\x01\x00\x01\x01\x01")?;
+    let mut welcome = [0; 7];
+    stream.read_exact(&mut welcome)?;
+    assert_eq!(&welcome, b"KVSP\x00\x01\x01");
+    stream.write_all(b"\x00\x00\x00\x08\x01\x00\x00\x00\x07not json")?;
+    let (opcode, id, payload) = read_frame(&mut stream)?;
+    assert_eq!((opcode, id), (3, 7));
+    assert!(payload.starts_with(br#"{"Malformed":"invalid request"#));
+    let request = br#"{"Remove":{"key":"nope"}}"#;
+    stream.write_all(&(request.len() as u32).to_be_bytes())?;
+    stream.write_all(b"\x01\x00\x00\x00\x08")?;
+    stream.write_all(request)?;
+    assert_eq!(
+        read_frame(&mut stream)?,
+        (2, 8, br#"{"Err":"Key not found"}"#.to_vec())
+    );
+    drop(stream);
+
+    // no common codec
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVSP\x00\x01\x00\x01\x01\x07")?;
+    let mut welcome = Vec::new();
+    stream.read_to_end(&mut welcome)?;
+    assert_eq!(welcome, b"KVSP\x00\x00\x00");
+
+    Ok(())
+}
+
+#[test]
+fn version_1_error_messages_are_mapped_back() -> Result<()> {
+    let addr = "127.0.0.1:4034";
+    let listener = TcpListener::bind(addr)?;
+    // a server of version 1, which sends errors as their message
+    let server = thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let mut hello = [0; 9];
+        stream.read_exact(&mut hello)?;
+        let mut codecs = vec![0; hello[8] as usize];
+        stream.read_exact(&mut codecs)?;
+        stream.write_all(b"KVSP\x00\x01\x01")?;
+        let (opcode, id, _) = read_frame(&mut stream)?;
+        assert_eq!(opcode, 1);
+        let response = br#"{"Err":"Key not found"}"#;
+        stream.write_all(&(response.len() as u32).to_be_bytes())?;
+        stream.write_all(&[2])?;
+        stream.write_all(&id.to_be_bytes())?;
+        stream.write_all(response)?;
+        Ok(())
+    });
+
+    let mut client = Client::with_protocol(addr, "json".parse()?)?;
+    assert!(matches!(
+        client.remove("nope".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    server.join().unwrap()
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, u32, Vec<u8>)> {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((header[4], id, payload))
+}
+
+#[test]
+fn cli_pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4010";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = common::start_server(temp_dir.path(), &["--engine", "kvs", "--addr", addr]);
+
+    for protocol in &[Protocol::Legacy, Protocol::Framed(Codec::Bincode)] {
+        let mut client = Client::with_protocol(addr, *protocol)?;
+        // more than the socket buffers hold in both directions
+        let value = "v".repeat(10_000);
+        let mut pipeline = client.pipeline();
+        for i in 0..500 {
+            pipeline.set(format!("key{}", i), value.clone());
+        }
+        assert_eq!(pipeline.len(), 500);
+        for reply in pipeline.execute()? {
+            assert_eq!(reply?, Reply::Set);
+        }
+        let mut pipeline = client.pipeline();
+        for i in 0..500 {
+            pipeline.get(format!("key{}", i));
+        }
+        for reply in pipeline.execute()? {
+            assert_eq!(reply?, Reply::Get(Some(value.clone())));
+        }
+
+        let mut pipeline = client.pipeline();
+        pipeline
+            .remove("missing".to_owned())
+            .set("counter".to_owned(), "1".to_owned())
+            .merge("counter".to_owned(), MergeOperator::Add, "2".to_owned())
+            .remove("counter".to_owned())
+            .get("counter".to_owned());
+        let replies = pipeline.execute()?;
+        assert!(replies[0].is_err());
+        assert_eq!(replies[1].as_ref().ok(), Some(&Reply::Set));
+        assert_eq!(
+            replies[2].as_ref().ok(),
+            Some(&Reply::Merge("3".to_owned()))
+        );
+        assert_eq!(replies[3].as_ref().ok(), Some(&Reply::Remove));
+        assert_eq!(replies[4].as_ref().ok(), Some(&Reply::Get(None)));
+        // the client is still usable
+        assert_eq!(client.get("key0".to_owned())?, Some(value));
+        assert!(client.pipeline().execute()?.is_empty());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cli_batches() -> Result<()> {
+    let addr = "127.0.0.1:4011";
+    let temp_dir = TempDir::new().unwrap();
+    let _server = common::start_server(
+        temp_dir.path(),
+        &["--engine", "kvs", "--addr", addr, "--max-value-size", "16"],
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key1", "value1", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key1", "key3", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\nvalue2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mdel", "key1", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("key3: Key not found"));
+
+    let mut client = Client::new(addr)?;
+    assert_eq!(
+        client.get_many(vec!["key1".to_owned(), "key2".to_owned()])?,
+        vec![None, Some("value2".to_owned())]
+    );
+    // a value over the server limits rejects the whole batch
+    match client.set_many(vec![
+        ("key3".to_owned(), "value3".to_owned()),
+        ("key4".to_owned(), "v".repeat(17)),
+    ]) {
+        Err(KvsError::TooLarge {
+            what: LimitKind::Value,
+            ..
+        }) => {}
+        other => panic!("expected a too large value, got {:?}", other),
+    }
+    assert_eq!(client.get("key3".to_owned())?, None);
+    let results = client.remove_many(vec!["key2".to_owned(), "key2".to_owned()])?;
+    assert!(results[0].is_ok());
+    assert!(results[1].is_err());
+    drop(client);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_unix_socket() {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", socket.display());
+    let server_args = ["--engine", "kvs", "--addr", &addr, "--socket-mode", "600"];
+    let start_server = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(server_args)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let mut child = start_server();
+
+    let metadata = fs::metadata(&socket).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // a socket in use is not taken over by another server
+    assert_cmd::Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(server_args)
+        .current_dir(&temp_dir)
+        .timeout(Duration::from_secs(5))
+        .assert()
+        .failure();
+    assert_eq!(
+        Client::new(addr.as_str())
+            .unwrap()
+            .get("key1".to_owned())
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // a socket left behind by a killed server is replaced
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(socket.exists());
+    let mut child = start_server();
+    assert_eq!(
+        Client::new(addr.as_str())
+            .unwrap()
+            .get("key1".to_owned())
+            .unwrap(),
+        Some("value1".to_owned())
+    );
+
+    // a server stopped with SIGTERM removes its socket
+    Command::new("kill")
+        .arg(child.id().to_string())
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+    assert!(!socket.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn unix_socket_mode_is_set_before_serving() -> Result<()> {
+    use kvs::Server;
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let addr = format!("unix:{}", socket.display());
+    let engine = KvStore::open(temp_dir.path())?;
+    let server = Server::new_with_socket_mode(addr.as_str(), engine.clone(), 0o600)?;
+    assert_eq!(fs::metadata(&socket)?.permissions().mode() & 0o777, 0o600);
+    // nothing is left of the private directory the socket was bound in
+    for entry in fs::read_dir(temp_dir.path())? {
+        assert!(!entry?.file_name().to_string_lossy().starts_with(".kvs-"));
+    }
+    // a socket in use is not taken over
+    assert!(Server::new(addr.as_str(), engine.clone()).is_err());
+    assert!(Server::new_with_socket_mode(addr.as_str(), engine, 0o600).is_err());
+    thread::spawn(move || server.serve());
+
+    assert_eq!(Client::new(addr.as_str())?.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn keyspaces_are_created_by_writes_only() -> Result<()> {
+    use kvs::Server;
+
+    let temp_dir = TempDir::new().unwrap();
+    let server =
+        Server::new("127.0.0.1:4033", KvStore::open(temp_dir.path())?)?.with_max_keyspaces(2);
+    thread::spawn(move || server.serve());
+
+    // reads of a keyspace that does not exist leave it so
+    let mut client = Client::new("127.0.0.1:4033")?;
+    client.set_keyspace(Some("ghost".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get_many(vec!["key1".to_owned()])?, [None]);
+    assert_eq!(client.stats()?.key_count, 0);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(!temp_dir.path().join("keyspaces").join("ghost").exists());
+
+    // writes create it, up to the limit
+    client.set_keyspace(Some("team".to_owned()));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set_keyspace(Some("other".to_owned()));
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::TooManyKeyspaces(2))
+    ));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set_keyspace(Some("team".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,100 @@
+// Helpers for the tests that run `kvs-server` and talk to it over TCP.
+
+// every test file uses only some of them
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use kvs::Result;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long a server may take to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A running `kvs-server`, killed when a test finishes whether or not it
+/// passed.
+pub struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().expect("server exited before killed");
+        self.0.wait().unwrap();
+    }
+}
+
+/// Starts `kvs-server` with `args` in `dir`, returning once the address of
+/// every `--addr` and `--*-addr` argument accepts connections.
+pub fn start_server<S: AsRef<OsStr>>(dir: &Path, args: &[S]) -> Server {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let mut server = Server(child);
+    let addrs = args.windows(2).filter_map(|pair| {
+        let flag = pair[0].as_ref().to_str()?;
+        if flag.starts_with("--") && flag.ends_with("addr") {
+            pair[1].as_ref().to_str()
+        } else {
+            None
+        }
+    });
+    for addr in addrs {
+        server.wait_for(addr);
+    }
+    server
+}
+
+impl Server {
+    fn wait_for(&mut self, addr: &str) {
+        let deadline = Instant::now() + START_TIMEOUT;
+        while TcpStream::connect(addr).is_err() {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                panic!("server exited with {} before listening", status);
+            }
+            assert!(
+                Instant::now() < deadline,
+                "server is not listening on {}",
+                addr
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// A connection speaking a line based protocol.
+pub struct Connection {
+    pub stream: TcpStream,
+    pub reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    pub fn open(addr: &str) -> Result<Connection> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Connection { stream, reader })
+    }
+
+    /// Checks the raw bytes read next.
+    pub fn expect(&mut self, expected: &str) -> Result<()> {
+        let mut reply = vec![0; expected.len()];
+        self.reader.read_exact(&mut reply)?;
+        assert_eq!(String::from_utf8_lossy(&reply), expected);
+        Ok(())
+    }
+
+    /// Reads a line ending with CRLF, without it.
+    pub fn line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        assert!(line.ends_with("\r\n"), "unterminated line {:?}", line);
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+}
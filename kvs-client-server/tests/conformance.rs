@@ -0,0 +1,342 @@
+// Behaviour every `KvsEngine` must share, run against each engine of the
+// crate by `conformance_tests!` at the bottom of this file.
+
+use kvs::{
+    KvStore, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MemoryKvsEngine, Result, SledKvsEngine,
+};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+trait TestEngine: KvsEngine {
+    fn open(path: &Path) -> Result<Self>;
+
+    // Makes everything written so far visible to the next `open`.
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+
+    // Whether the engine compacts on its own and counts it in `stats`.
+    fn counts_compactions() -> bool {
+        false
+    }
+}
+
+impl TestEngine for KvStore {
+    fn open(path: &Path) -> Result<Self> {
+        KvStore::open(path)
+    }
+
+    fn counts_compactions() -> bool {
+        true
+    }
+}
+
+impl TestEngine for SledKvsEngine {
+    fn open(path: &Path) -> Result<Self> {
+        // sled releases its file lock from a background thread once the last
+        // handle is dropped, so a quick reopen may have to wait for it
+        let mut attempts = 0;
+        loop {
+            match SledKvsEngine::open(path) {
+                Err(_) if attempts < 50 => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(20));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl TestEngine for MemoryKvsEngine {
+    fn open(path: &Path) -> Result<Self> {
+        MemoryKvsEngine::open(path)
+    }
+
+    fn close(self) -> Result<()> {
+        self.snapshot()
+    }
+}
+
+impl TestEngine for LsmKvsEngine {
+    fn open(path: &Path) -> Result<Self> {
+        // a small memtable, so that the tests flush and compact tables
+        let options = LsmOptions {
+            memtable_size: 64 * 1024,
+            ..LsmOptions::default()
+        };
+        LsmKvsEngine::open_with_options(path, options)
+    }
+
+    fn counts_compactions() -> bool {
+        true
+    }
+}
+
+fn reopen<E: TestEngine>(engine: E, path: &Path) -> Result<E> {
+    engine.close()?;
+    E::open(path)
+}
+
+fn get_stored_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let engine = reopen(engine, temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn overwrite_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    let engine = reopen(engine, temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+fn get_non_existent_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    let engine = reopen(engine, temp_dir.path())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn remove_key<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    assert!(engine.remove("key1".to_owned()).is_err());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    // A removal must survive a reopen
+    let engine = reopen(engine, temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+fn batch_operations<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    let results = engine.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    assert!(results.iter().all(Result::is_ok));
+    let keys = vec!["key1".to_owned(), "missing".to_owned(), "key2".to_owned()];
+    assert_eq!(
+        engine.get_many(keys.clone())?,
+        vec![Some("value3".to_owned()), None, Some("value2".to_owned())]
+    );
+
+    let results = engine.remove_many(vec![
+        "key2".to_owned(),
+        "missing".to_owned(),
+        "key2".to_owned(),
+    ])?;
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(KvsError::KeyNotFound)));
+    assert!(matches!(results[2], Err(KvsError::KeyNotFound)));
+
+    let engine = reopen(engine, temp_dir.path())?;
+    assert_eq!(
+        engine.get_many(keys)?,
+        vec![Some("value3".to_owned()), None, None]
+    );
+
+    Ok(())
+}
+
+// Overwrites the same keys until the kvs engine has compacted several times
+// and checks that only the latest values are left.
+fn overwrite_many_times<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+    let padding = "x".repeat(100);
+
+    for iter in 0..20 {
+        for key_id in 0..1000 {
+            engine.set(format!("key{}", key_id), format!("{}{}", iter, padding))?;
+        }
+    }
+    for key_id in (0..1000).step_by(2) {
+        engine.remove(format!("key{}", key_id))?;
+    }
+    if E::counts_compactions() {
+        assert!(engine.stats()?.compaction_count > 0);
+    }
+
+    let engine = reopen(engine, temp_dir.path())?;
+    for key_id in 0..1000 {
+        let expected = if key_id % 2 == 0 {
+            None
+        } else {
+            Some(format!("19{}", padding))
+        };
+        assert_eq!(engine.get(format!("key{}", key_id))?, expected);
+    }
+    assert_eq!(engine.stats()?.key_count, 500);
+
+    Ok(())
+}
+
+fn concurrent_set<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..10 {
+                let key_id = thread_id * 10 + i;
+                engine
+                    .set(format!("key{}", key_id), format!("value{}", key_id))
+                    .unwrap();
+            }
+        }));
+    }
+    // joining drops every clone before the engine is reopened
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let engine = reopen(engine, temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+fn concurrent_get_and_set<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open(temp_dir.path())?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..50 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..30 {
+                let key_id = (i + thread_id) % 100;
+                if thread_id % 2 == 0 {
+                    engine
+                        .set(format!("other{}", key_id), thread_id.to_string())
+                        .unwrap();
+                } else {
+                    assert_eq!(
+                        engine.get(format!("key{}", key_id)).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let engine = reopen(engine, temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            engine.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(engine.get("other0".to_owned())?, Some("0".to_owned()));
+
+    Ok(())
+}
+
+macro_rules! conformance_tests {
+    ($($module:ident: $engine:ty,)*) => {
+        $(
+            mod $module {
+                use super::*;
+
+                #[test]
+                fn get_stored_value() -> Result<()> {
+                    super::get_stored_value::<$engine>()
+                }
+
+                #[test]
+                fn overwrite_value() -> Result<()> {
+                    super::overwrite_value::<$engine>()
+                }
+
+                #[test]
+                fn get_non_existent_value() -> Result<()> {
+                    super::get_non_existent_value::<$engine>()
+                }
+
+                #[test]
+                fn remove_key() -> Result<()> {
+                    super::remove_key::<$engine>()
+                }
+
+                #[test]
+                fn batch_operations() -> Result<()> {
+                    super::batch_operations::<$engine>()
+                }
+
+                #[test]
+                fn overwrite_many_times() -> Result<()> {
+                    super::overwrite_many_times::<$engine>()
+                }
+
+                #[test]
+                fn concurrent_set() -> Result<()> {
+                    super::concurrent_set::<$engine>()
+                }
+
+                #[test]
+                fn concurrent_get_and_set() -> Result<()> {
+                    super::concurrent_get_and_set::<$engine>()
+                }
+            }
+        )*
+    };
+}
+
+conformance_tests! {
+    kvs_store: KvStore,
+    sled_engine: SledKvsEngine,
+    memory_engine: MemoryKvsEngine,
+    lsm_engine: LsmKvsEngine,
+}
@@ -0,0 +1,236 @@
+// Runs the engines on an in-memory filesystem and checks that `KvStore`
+// survives the failures a `FaultyFileSystem` injects.
+
+use kvs::{
+    Durability, Fault, FaultKind, FaultyFileSystem, FileSystem, FsOp, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, LsmKvsEngine, LsmOptions, MemoryFileSystem, Result,
+};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+fn open(fs: &FaultyFileSystem) -> Result<KvStore> {
+    open_in(Arc::new(fs.clone()), Durability::Sync)
+}
+
+fn open_in(fs: Arc<dyn FileSystem>, durability: Durability) -> Result<KvStore> {
+    let options = KvStoreOptions {
+        durability,
+        fs,
+        ..KvStoreOptions::default()
+    };
+    KvStore::open_with_options("/store", options)
+}
+
+fn assert_fails_with(result: Result<()>, kind: io::ErrorKind) {
+    match result {
+        Err(KvsError::Io(e)) => assert_eq!(e.kind(), kind),
+        other => panic!("expected an I/O error, got {:?}", other.err()),
+    }
+}
+
+fn set_keys(store: &KvStore, count: usize) -> Result<()> {
+    for i in 0..count {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    Ok(())
+}
+
+fn assert_keys(store: &KvStore, count: usize) -> Result<()> {
+    for i in 0..count {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+#[test]
+fn memory_filesystem() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let store = open_in(Arc::new(fs.clone()), Durability::Flush)?;
+    set_keys(&store, 100)?;
+    store.remove("key0".to_owned())?;
+    store
+        .keyspace("team")?
+        .set("key1".to_owned(), "team1".to_owned())?;
+    store.compact()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    drop(store);
+    assert!(!Path::new("/store").exists());
+    assert!(fs.exists(Path::new("/store/current.db")));
+
+    let store = open_in(Arc::new(fs.clone()), Durability::Flush)?;
+    assert_keys(&store, 100)?;
+    assert_eq!(store.keyspaces()?, vec!["default", "team"]);
+    assert_eq!(
+        store.keyspace("team")?.get("key1".to_owned())?,
+        Some("team1".to_owned())
+    );
+
+    // and so does the scan of the log
+    let scan = KvStore::scan_log(&fs, "/store")?;
+    assert_eq!(scan.live_records().len(), 100);
+
+    // readers see the same filesystem
+    let options = KvStoreOptions {
+        fs: Arc::new(fs),
+        ..KvStoreOptions::default()
+    };
+    let reader = KvStore::open_read_only_with_options("/store", options)?;
+    assert_keys(&reader, 100)?;
+    assert_eq!(
+        reader.keyspace("team")?.get("key1".to_owned())?,
+        Some("team1".to_owned())
+    );
+    assert!(matches!(
+        reader.set("key1".to_owned(), "x".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn no_space_on_write() -> Result<()> {
+    let fs = FaultyFileSystem::new(MemoryFileSystem::new());
+    let store = open(&fs)?;
+    set_keys(&store, 10)?;
+
+    fs.inject(Fault::new(FsOp::Write, FaultKind::NoSpace).file_name("current.db"));
+    assert_fails_with(
+        store.set("full".to_owned(), "value".to_owned()),
+        io::ErrorKind::StorageFull,
+    );
+    assert_eq!(store.get("full".to_owned())?, None);
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_keys(&store, 11)?;
+    assert_eq!(store.get("full".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn short_write_is_cut_off() -> Result<()> {
+    let fs = FaultyFileSystem::new(MemoryFileSystem::new());
+    let store = open(&fs)?;
+    set_keys(&store, 10)?;
+
+    fs.inject(Fault::new(FsOp::Write, FaultKind::ShortWrite(12)).file_name("current.db"));
+    assert_fails_with(store.remove("key3".to_owned()), io::ErrorKind::StorageFull);
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    drop(store);
+
+    // the torn record would make the log unreadable from there on
+    let store = open(&fs)?;
+    assert_keys(&store, 11)?;
+
+    Ok(())
+}
+
+#[test]
+fn failed_sync_on_write() -> Result<()> {
+    let fs = FaultyFileSystem::new(MemoryFileSystem::new());
+    let store = open(&fs)?;
+
+    fs.inject(Fault::new(FsOp::Sync, FaultKind::Io).skip(3).times(2));
+    set_keys(&store, 3)?;
+    assert_fails_with(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        io::ErrorKind::Other,
+    );
+    assert_fails_with(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        io::ErrorKind::Other,
+    );
+    assert_eq!(fs.pending(), 0);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_keys(&store, 4)?;
+
+    Ok(())
+}
+
+#[test]
+fn lsm_on_memory_filesystem() -> Result<()> {
+    let fs = MemoryFileSystem::new();
+    let options = || LsmOptions {
+        memtable_size: 256,
+        table_size: 512,
+        level0_tables: 2,
+        level_size_base: 1024,
+        fs: Arc::new(fs.clone()),
+        ..LsmOptions::default()
+    };
+    let engine = LsmKvsEngine::open_with_options("/lsm", options())?;
+    for i in 0..200 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    engine
+        .keyspace("team")?
+        .set("key1".to_owned(), "team1".to_owned())?;
+    assert!(engine.stats()?.compaction_count > 0);
+    drop(engine);
+    assert!(!Path::new("/lsm").exists());
+
+    let engine = LsmKvsEngine::open_with_options("/lsm", options())?;
+    assert_eq!(
+        engine.get("key199".to_owned())?,
+        Some("value199".to_owned())
+    );
+    assert_eq!(engine.keyspaces()?, vec!["default", "team"]);
+    Ok(())
+}
+
+#[test]
+fn failed_rename_during_compaction() -> Result<()> {
+    let fs = FaultyFileSystem::new(MemoryFileSystem::new());
+    let store = open(&fs)?;
+    set_keys(&store, 100)?;
+    set_keys(&store, 100)?;
+
+    fs.inject(Fault::new(FsOp::Rename, FaultKind::Io).file_name("current.db"));
+    assert_fails_with(store.compact(), io::ErrorKind::Other);
+    assert!(!fs.exists(Path::new("/store/tmp.db")));
+    // the store keeps reading the log it had before
+    assert_keys(&store, 100)?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+
+    store.compact()?;
+    assert_keys(&store, 101)?;
+    drop(store);
+    let store = open(&fs)?;
+    assert_keys(&store, 101)?;
+
+    Ok(())
+}
+
+#[test]
+fn failed_writes_during_compaction() -> Result<()> {
+    let fs = FaultyFileSystem::new(MemoryFileSystem::new());
+    let store = open(&fs)?;
+    // large enough to be written to the new log in several chunks
+    set_keys(&store, 2000)?;
+    let compactions = store.stats()?.compaction_count;
+
+    for fault in [
+        Fault::new(FsOp::Open, FaultKind::NoSpace).file_name("tmp.db"),
+        Fault::new(FsOp::Write, FaultKind::ShortWrite(100))
+            .file_name("tmp.db")
+            .skip(1),
+        Fault::new(FsOp::Sync, FaultKind::Io).file_name("tmp.db"),
+    ] {
+        fs.inject(fault);
+        assert!(store.compact().is_err());
+        assert_eq!(fs.pending(), 0);
+        assert!(!fs.exists(Path::new("/store/tmp.db")));
+        assert_keys(&store, 2000)?;
+    }
+    assert_eq!(store.stats()?.compaction_count, compactions);
+    drop(store);
+
+    let store = open(&fs)?;
+    assert_keys(&store, 2000)?;
+
+    Ok(())
+}
@@ -0,0 +1,263 @@
+// Drives the HTTP/JSON front-end of `kvs-server` with raw HTTP/1.1 requests.
+
+mod common;
+
+use common::{start_server, Connection, Server};
+use kvs::{Client, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+use tempfile::TempDir;
+
+fn start_http_server(addr: &str, http_addr: &str, temp_dir: &TempDir) -> Server {
+    start_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--http-addr",
+            http_addr,
+            "--max-value-size",
+            "16",
+        ],
+    )
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Option<Value>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Requests over a kept-alive connection.
+impl Connection {
+    fn request(&mut self, method: &str, target: &str, body: Option<Value>) -> Result<Response> {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        write!(
+            self.stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            target,
+            body.len(),
+            body
+        )?;
+        self.response()
+    }
+
+    fn response(&mut self) -> Result<Response> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(": ").unwrap();
+            headers.push((name.to_owned(), value.to_owned()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: None,
+        };
+        let len: usize = response.header("Content-Length").unwrap().parse().unwrap();
+        let mut body = vec![0; len];
+        self.reader.read_exact(&mut body)?;
+        if len > 0 {
+            response.body = Some(serde_json::from_slice(&body)?);
+        }
+        Ok(response)
+    }
+}
+
+#[test]
+fn http_keys() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_http_server("127.0.0.1:4018", "127.0.0.1:4019", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4019")?;
+    let resp = conn.request("GET", "/health", None)?;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.body.unwrap()["status"], "ok");
+
+    let resp = conn.request("PUT", "/keys/key1", Some(json!({ "value": "value1" })))?;
+    assert_eq!(resp.status, 204);
+    assert_eq!(resp.body, None);
+    let resp = conn.request("GET", "/keys/key1", None)?;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Content-Type"), Some("application/json"));
+    assert_eq!(resp.body, Some(json!({ "key": "key1", "value": "value1" })));
+
+    // keys are percent-decoded and may contain slashes
+    let resp = conn.request("PUT", "/keys/a%2Fb%20c", Some(json!({ "value": "1" })))?;
+    assert_eq!(resp.status, 204);
+    let resp = conn.request("GET", "/keys/a/b%20c", None)?;
+    assert_eq!(resp.body, Some(json!({ "key": "a/b c", "value": "1" })));
+    for target in &["/keys/a%2", "/keys/a%zz", "/keys/a%+f", "/keys/a%-1"] {
+        assert_eq!(conn.request("GET", target, None)?.status, 400);
+    }
+
+    let resp = conn.request("GET", "/keys/missing", None)?;
+    assert_eq!(resp.status, 404);
+    assert_eq!(resp.body, Some(json!({ "error": "Key not found" })));
+    let resp = conn.request("DELETE", "/keys/key1", None)?;
+    assert_eq!(resp.status, 204);
+    let resp = conn.request("DELETE", "/keys/key1", None)?;
+    assert_eq!(resp.status, 404);
+
+    let resp = conn.request("PUT", "/keys/key1", Some(json!({ "val": "x" })))?;
+    assert_eq!(resp.status, 400);
+    let resp = conn.request(
+        "PUT",
+        "/keys/key1",
+        Some(json!({ "value": "x".repeat(17) })),
+    )?;
+    assert_eq!(resp.status, 413);
+    let resp = conn.request("POST", "/keys/key1", None)?;
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("GET, PUT, DELETE"));
+    let resp = conn.request("GET", "/nothing", None)?;
+    assert_eq!(resp.status, 404);
+
+    // curl asks before sending a body
+    write!(
+        conn.stream,
+        "PUT /keys/key2 HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 18\r\n\r\n"
+    )?;
+    let mut line = String::new();
+    conn.reader.read_line(&mut line)?;
+    conn.reader.read_line(&mut line)?;
+    assert_eq!(line, "HTTP/1.1 100 Continue\r\n\r\n");
+    conn.stream.write_all(br#"{"value":"value2"}"#)?;
+    assert_eq!(conn.response()?.status, 204);
+    let resp = conn.request("GET", "/keys/key2", None)?;
+    assert_eq!(resp.body.unwrap()["value"], "value2");
+    drop(conn);
+
+    // HTTP/1.0 connections are closed after the response
+    let mut conn = Connection::open("127.0.0.1:4019")?;
+    write!(conn.stream, "GET /keys/a/b%20c HTTP/1.0\r\n\r\n")?;
+    let resp = conn.response()?;
+    assert_eq!(resp.status, 200);
+    assert_eq!(resp.header("Connection"), Some("close"));
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    // bodies over the limit are refused before they are read
+    let mut conn = Connection::open("127.0.0.1:4019")?;
+    write!(
+        conn.stream,
+        "PUT /keys/key3 HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n"
+    )?;
+    let resp = conn.response()?;
+    assert_eq!(resp.status, 413);
+    assert_eq!(resp.header("Connection"), Some("close"));
+
+    let mut conn = Connection::open("127.0.0.1:4019")?;
+    write!(conn.stream, "NOT HTTP\r\n\r\n")?;
+    assert_eq!(conn.response()?.status, 400);
+    drop(conn);
+
+    // the native protocol serves the same engine
+    assert_eq!(
+        Client::new("127.0.0.1:4018")?.get("a/b c".to_owned())?,
+        Some("1".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn http_listing_and_batches() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_http_server("127.0.0.1:4020", "127.0.0.1:4021", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4021")?;
+    let items: Vec<Value> = (0..5)
+        .map(|i| json!({ "key": format!("user:{}", i), "value": i.to_string() }))
+        .chain(Some(json!({ "key": "other", "value": "x" })))
+        .collect();
+    let resp = conn.request("POST", "/batch/set", Some(json!({ "items": items })))?;
+    assert_eq!(resp.status, 200);
+    let results = resp.body.unwrap()["results"].as_array().unwrap().clone();
+    assert_eq!(results.len(), 6);
+    assert!(results.iter().all(|result| result["ok"] == true));
+
+    // a batch with a value over the limits is rejected whole
+    let resp = conn.request(
+        "POST",
+        "/batch/set",
+        Some(json!({ "items": [
+            { "key": "fine", "value": "x" },
+            { "key": "big", "value": "x".repeat(17) },
+        ] })),
+    )?;
+    assert_eq!(resp.status, 413);
+
+    let mut keys = Vec::new();
+    let mut target = "/keys?prefix=user%3A&limit=2".to_owned();
+    let mut pages = 0;
+    loop {
+        let resp = conn.request("GET", &target, None)?;
+        assert_eq!(resp.status, 200);
+        let body = resp.body.unwrap();
+        for item in body["items"].as_array().unwrap() {
+            assert_eq!(
+                item["key"],
+                format!("user:{}", item["value"].as_str().unwrap())
+            );
+            keys.push(item["key"].as_str().unwrap().to_owned());
+        }
+        pages += 1;
+        match body["next"].as_str() {
+            Some(next) => {
+                target = format!("/keys?prefix=user%3A&limit=2&after={}", next);
+            }
+            None => break,
+        }
+    }
+    assert_eq!(keys, ["user:0", "user:1", "user:2", "user:3", "user:4"]);
+    assert_eq!(pages, 3);
+    let resp = conn.request("GET", "/keys", None)?;
+    assert_eq!(resp.body.unwrap()["items"].as_array().unwrap().len(), 6);
+    let resp = conn.request("GET", "/keys?limit=0", None)?;
+    assert_eq!(resp.status, 400);
+
+    let resp = conn.request(
+        "POST",
+        "/batch/get",
+        Some(json!({ "keys": ["user:1", "missing", "other"] })),
+    )?;
+    assert_eq!(resp.body, Some(json!({ "values": ["1", null, "x"] })));
+
+    let resp = conn.request(
+        "POST",
+        "/batch/delete",
+        Some(json!({ "keys": ["user:1", "missing"] })),
+    )?;
+    assert_eq!(
+        resp.body,
+        Some(json!({ "results": [
+            { "key": "user:1", "ok": true },
+            { "key": "missing", "ok": false, "error": "Key not found" },
+        ] }))
+    );
+    let resp = conn.request("GET", "/batch/get", None)?;
+    assert_eq!(resp.status, 405);
+    assert_eq!(resp.header("Allow"), Some("POST"));
+    Ok(())
+}
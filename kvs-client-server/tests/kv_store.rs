@@ -1,4 +1,10 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, LimitKind, Limits, LsmKvsEngine,
+    LsmOptions, MemoryKvsEngine, MergeOperator, OsFileSystem, Result, SledKvsEngine, SledMode,
+    SledOptions, WatchEvent, DEFAULT_KEYSPACE, WATCH_BACKLOG,
+};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -210,3 +216,710 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert!(stats.live_bytes > 0);
+    assert!(stats.stale_bytes > 0);
+    assert_eq!(stats.get_hits, 1);
+    assert_eq!(stats.get_misses, 1);
+    assert_eq!(stats.hit_rate(), Some(0.5));
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.key_count, 2);
+    assert_eq!(stats.stale_bytes, 0);
+    assert_eq!(stats.compaction_count, 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut watcher = store.watch("user:".to_owned())?;
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:1".to_owned())?;
+
+    assert_eq!(
+        watcher.next(),
+        Some(WatchEvent::Set {
+            key: "user:1".to_owned(),
+            value: "alice".to_owned()
+        })
+    );
+    assert_eq!(
+        watcher.next(),
+        Some(WatchEvent::Remove {
+            key: "user:1".to_owned()
+        })
+    );
+
+    // The stream ends once the store is gone
+    drop(store);
+    assert_eq!(watcher.next(), None);
+
+    Ok(())
+}
+
+#[test]
+fn merge_operators() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -2)?, 3);
+    store.merge("log".to_owned(), MergeOperator::Append, "a".to_owned())?;
+    store.merge("log".to_owned(), MergeOperator::Append, "b".to_owned())?;
+    store.set("high".to_owned(), "7".to_owned())?;
+    store.merge("high".to_owned(), MergeOperator::Max, "3".to_owned())?;
+    store.merge("high".to_owned(), MergeOperator::Max, "9".to_owned())?;
+    store.set("doc".to_owned(), r#"{"a":1,"b":2}"#.to_owned())?;
+    store.merge(
+        "doc".to_owned(),
+        MergeOperator::JsonMerge,
+        r#"{"b":null,"c":3}"#.to_owned(),
+    )?;
+
+    // Operands that cannot be folded are rejected up front
+    assert!(store
+        .merge("log".to_owned(), MergeOperator::Add, "1".to_owned())
+        .is_err());
+    assert!(store.increment("counter".to_owned(), i64::MAX).is_err());
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("counter".to_owned())?, Some("3".to_owned()));
+        assert_eq!(store.get("log".to_owned())?, Some("ab".to_owned()));
+        assert_eq!(store.get("high".to_owned())?, Some("9".to_owned()));
+        assert_eq!(
+            store.get("doc".to_owned())?,
+            Some(r#"{"a":1,"c":3}"#.to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+#[test]
+fn custom_merge_operators() -> Result<()> {
+    fn min(existing: Option<&str>, operand: &str) -> Result<String> {
+        let parse = |value: &str| {
+            value
+                .parse::<i64>()
+                .map_err(|_| KvsError::InvalidMerge(format!("{:?} is not an integer", value)))
+        };
+        let operand = parse(operand)?;
+        Ok(match existing {
+            Some(existing) => parse(existing)?.min(operand),
+            None => operand,
+        }
+        .to_string())
+    }
+    let min_op = || MergeOperator::Custom("min".to_owned());
+
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        let min_op = || MergeOperator::Custom("min".to_owned());
+        assert!(matches!(
+            engine.merge("low".to_owned(), min_op(), "5".to_owned()),
+            Err(KvsError::InvalidMerge(_))
+        ));
+        engine.merge_operators().register("min", min);
+        // keyspaces share the operators of the store
+        let team = engine.keyspace("team")?;
+        assert_eq!(team.merge("low".to_owned(), min_op(), "5".to_owned())?, "5");
+        assert_eq!(team.merge("low".to_owned(), min_op(), "8".to_owned())?, "5");
+        assert_eq!(team.merge("low".to_owned(), min_op(), "2".to_owned())?, "2");
+        assert!(matches!(
+            team.merge("low".to_owned(), min_op(), "x".to_owned()),
+            Err(KvsError::InvalidMerge(_))
+        ));
+        assert_eq!(team.get("low".to_owned())?, Some("2".to_owned()));
+        Ok(())
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&KvStore::open(temp_dir.path())?)?;
+    check(&MemoryKvsEngine::new())?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&SledKvsEngine::open(sled_dir.path())?)?;
+    let lsm_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(&LsmKvsEngine::open(lsm_dir.path())?)?;
+
+    // `KvStore` folds merges when a key is read, with the operators
+    // registered at that time
+    let store = KvStore::open(temp_dir.path())?.keyspace("team")?;
+    assert!(matches!(
+        store.get("low".to_owned()),
+        Err(KvsError::InvalidMerge(_))
+    ));
+    // compaction copies the merges it cannot fold
+    store.set("other".to_owned(), "1".to_owned())?;
+    store.compact()?;
+    let team_dir = temp_dir.path().join("keyspaces").join("team");
+    let (values, unfolded) = KvStore::scan_log(&OsFileSystem, &team_dir)?.live_values();
+    assert_eq!(values.get("other"), Some(&"1".to_owned()));
+    assert_eq!(unfolded, ["low"]);
+    store.merge_operators().register("min", min);
+    assert_eq!(store.get("low".to_owned())?, Some("2".to_owned()));
+    store.merge("low".to_owned(), min_op(), "1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?.keyspace("team")?;
+    assert_eq!(store.get("low".to_owned())?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..10 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                store.increment("counter".to_owned(), 1).unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("1000".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("1000".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let team_a = store.keyspace("team-a")?;
+    let team_b = store.keyspace("team_b")?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    team_a.set("key1".to_owned(), "a".to_owned())?;
+    team_b.set("key2".to_owned(), "b".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(team_a.get("key1".to_owned())?, Some("a".to_owned()));
+    assert_eq!(team_b.get("key1".to_owned())?, None);
+    assert!(team_b.remove("key1".to_owned()).is_err());
+    assert_eq!(team_a.stats()?.key_count, 1);
+    assert_eq!(team_b.stats()?.get_misses, 1);
+
+    // Handles on the same keyspace share their state
+    assert_eq!(store.keyspace("team-a")?.stats()?.get_hits, 1);
+    assert_eq!(
+        team_a.keyspace(DEFAULT_KEYSPACE)?.get("key1".to_owned())?,
+        Some("default".to_owned())
+    );
+
+    assert!(store.keyspace("").is_err());
+    assert!(store.keyspace("../escape").is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    drop(team_a);
+    drop(team_b);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(
+        store.keyspace("team-a")?.get("key1".to_owned())?,
+        Some("a".to_owned())
+    );
+    assert_eq!(
+        store.keyspace("team_b")?.get("key2".to_owned())?,
+        Some("b".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sled_keyspaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let team_a = engine.keyspace("team-a")?;
+
+    engine.set("key1".to_owned(), "default".to_owned())?;
+    team_a.set("key1".to_owned(), "a".to_owned())?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(team_a.get("key1".to_owned())?, Some("a".to_owned()));
+    assert_eq!(engine.stats()?.key_count, 1);
+    assert_eq!(team_a.stats()?.key_count, 1);
+    assert!(engine.keyspace("no/slash").is_err());
+    // sled's own tree is not a keyspace of its own
+    assert!(matches!(
+        engine.keyspace("__sled__default"),
+        Err(KvsError::InvalidKeyspace(_))
+    ));
+    assert_eq!(engine.keyspaces()?, vec![DEFAULT_KEYSPACE, "team-a"]);
+
+    // Open from disk again and check persistent data
+    drop(engine);
+    drop(team_a);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(
+        engine.keyspace("team-a")?.get("key1".to_owned())?,
+        Some("a".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for i in 0..10 {
+        store.set(format!("user:{}", i), format!("value{}", i))?;
+    }
+    store.set("users".to_owned(), "other".to_owned())?;
+    store.set("a".to_owned(), "before".to_owned())?;
+    store.remove("user:5".to_owned())?;
+
+    let page = store.scan("user:", None, 4)?;
+    let keys: Vec<&str> = page.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["user:0", "user:1", "user:2", "user:3"]);
+    assert_eq!(page[0].1, "value0");
+
+    let page = store.scan("user:", Some("user:3"), 4)?;
+    let keys: Vec<&str> = page.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["user:4", "user:6", "user:7", "user:8"]);
+
+    let page = store.scan("user:", Some("user:8"), 4)?;
+    assert_eq!(page.len(), 1);
+    assert_eq!(store.scan("", None, 100)?.len(), 11);
+
+    Ok(())
+}
+
+#[test]
+fn slow_watchers_are_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    let slow = engine.watch("key".to_owned())?;
+    let mut reading = engine.watch("key".to_owned())?;
+
+    let pairs = (0..WATCH_BACKLOG)
+        .map(|i| (format!("key{}", i), i.to_string()))
+        .collect();
+    engine.set_many(pairs)?;
+    // sled does not keep the order of the writes of a batch
+    assert!(reading.next().unwrap().key().starts_with("key"));
+    engine.set("key-last".to_owned(), "x".to_owned())?;
+
+    // the slow watcher reads what it was sent before falling behind, then
+    // ends, while the other one keeps up
+    assert_eq!(slow.count(), WATCH_BACKLOG);
+    assert_eq!(
+        reading.nth(WATCH_BACKLOG - 1),
+        Some(WatchEvent::Set {
+            key: "key-last".to_owned(),
+            value: "x".to_owned()
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    let watcher = engine.watch("key".to_owned())?;
+    let team_a = engine.keyspace("team-a")?;
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    team_a.set("key1".to_owned(), "a".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(team_a.get("key1".to_owned())?, Some("a".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.increment("key2".to_owned(), 5)?, 5);
+    engine.remove("key1".to_owned())?;
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    let events: Vec<WatchEvent> = watcher.take(3).collect();
+    assert_eq!(events[0].key(), "key1");
+    assert_eq!(
+        events[1],
+        WatchEvent::Set {
+            key: "key2".to_owned(),
+            value: "5".to_owned()
+        }
+    );
+    assert_eq!(events[2].key(), "key1");
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.key_count, 1);
+    assert_eq!((stats.get_hits, stats.get_misses), (1, 1));
+    assert_eq!(engine.keyspaces()?, vec![DEFAULT_KEYSPACE, "team-a"]);
+    assert_eq!(
+        engine.scan("", None, 10)?,
+        vec![("key2".to_owned(), "5".to_owned())]
+    );
+    assert!(engine.snapshot().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn memory_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = MemoryKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine
+        .keyspace("team-a")?
+        .set("key1".to_owned(), "a".to_owned())?;
+    engine.snapshot()?;
+    engine.set("key2".to_owned(), "unsaved".to_owned())?;
+
+    // Only data written before the snapshot survives
+    drop(engine);
+    let engine = MemoryKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(
+        engine.keyspace("team-a")?.get("key1".to_owned())?,
+        Some("a".to_owned())
+    );
+
+    Ok(())
+}
+
+// Reads must stay correct while the log grows past its mapped prefix and
+// after compaction replaces the mapped file.
+#[test]
+fn read_across_remaps() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let padding = "x".repeat(500);
+
+    for round in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", round, padding))?;
+            // read back old and fresh records as the log grows
+            assert_eq!(
+                store.get(format!("key{}", key_id / 2))?,
+                Some(format!("{}{}", round, padding))
+            );
+        }
+    }
+    assert!(store.stats()?.compaction_count > 0);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("2{}", padding))
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn durability_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_durability(temp_dir.path(), Durability::Sync)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store
+        .keyspace("team")?
+        .set("key1".to_owned(), "a".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        cache_capacity: 1024 * 1024,
+        flush_every_ms: None,
+        mode: SledMode::HighThroughput,
+        durability: Durability::Background,
+        ..SledOptions::default()
+    };
+    let engine = SledKvsEngine::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(engine.increment("counter".to_owned(), 2)?, 2);
+    assert_eq!(engine.get("key7".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(engine.stats()?.key_count, 101);
+
+    // sled needs the compression feature for this option
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        use_compression: true,
+        ..SledOptions::default()
+    };
+    assert_eq!(
+        SledKvsEngine::open_with_options(temp_dir.path(), options).is_ok(),
+        cfg!(feature = "compression")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // small sizes so that a few thousand keys fill several levels
+    let options = LsmOptions {
+        memtable_size: 4 * 1024,
+        table_size: 8 * 1024,
+        level0_tables: 2,
+        level_size_base: 32 * 1024,
+        ..LsmOptions::default()
+    };
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    for iter in 0..3 {
+        for i in 0..2000 {
+            engine.set(format!("key{:04}", i), format!("value{}-{}", i, iter))?;
+        }
+    }
+    for i in (0..2000).step_by(3) {
+        engine.remove(format!("key{:04}", i))?;
+    }
+
+    let stats = engine.stats()?;
+    assert!(stats.segment_count > 1);
+    assert!(stats.compaction_count > 0);
+    assert_eq!(stats.key_count, 1333);
+    assert_eq!(
+        engine.get("key0001".to_owned())?,
+        Some("value1-2".to_owned())
+    );
+    assert_eq!(engine.get("key0003".to_owned())?, None);
+    assert_eq!(
+        engine.scan("key00", Some("key0004"), 3)?,
+        vec![
+            ("key0005".to_owned(), "value5-2".to_owned()),
+            ("key0007".to_owned(), "value7-2".to_owned()),
+            ("key0008".to_owned(), "value8-2".to_owned()),
+        ]
+    );
+    drop(engine);
+
+    // a torn record at the end of the write-ahead log is dropped
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options.clone())?;
+    engine.set("torn".to_owned(), "value".to_owned())?;
+    engine.set("last".to_owned(), "value".to_owned())?;
+    drop(engine);
+    let wal = temp_dir.path().join("wal.log");
+    let len = fs::metadata(&wal)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&wal)?
+        .set_len(len - 2)?;
+
+    let engine = LsmKvsEngine::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(engine.get("torn".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get("last".to_owned())?, None);
+    assert_eq!(
+        engine.get("key1999".to_owned())?,
+        Some("value1999-2".to_owned())
+    );
+    assert_eq!(engine.stats()?.key_count, 1334);
+
+    Ok(())
+}
+
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store
+        .keyspace("team")?
+        .set("key1".to_owned(), "team1".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        reader.keyspace("team")?.get("key1".to_owned())?,
+        Some("team1".to_owned())
+    );
+    assert!(matches!(
+        reader.set("key1".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.increment("counter".to_owned(), 1),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(reader.compact(), Err(KvsError::ReadOnly)));
+
+    // appended records are only seen after a refresh
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(reader.get("key3".to_owned())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    // a refresh after the writer compacted reloads the new log
+    store.compact()?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(reader.stats()?.key_count, 3);
+
+    // a record the writer has only partly appended is picked up once whole
+    drop(store);
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("current.db"))?;
+    log.write_all(br#"{"cmd":"Set","key":"key5","#)?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key5".to_owned())?, None);
+    log.write_all(br#""value":"value5"}"#)?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn size_limits() -> Result<()> {
+    let limits = Limits {
+        max_key_size: 8,
+        max_value_size: 16,
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            limits,
+            ..KvStoreOptions::default()
+        },
+    )?;
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open_with_options(
+        sled_dir.path(),
+        SledOptions {
+            limits,
+            ..SledOptions::default()
+        },
+    )?;
+    let lsm_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lsm = LsmKvsEngine::open_with_options(
+        lsm_dir.path(),
+        LsmOptions {
+            limits,
+            ..LsmOptions::default()
+        },
+    )?;
+    let memory = MemoryKvsEngine::new().with_limits(limits);
+
+    fn check<E: KvsEngine>(engine: &E) -> Result<()> {
+        assert!(matches!(
+            engine.set("key-too-long".to_owned(), "value".to_owned()),
+            Err(KvsError::TooLarge {
+                what: LimitKind::Key,
+                size: 12,
+                limit: 8,
+            })
+        ));
+        assert!(matches!(
+            engine.set("key".to_owned(), "a value too long".repeat(2)),
+            Err(KvsError::TooLarge {
+                what: LimitKind::Value,
+                ..
+            })
+        ));
+        engine.set("key".to_owned(), "0123456789".to_owned())?;
+        // the merged value would go over the limit
+        assert!(matches!(
+            engine.merge(
+                "key".to_owned(),
+                MergeOperator::Append,
+                "0123456789".to_owned()
+            ),
+            Err(KvsError::TooLarge {
+                what: LimitKind::Value,
+                size: 20,
+                limit: 16,
+            })
+        ));
+        assert_eq!(engine.get("key".to_owned())?, Some("0123456789".to_owned()));
+        assert_eq!(engine.get("key-too-long".to_owned())?, None);
+        Ok(())
+    }
+    check(&store)?;
+    check(&sled)?;
+    check(&lsm)?;
+    check(&memory)?;
+    // keyspaces keep the limits of the handle they were opened from
+    check(&memory.keyspace("team")?)?;
+
+    Ok(())
+}
+
+#[test]
+fn checksums_are_verified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the record still decodes, but not to what was written
+    let log_path = temp_dir.path().join("current.db");
+    let log = fs::read_to_string(&log_path)?;
+    fs::write(&log_path, log.replacen("\"value2\"", "\"valueX\"", 1))?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.get("key2".to_owned()).is_err());
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
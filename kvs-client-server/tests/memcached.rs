@@ -0,0 +1,183 @@
+// Drives the memcached front-end of `kvs-server` with raw protocol lines.
+
+mod common;
+
+use common::{start_server, Connection, Server};
+use kvs::{Client, Result};
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_memcached_server(addr: &str, memcached_addr: &str, temp_dir: &TempDir) -> Server {
+    start_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--memcached-addr",
+            memcached_addr,
+            "--max-value-size",
+            "64",
+        ],
+    )
+}
+
+impl Connection {
+    // Sends a request and checks the raw bytes of the reply.
+    fn check(&mut self, request: &str, expected: &str) -> Result<()> {
+        self.stream.write_all(request.as_bytes())?;
+        self.expect(expected)
+    }
+
+    // Returns the cas unique of an item from a gets reply.
+    fn cas_unique(&mut self, key: &str) -> Result<String> {
+        self.stream
+            .write_all(format!("gets {}\r\n", key).as_bytes())?;
+        let header = self.line()?;
+        let fields: Vec<&str> = header.split(' ').collect();
+        assert_eq!(fields[..2], ["VALUE", key]);
+        let cas_unique = fields[4].to_owned();
+        self.line()?;
+        assert_eq!(self.line()?, "END");
+        Ok(cas_unique)
+    }
+}
+
+#[test]
+fn memcached_commands() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_memcached_server("127.0.0.1:4016", "127.0.0.1:4017", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4017")?;
+    conn.check("set key1 0 0 6\r\nvalue1\r\n", "STORED\r\n")?;
+    conn.check("get key1\r\n", "VALUE key1 0 6\r\nvalue1\r\nEND\r\n")?;
+    conn.check("get missing\r\n", "END\r\n")?;
+    conn.check("set key2 42 0 0\r\n\r\n", "STORED\r\n")?;
+    conn.check(
+        "get key1 missing key2\r\n",
+        "VALUE key1 0 6\r\nvalue1\r\nVALUE key2 42 0\r\n\r\nEND\r\n",
+    )?;
+
+    conn.check("add key1 0 0 1\r\na\r\n", "NOT_STORED\r\n")?;
+    conn.check("add key3 7 0 1\r\na\r\n", "STORED\r\n")?;
+    conn.check("replace missing 0 0 1\r\nb\r\n", "NOT_STORED\r\n")?;
+    conn.check("replace key3 0 0 1\r\nb\r\n", "STORED\r\n")?;
+    conn.check("get key3\r\n", "VALUE key3 0 1\r\nb\r\nEND\r\n")?;
+
+    // cas succeeds only while the item is unchanged
+    let cas_unique = conn.cas_unique("key1")?;
+    conn.check(
+        &format!("cas key1 0 0 2 {}\r\nv2\r\n", cas_unique),
+        "STORED\r\n",
+    )?;
+    conn.check(
+        &format!("cas key1 0 0 2 {}\r\nv3\r\n", cas_unique),
+        "EXISTS\r\n",
+    )?;
+    conn.check("cas missing 0 0 2 1\r\nv3\r\n", "NOT_FOUND\r\n")?;
+    assert_ne!(conn.cas_unique("key1")?, cas_unique);
+
+    conn.check("set counter 0 0 2\r\n10\r\n", "STORED\r\n")?;
+    conn.check("incr counter 5\r\n", "15\r\n")?;
+    conn.check("decr counter 20\r\n", "0\r\n")?;
+    conn.check("incr missing 1\r\n", "NOT_FOUND\r\n")?;
+    conn.check(
+        "incr key1 1\r\n",
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+    )?;
+    conn.check(
+        "incr counter x\r\n",
+        "CLIENT_ERROR invalid numeric delta argument\r\n",
+    )?;
+
+    conn.check("delete key3\r\n", "DELETED\r\n")?;
+    conn.check("delete key3\r\n", "NOT_FOUND\r\n")?;
+
+    // expired items are gone, whether they expired before or after the set
+    conn.check("set key4 0 -1 1\r\na\r\n", "STORED\r\n")?;
+    conn.check("set key5 0 1000000000 1\r\na\r\n", "STORED\r\n")?;
+    conn.check("set key6 3 1 1\r\na\r\n", "STORED\r\n")?;
+    conn.check("get key6\r\n", "VALUE key6 3 1\r\na\r\nEND\r\n")?;
+    thread::sleep(Duration::from_secs(2));
+    conn.check("get key4 key5 key6\r\n", "END\r\n")?;
+
+    // noreply commands are answered by the next command alone
+    conn.check(
+        "set quiet 0 0 1 noreply\r\nq\r\ndelete key2 noreply\r\nget quiet key2\r\n",
+        "VALUE quiet 0 1\r\nq\r\nEND\r\n",
+    )?;
+
+    conn.check(
+        &format!("set big 0 0 65\r\n{}\r\n", "x".repeat(65)),
+        "SERVER_ERROR object too large for cache\r\n",
+    )?;
+    conn.check(
+        "set key1 0 0 2\r\nabcd\r\n",
+        "CLIENT_ERROR bad data chunk\r\n",
+    )?;
+    conn.check(
+        "set key1 0 zero 2\r\n",
+        "CLIENT_ERROR bad command line format\r\n",
+    )?;
+    conn.check("get\r\n", "CLIENT_ERROR bad command line format\r\n")?;
+    conn.check("flush\r\n", "ERROR\r\n")?;
+    conn.check(
+        "version\r\n",
+        &format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")),
+    )?;
+    conn.stream.write_all(b"quit\r\n")?;
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    // values are shared with the native protocol, metadata is not
+    let mut client = Client::new("127.0.0.1:4016")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(client.get("key6".to_owned())?, None);
+    client.set("key7".to_owned(), "native".to_owned())?;
+    drop(client);
+    let mut conn = Connection::open("127.0.0.1:4017")?;
+    conn.check("get key7\r\n", "VALUE key7 0 6\r\nnative\r\nEND\r\n")?;
+    Ok(())
+}
+
+#[test]
+fn memcached_metadata_follows_native_writes() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_memcached_server("127.0.0.1:4029", "127.0.0.1:4030", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4030")?;
+    conn.check("set key1 5 1 3\r\nold\r\n", "STORED\r\n")?;
+    let cas_unique = conn.cas_unique("key1")?;
+    drop(conn);
+
+    // a value rewritten through the native protocol drops the flags, expiry
+    // time and cas unique memcached gave it
+    let mut client = Client::new("127.0.0.1:4029")?;
+    client.set("key1".to_owned(), "new".to_owned())?;
+    drop(client);
+    let mut conn = Connection::open("127.0.0.1:4030")?;
+    conn.check(
+        &format!("cas key1 0 0 1 {}\r\nx\r\n", cas_unique),
+        "EXISTS\r\n",
+    )?;
+    conn.check("get key1\r\n", "VALUE key1 0 3\r\nnew\r\nEND\r\n")?;
+    thread::sleep(Duration::from_secs(2));
+    conn.check("get key1\r\n", "VALUE key1 0 3\r\nnew\r\nEND\r\n")?;
+
+    // cas uniques only grow, even for an item deleted and added again
+    let native_cas_unique = conn.cas_unique("key1")?;
+    assert!(native_cas_unique.parse::<u64>().unwrap() > cas_unique.parse().unwrap());
+    conn.check("delete key1\r\n", "DELETED\r\n")?;
+    conn.check("add key1 0 0 3\r\nnew\r\n", "STORED\r\n")?;
+    let added_cas_unique = conn.cas_unique("key1")?;
+    assert!(added_cas_unique.parse::<u64>().unwrap() > native_cas_unique.parse().unwrap());
+    // incr keeps the flags
+    conn.check("set key2 3 0 1\r\n1\r\n", "STORED\r\n")?;
+    conn.check("incr key2 1\r\n", "2\r\n")?;
+    conn.check("get key2\r\n", "VALUE key2 3 1\r\n2\r\nEND\r\n")?;
+    Ok(())
+}
@@ -0,0 +1,240 @@
+// Drives the RESP front-end of `kvs-server` with raw protocol frames.
+
+mod common;
+
+use common::{start_server, Connection, Server};
+use kvs::{Client, Result};
+use std::io::{Read, Write};
+use tempfile::TempDir;
+
+fn start_resp_server(addr: &str, resp_addr: &str, temp_dir: &TempDir) -> Server {
+    start_server(
+        temp_dir.path(),
+        &[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--resp-addr",
+            resp_addr,
+            "--max-key-size",
+            "16",
+            "--max-value-size",
+            "64",
+        ],
+    )
+}
+
+// Encodes a command the way client libraries send it.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    buf
+}
+
+impl Connection {
+    // Sends a command and checks the raw bytes of the reply.
+    fn check(&mut self, args: &[&str], expected: &str) -> Result<()> {
+        self.stream.write_all(&command(args))?;
+        self.expect(expected)
+    }
+
+    // Reads a bulk string reply.
+    fn bulk(&mut self) -> Result<String> {
+        let len: usize = self.line()?.trim_start_matches('$').parse().unwrap();
+        let mut value = vec![0; len + 2];
+        self.reader.read_exact(&mut value)?;
+        value.truncate(len);
+        Ok(String::from_utf8(value)?)
+    }
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server("127.0.0.1:4012", "127.0.0.1:4013", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4013")?;
+    conn.check(&["PING"], "+PONG\r\n")?;
+    conn.check(&["ping", "hello"], "$5\r\nhello\r\n")?;
+    conn.check(&["SET", "key1", "value1"], "+OK\r\n")?;
+    conn.check(&["GET", "key1"], "$6\r\nvalue1\r\n")?;
+    conn.check(&["GET", "missing"], "$-1\r\n")?;
+    conn.check(&["MSET", "key2", "value2", "key3", ""], "+OK\r\n")?;
+    conn.check(
+        &["MGET", "key1", "missing", "key3"],
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$0\r\n\r\n",
+    )?;
+    conn.check(&["EXISTS", "key1", "key1", "missing"], ":2\r\n")?;
+    conn.check(&["DEL", "key2", "key2", "missing"], ":1\r\n")?;
+    conn.check(&["EXISTS", "key2"], ":0\r\n")?;
+
+    conn.check(&["FOO"], "-ERR unknown command 'foo'\r\n")?;
+    conn.check(
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    )?;
+    conn.check(
+        &["SET", "key1", "value1", "EX", "10"],
+        "-ERR syntax error\r\n",
+    )?;
+    conn.check(
+        &["GET", "a-key-over-16-bytes"],
+        "-ERR key of 19 bytes is over the limit of 16 bytes\r\n",
+    )?;
+
+    // pipelined and inline commands
+    conn.stream
+        .write_all(b"SET inline value\r\nGET inline\r\n\r\n")?;
+    conn.stream.write_all(&command(&["GET", "key1"]))?;
+    conn.expect("+OK\r\n$5\r\nvalue\r\n$6\r\nvalue1\r\n")?;
+
+    conn.stream.write_all(&command(&["INFO", "keyspace"]))?;
+    assert_eq!(
+        conn.bulk()?,
+        "# Keyspace\r\ndb0:keys=3,expires=0,avg_ttl=0\r\n"
+    );
+    conn.check(&["QUIT"], "+OK\r\n")?;
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+
+    // RESP3 is chosen with HELLO
+    let mut conn = Connection::open("127.0.0.1:4013")?;
+    conn.check(&["HELLO", "4"], "-NOPROTO unsupported protocol version\r\n")?;
+    let version = env!("CARGO_PKG_VERSION");
+    conn.check(
+        &["HELLO", "3"],
+        &format!(
+            "%6\r\n$6\r\nserver\r\n$3\r\nkvs\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            version.len(),
+            version
+        ),
+    )?;
+    conn.check(&["GET", "missing"], "_\r\n")?;
+    conn.check(&["MGET", "missing"], "*1\r\n_\r\n")?;
+
+    // a malformed frame closes the connection
+    conn.stream.write_all(b"*1\r\nGET\r\n")?;
+    assert_eq!(conn.line()?, "-ERR Protocol error: expected '$', got 'G'");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    drop(conn);
+
+    // the native protocol serves the same engine
+    assert_eq!(
+        Client::new("127.0.0.1:4012")?.get("inline".to_owned())?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn resp_command_limits() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server("127.0.0.1:4031", "127.0.0.1:4032", &temp_dir);
+
+    // arguments may be as long as a value, and are refused before they are
+    // read if longer
+    let mut conn = Connection::open("127.0.0.1:4032")?;
+    conn.check(&["SET", "key", &"x".repeat(64)], "+OK\r\n")?;
+    conn.stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$65\r\n")?;
+    assert_eq!(conn.line()?, "-ERR Protocol error: invalid bulk length");
+
+    let mut conn = Connection::open("127.0.0.1:4032")?;
+    conn.stream.write_all(b"*2000000\r\n")?;
+    assert_eq!(
+        conn.line()?,
+        "-ERR Protocol error: invalid multibulk length"
+    );
+
+    // the arguments of a command may add up to the size of a request, that
+    // of a key and a value sent to the native protocol
+    let max_request_size = (16 + 64) * 6 + 4096;
+    let arg = "x".repeat(50);
+    let mut conn = Connection::open("127.0.0.1:4032")?;
+    let mut request = b"*1000\r\n".to_vec();
+    for _ in 0..max_request_size / arg.len() {
+        request.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+    conn.stream.write_all(&request)?;
+    assert_eq!(conn.line()?, "-ERR Protocol error: too big command");
+    let mut rest = Vec::new();
+    conn.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = start_resp_server("127.0.0.1:4014", "127.0.0.1:4015", &temp_dir);
+
+    let mut conn = Connection::open("127.0.0.1:4015")?;
+    let mut mset = vec!["MSET"];
+    let keys: Vec<String> = (0..25).map(|i| format!("user:{:02}", i)).collect();
+    for key in &keys {
+        mset.push(key);
+        mset.push("value");
+    }
+    mset.extend_from_slice(&["users", "value", "other", "value"]);
+    conn.check(&mset, "+OK\r\n")?;
+
+    let mut found = Vec::new();
+    let mut cursors = Vec::new();
+    let mut cursor = "0".to_owned();
+    let mut pages = 0;
+    loop {
+        conn.stream.write_all(&command(&[
+            "SCAN", &cursor, "MATCH", "user:*", "COUNT", "10",
+        ]))?;
+        assert_eq!(conn.line()?, "*2");
+        cursor = conn.bulk()?;
+        let count: usize = conn.line()?.trim_start_matches('*').parse().unwrap();
+        for _ in 0..count {
+            found.push(conn.bulk()?);
+        }
+        pages += 1;
+        if cursor == "0" {
+            break;
+        }
+        cursors.push(cursor.clone());
+    }
+    assert_eq!(found, keys);
+    assert_eq!(pages, 3);
+
+    // a cursor can be resumed again
+    conn.stream.write_all(&command(&[
+        "SCAN",
+        &cursors[0],
+        "MATCH",
+        "user:*",
+        "COUNT",
+        "10",
+    ]))?;
+    assert_eq!(conn.line()?, "*2");
+    assert_ne!(conn.bulk()?, "0");
+    assert_eq!(conn.line()?, "*10");
+    for key in &keys[10..20] {
+        assert_eq!(&conn.bulk()?, key);
+    }
+
+    conn.check(
+        &["SCAN", "0", "MATCH", "user:1[0-2]", "COUNT", "100"],
+        "*2\r\n$1\r\n0\r\n*3\r\n$7\r\nuser:10\r\n$7\r\nuser:11\r\n$7\r\nuser:12\r\n",
+    )?;
+    conn.check(
+        &["SCAN", "0", "MATCH", "*s", "COUNT", "100"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$5\r\nusers\r\n",
+    )?;
+    conn.check(&["SCAN", "12345"], "-ERR invalid cursor\r\n")?;
+    drop(conn);
+    Ok(())
+}
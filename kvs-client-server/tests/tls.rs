@@ -0,0 +1,292 @@
+// Serves the native protocol over TLS with certificates made for each test.
+
+mod common;
+
+use assert_cmd::prelude::*;
+use common::{start_server, Server};
+use kvs::{Client, ClientTlsOptions, Result, WatchEvent};
+use predicates::str::contains;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{version, ClientConfig, ClientConnection, RootCertStore};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use tempfile::TempDir;
+
+fn start_tls_server(addr: &str, tls_args: &[&Path], temp_dir: &TempDir) -> Server {
+    let mut args = vec![
+        "--engine".into(),
+        "kvs".into(),
+        "--addr".into(),
+        addr.into(),
+    ];
+    for (flag, path) in ["--tls-cert", "--tls-key", "--tls-client-ca"]
+        .iter()
+        .zip(tls_args)
+    {
+        args.push(flag.into());
+        args.push(path.as_os_str().to_owned());
+    }
+    start_server::<OsString>(temp_dir.path(), &args)
+}
+
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+impl Ca {
+    fn new(name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        let cert = params.self_signed(&key).unwrap();
+        Ca { cert, key }
+    }
+
+    // Writes the CA certificate to `dir`, returning its path.
+    fn write(&self, dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(format!("{}.pem", name));
+        fs::write(&path, self.cert.pem()).unwrap();
+        path
+    }
+
+    // Issues a certificate for `names` and writes it with its key to `dir`,
+    // returning their paths.
+    fn issue(
+        &self,
+        dir: &Path,
+        name: &str,
+        names: &[&str],
+        usage: ExtendedKeyUsagePurpose,
+    ) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut params = CertificateParams::new(names).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}.key", name));
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+#[test]
+fn tls_server() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test CA");
+    let ca_path = ca.write(certs.path(), "ca");
+    let (cert, key) = ca.issue(
+        certs.path(),
+        "server",
+        &["localhost", "127.0.0.1"],
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let _server = start_tls_server("127.0.0.1:4022", &[&cert, &key], &temp_dir);
+    let tls = ClientTlsOptions::new(&ca_path);
+
+    let mut client = Client::with_tls("127.0.0.1:4022", None, &tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    // pipelines read and write the session at once
+    let mut pipeline = client.pipeline();
+    for i in 0..200 {
+        pipeline.set(format!("bulk{}", i), "x".repeat(1000));
+    }
+    pipeline.get("bulk7".to_owned());
+    let replies = pipeline.execute()?;
+    assert_eq!(replies.len(), 201);
+    assert!(replies.into_iter().all(|reply| reply.is_ok()));
+    drop(client);
+
+    // events are written by another thread of the server
+    let watch = Client::with_tls("127.0.0.1:4022", None, &tls)?.watch("w".to_owned())?;
+    let mut client = Client::with_tls("127.0.0.1:4022", None, &tls)?;
+    client.set("w1".to_owned(), "1".to_owned())?;
+    drop(client);
+    assert_eq!(
+        watch.take(1).collect::<Result<Vec<_>>>()?,
+        [WatchEvent::Set {
+            key: "w1".to_owned(),
+            value: "1".to_owned()
+        }]
+    );
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4022", "--tls-ca"])
+        .arg(&ca_path)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // the certificate must be for the name connected to
+    let wrong_name = ClientTlsOptions {
+        server_name: Some("kvs.example.com".to_owned()),
+        ..tls.clone()
+    };
+    assert!(Client::with_tls("127.0.0.1:4022", None, &wrong_name).is_err());
+    // and signed by a trusted CA
+    let other_ca = Ca::new("other CA").write(certs.path(), "other-ca");
+    assert!(Client::with_tls("127.0.0.1:4022", None, &ClientTlsOptions::new(other_ca)).is_err());
+    // plaintext clients are refused
+    assert!(Client::new("127.0.0.1:4022").is_err());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4022"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn tls_client_certificates() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test CA");
+    let ca_path = ca.write(certs.path(), "ca");
+    let (cert, key) = ca.issue(
+        certs.path(),
+        "server",
+        &["localhost"],
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let _server = start_tls_server("127.0.0.1:4023", &[&cert, &key, &ca_path], &temp_dir);
+    let client_cert = ca.issue(
+        certs.path(),
+        "client",
+        &[],
+        ExtendedKeyUsagePurpose::ClientAuth,
+    );
+
+    let tls = ClientTlsOptions {
+        cert: Some(client_cert.clone()),
+        server_name: Some("localhost".to_owned()),
+        ..ClientTlsOptions::new(&ca_path)
+    };
+    let mut client = Client::with_tls("127.0.0.1:4023", None, &tls)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // clients without a certificate of the CA are refused
+    let anonymous = ClientTlsOptions {
+        cert: None,
+        ..tls.clone()
+    };
+    let refused = Client::with_tls("127.0.0.1:4023", None, &anonymous)
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(refused.is_err());
+    let other_ca = Ca::new("other CA");
+    let other_cert = other_ca.issue(
+        certs.path(),
+        "other-client",
+        &[],
+        ExtendedKeyUsagePurpose::ClientAuth,
+    );
+    let impostor = ClientTlsOptions {
+        cert: Some(other_cert),
+        ..tls.clone()
+    };
+    let refused = Client::with_tls("127.0.0.1:4023", None, &impostor)
+        .and_then(|mut client| client.get("key1".to_owned()));
+    assert!(refused.is_err());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "get",
+            "key1",
+            "--addr",
+            "127.0.0.1:4023",
+            "--tls-server-name",
+        ])
+        .arg("localhost")
+        .arg("--tls-ca")
+        .arg(&ca_path)
+        .arg("--tls-cert")
+        .arg(&client_cert.0)
+        .arg("--tls-key")
+        .arg(&client_cert.1)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4023", "--tls-cert"])
+        .arg(&client_cert.0)
+        .assert()
+        .failure()
+        .stderr(contains("--tls-key"));
+    Ok(())
+}
+
+#[test]
+fn tls_pipelined_responses_share_a_record() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = TempDir::new().unwrap();
+    let ca = Ca::new("kvs test CA");
+    let (cert, key) = ca.issue(
+        certs.path(),
+        "server",
+        &["localhost"],
+        ExtendedKeyUsagePurpose::ServerAuth,
+    );
+    let _server = start_tls_server("127.0.0.1:4028", &[&cert, &key], &temp_dir);
+
+    // every write of the server is sent as a record of its own, so the
+    // records show how often the responses were flushed. TLS 1.2 sends no
+    // session tickets after the handshake.
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.cert.der().clone()).unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&version::TLS12])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut sock = TcpStream::connect("127.0.0.1:4028")?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut sock)?;
+    }
+    let requests = r#"{"Get":{"key":"missing"}}"#.repeat(10);
+    conn.writer().write_all(requests.as_bytes())?;
+    conn.complete_io(&mut sock)?;
+
+    let expected = r#"{"Ok":null}"#.repeat(10);
+    let mut plaintext = Vec::new();
+    let mut records = 0;
+    while plaintext.len() < expected.len() {
+        let mut record = vec![0; 5];
+        sock.read_exact(&mut record)?;
+        let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+        record.resize(5 + len, 0);
+        sock.read_exact(&mut record[5..])?;
+        // application data
+        if record[0] == 23 {
+            records += 1;
+        }
+        conn.read_tls(&mut &record[..])?;
+        conn.process_new_packets().unwrap();
+        let _ = conn.reader().read_to_end(&mut plaintext);
+    }
+    assert_eq!(String::from_utf8_lossy(&plaintext), expected);
+    assert_eq!(records, 1);
+    Ok(())
+}
@@ -0,0 +1,126 @@
+// Opens directories written by earlier versions, kept under tests/fixtures,
+// and checks that they are upgraded to the current on-disk format.
+
+use kvs::{KvStore, KvsEngine, KvsError, OsFileSystem, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+// Fixtures are copied so that upgrading never modifies them.
+fn copy_fixture(name: &str) -> TempDir {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in WalkDir::new(&fixture) {
+        let entry = entry.expect("unable to read fixture");
+        let dest = temp_dir
+            .path()
+            .join(entry.path().strip_prefix(&fixture).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(dest).unwrap();
+        } else {
+            fs::copy(entry.path(), dest).unwrap();
+        }
+    }
+    temp_dir
+}
+
+fn assert_upgraded(dir: &Path) -> Result<()> {
+    assert_eq!(fs::read_to_string(dir.join("format"))?, "2");
+    assert_checksummed(dir)
+}
+
+fn assert_checksummed(dir: &Path) -> Result<()> {
+    let scan = KvStore::scan_log(&OsFileSystem, dir)?;
+    assert!(scan.corrupt.is_empty());
+    assert!(scan
+        .records
+        .iter()
+        .all(|record| record.checksum_ok == Some(true)));
+    Ok(())
+}
+
+fn assert_basic_keys(store: &KvStore) -> Result<()> {
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Written by the kvs CLI of the kvs-2 crate, which rewrote the log with
+// only its live keys once it grew over 1 KiB and appended after that
+#[test]
+fn upgrade_kvs_2_cli() -> Result<()> {
+    let temp_dir = copy_fixture("kvs-2");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_basic_keys(&store)?;
+    assert_eq!(store.get("pad".to_owned())?, None);
+    assert_upgraded(temp_dir.path())?;
+
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_basic_keys(&store)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Written by kvs-server before records carried checksums
+#[test]
+fn upgrade_server_without_checksums() -> Result<()> {
+    let temp_dir = copy_fixture("server-no-checksums");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_basic_keys(&store)?;
+    assert_upgraded(temp_dir.path())?;
+    assert_eq!(fs::read_to_string(temp_dir.path().join("engine"))?, "kvs");
+
+    Ok(())
+}
+
+// Written by kvs-server with checksums, merges and keyspaces but before the
+// format was versioned
+#[test]
+fn upgrade_server_with_keyspaces() -> Result<()> {
+    let temp_dir = copy_fixture("server-checksums");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_basic_keys(&store)?;
+    assert_eq!(store.get("counter".to_owned())?, Some("6".to_owned()));
+    assert_eq!(store.get("doc".to_owned())?, Some(r#"{"a":1}"#.to_owned()));
+    assert_eq!(
+        store.keyspace("team")?.get("key1".to_owned())?,
+        Some("team1".to_owned())
+    );
+    assert_upgraded(temp_dir.path())?;
+    assert_checksummed(&temp_dir.path().join("keyspaces").join("team"))?;
+
+    Ok(())
+}
+
+#[test]
+fn new_directory_records_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_upgraded(temp_dir.path())?;
+
+    Ok(())
+}
+
+#[test]
+fn newer_format_is_rejected() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(temp_dir.path())?);
+    fs::write(temp_dir.path().join("format"), "3")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(3)) => Ok(()),
+        other => panic!(
+            "expected an unsupported format error, got {:?}",
+            other.err()
+        ),
+    }
+}